@@ -1,9 +1,17 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_lang::solana_program::{
-    program::invoke_signed,
+    program::invoke,
     system_instruction,
 };
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+};
+use anchor_spl::token_interface::{
+    self, CloseAccount, Mint, SetAuthority, SyncNative, TokenAccount, TokenInterface,
+    TransferChecked,
+};
 
 //  placeholders for  oracle usage
 use pyth_sdk_solana::load_price_feed_from_account_info;
@@ -24,21 +32,26 @@ pub mod perpetual_program {
     ////////////////////////////////////////////////////////////////////////////
     // MULTI-ASSET COLLATERAL SUPPORT (SOL, USDC)
     ////////////////////////////////////////////////////////////////////////////
-    // Store a list of accepted collaterals in MarketState, plus some logic.
-    // For demonstration purposes, USDC and SOL are shown. If using wSOL, treat it as an SPL token.  
+    // The quote mint is the settlement collateral: fees, PnL and funding are paid in it.
+    // Further mints are listed in the market's CollateralRegistry with an oracle and
+    // haircuts, and count toward margin at their haircut-weighted oracle value.
 
     /// Initialize the market, create PDAs for fee & insurance vaults, etc.
+    /// `quote_freeze_authority` acknowledges the quote mint's freeze authority, if any.
     pub fn initialize_market(
         ctx: Context<InitializeMarket>,
         initial_funding_rate: i64,
         base_asset_symbol: String,
-        quote_asset_mint: Pubkey, // The primary SPL token used for collateral
+        quote_freeze_authority: Option<Pubkey>,
     ) -> Result<()> {
+        validate_collateral_mint(&ctx.accounts.quote_asset_mint, quote_freeze_authority)?;
+
         let market_state = &mut ctx.accounts.market_state;
 
         market_state.authority = *ctx.accounts.authority.key;
         market_state.base_asset_symbol = base_asset_symbol;
-        market_state.quote_asset_mint = quote_asset_mint;
+        // The primary SPL token used for collateral
+        market_state.quote_asset_mint = ctx.accounts.quote_asset_mint.key();
 
         market_state.funding_rate = initial_funding_rate;
         market_state.last_funding_time = Clock::get()?.unix_timestamp;
@@ -63,6 +76,24 @@ pub mod perpetual_program {
         // For Dutch auction liquidation
         market_state.dutch_auction_discount_bps = 0; // Start at 0 => no discount initially
 
+        // vAMM is optional and enabled separately via initialize_vamm
+        market_state.vamm_enabled = false;
+
+        // Order book is attached separately via initialize_order_book
+        market_state.order_book = Pubkey::default();
+        market_state.event_queue = Pubkey::default();
+
+        // No keeper fee until governance sets one
+        market_state.keeper_fee = 0;
+
+        // Trading fees go to the fee vault, owned by the market authority PDA
+        market_state.taker_fee_bps = DEFAULT_TAKER_FEE_BPS;
+        market_state.maker_fee_bps = DEFAULT_MAKER_FEE_BPS;
+        market_state.market_authority_bump = ctx.bumps.market_authority;
+
+        // Every instruction that reads a price must pass this feed
+        market_state.oracle = ctx.accounts.oracle_price_feed_account.key();
+
         msg!("Market initialized. Multi-asset framework is in place.");
         Ok(())
     }
@@ -74,970 +105,8369 @@ pub mod perpetual_program {
     pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
         require!(amount > 0, PerpError::InvalidAmount);
 
-        // Transfer from user to user vault
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_collateral_account.to_account_info(),
-            to: ctx.accounts.user_vault.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        // Transfer from user to user vault, crediting what arrives after any transfer fee
+        let amount = deposit_to_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.user_collateral_account.to_account_info(),
+            &mut ctx.accounts.user_vault,
+            ctx.accounts.user.to_account_info(),
+            amount,
+        )?;
+
+        record_deposit(
+            &mut ctx.accounts.user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.bumps.user_stats,
+            ctx.accounts.user.key(),
+            ctx.accounts.market_state.key(),
+            amount,
+        )
+    }
 
-        // Track user position
-        let user_position = &mut ctx.accounts.user_position;
-        user_position.user = ctx.accounts.user.key();
-        user_position.market = ctx.accounts.market_state.key();
-        user_position.collateral = user_position
-            .collateral
-            .checked_add(amount)
-            .ok_or(PerpError::MathOverflow)?;
+    /// Deposit quote collateral into `beneficiary`'s position, e.g. a treasury topping
+    /// up a trader. The depositor gains no rights over the position.
+    pub fn deposit_for(ctx: Context<DepositFor>, beneficiary: Pubkey, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-        emit!(CollateralDeposited {
-            user: user_position.user,
+        let amount = deposit_to_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.depositor_token_account.to_account_info(),
+            &mut ctx.accounts.user_vault,
+            ctx.accounts.depositor.to_account_info(),
+            amount,
+        )?;
+
+        record_deposit(
+            &mut ctx.accounts.user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.bumps.user_stats,
+            beneficiary,
+            ctx.accounts.market_state.key(),
+            amount,
+        )?;
+
+        emit!(DepositedFor {
+            depositor: ctx.accounts.depositor.key(),
+            beneficiary,
+            market: ctx.accounts.market_state.key(),
             amount,
         });
 
         Ok(())
     }
 
-    /// Withdraws collateral. Partial withdrawals are allowed as long as they do not break margin requirements.  
+    /// Let `delegate` place and cancel orders and open and close positions on the
+    /// user's behalf in every market. Delegates can never withdraw. Pass
+    /// `Pubkey::default()` to remove the delegate.
+    pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Pubkey) -> Result<()> {
+        require_keys_neq!(delegate, ctx.accounts.user.key(), PerpError::InvalidDelegate);
+        let user_stats = &mut ctx.accounts.user_stats;
+        user_stats.delegate = delegate;
+
+        emit!(DelegateUpdated {
+            user: user_stats.authority,
+            delegate,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraws collateral. Partial withdrawals are allowed as long as the position
+    /// still meets initial margin afterwards.
+    /// Registered collateral assets count toward margin when the CollateralRegistry,
+    /// UserCollateral and their oracles (remaining accounts) are passed.
     pub fn withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
         require!(amount > 0, PerpError::InvalidAmount);
+        require!(ctx.accounts.user_position.collateral >= amount, PerpError::InsufficientCollateral);
+
+        // Check margin on the post-withdrawal state
+        let max_amount = withdrawable_collateral(&ctx)?;
+        require!(amount <= max_amount, PerpError::InsufficientMargin);
 
         let user_position = &mut ctx.accounts.user_position;
 
-        // Check margin requirement
-        let (margin_ok, _) = is_margin_healthy(user_position, &ctx.accounts.market_state, None);
-        require!(margin_ok, PerpError::InsufficientMargin);
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        vault.transfer(
+            &user_position.user,
+            &ctx.accounts.market_state.key(),
+            ctx.accounts.user_collateral_account.to_account_info(),
+            amount,
+        )?;
 
-        require!(user_position.collateral >= amount, PerpError::InsufficientCollateral);
+        user_position.collateral = user_position
+            .collateral
+            .checked_sub(amount)
+            .ok_or(PerpError::MathOverflow)?;
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_vault.to_account_info(),
-            to: ctx.accounts.user_collateral_account.to_account_info(),
-            authority: ctx.accounts.user_vault_authority.to_account_info(),
-        };
+        emit!(CollateralWithdrawn {
+            user: user_position.user,
+            amount,
+        });
 
-        let market_key = ctx.accounts.market_state.key();
+        Ok(())
+    }
+
+    /// Move a vault created under the legacy layout, where the vault authority PDA
+    /// shared the quote vault's seeds `[b"user_vault", user, scope]`, to the
+    /// `[b"vault_authority", user, scope]` authority. Works for quote vaults, collateral
+    /// asset vaults and margin vaults (scope = market or margin account). Permissionless:
+    /// the new owner is always the user's own vault authority PDA.
+    pub fn migrate_vault_authority(ctx: Context<MigrateVaultAuthority>, scope: Pubkey) -> Result<()> {
+        let user = ctx.accounts.user.key();
         let seeds = &[
             b"user_vault",
-            user_position.user.as_ref(),
-            market_key.as_ref(),
-            &[ctx.bumps.user_vault_authority],
+            user.as_ref(),
+            scope.as_ref(),
+            &[ctx.bumps.legacy_vault_authority],
         ];
         let signer = &[&seeds[..]];
 
+        let cpi_accounts = SetAuthority {
+            current_authority: ctx.accounts.legacy_vault_authority.to_account_info(),
+            account_or_mint: ctx.accounts.vault.to_account_info(),
+        };
         let cpi_ctx = CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
             cpi_accounts,
             signer,
         );
-        token::transfer(cpi_ctx, amount)?;
+        token_interface::set_authority(
+            cpi_ctx,
+            spl_token_2022::instruction::AuthorityType::AccountOwner,
+            Some(ctx.accounts.vault_authority.key()),
+        )?;
+
+        emit!(VaultAuthorityMigrated {
+            user,
+            scope,
+            vault: ctx.accounts.vault.key(),
+            vault_authority: ctx.accounts.vault_authority.key(),
+        });
 
-        user_position.collateral = user_position
-            .collateral
-            .checked_sub(amount)
-            .ok_or(PerpError::MathOverflow)?;
+        Ok(())
+    }
 
-        emit!(CollateralWithdrawn {
-            user: user_position.user,
-            amount,
+    /// Withdraw the largest amount of quote collateral that keeps the position at or
+    /// above initial margin. Takes the same accounts as `withdraw_collateral`.
+    pub fn withdraw_max(ctx: Context<WithdrawCollateral>) -> Result<()> {
+        let amount = withdrawable_collateral(&ctx)?;
+        require!(amount > 0, PerpError::InsufficientMargin);
+        withdraw_collateral(ctx, amount)
+    }
+
+    /// Register a session key that can trade for the owner until `expires_at`, limited
+    /// to `markets`, a per-trade `max_notional` and the `allowed_instructions` bitmask
+    /// (SESSION_* flags). Session keys can never withdraw.
+    pub fn register_session_key(
+        ctx: Context<RegisterSessionKey>,
+        session_signer: Pubkey,
+        markets: Vec<Pubkey>,
+        max_notional: u64,
+        allowed_instructions: u16,
+        expires_at: i64,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            expires_at > now && expires_at - now <= MAX_SESSION_DURATION,
+            PerpError::InvalidSessionKey
+        );
+        require!(
+            !markets.is_empty() && markets.len() <= MAX_SESSION_MARKETS,
+            PerpError::InvalidSessionKey
+        );
+        require!(
+            allowed_instructions != 0 && allowed_instructions & !SESSION_ALL == 0,
+            PerpError::InvalidSessionKey
+        );
+        require_keys_neq!(session_signer, ctx.accounts.owner.key(), PerpError::InvalidSessionKey);
+
+        let session_key = &mut ctx.accounts.session_key;
+        session_key.owner = ctx.accounts.owner.key();
+        session_key.signer = session_signer;
+        session_key.markets = [Pubkey::default(); MAX_SESSION_MARKETS];
+        session_key.markets[..markets.len()].copy_from_slice(&markets);
+        session_key.market_count = markets.len() as u8;
+        session_key.max_notional = max_notional;
+        session_key.allowed_instructions = allowed_instructions;
+        session_key.expires_at = expires_at;
+        session_key.bump = ctx.bumps.session_key;
+
+        emit!(SessionKeyRegistered {
+            owner: session_key.owner,
+            signer: session_signer,
+            max_notional,
+            allowed_instructions,
+            expires_at,
         });
 
         Ok(())
     }
 
-    ////////////////////////////////////////////////////////////////////////////
-    //  OCO & Bracket Orders for HFT traders
-    ////////////////////////////////////////////////////////////////////////////
-    // The advanced order logic will be expanded to accept a bracket of (stop_loss, take_profit).
-    
-    /// Place a bracket order that includes both stop_loss and take_profit.
-    /// For demonstration purposes, they are stored in a new bracket order struct.  
-    pub fn place_bracket_order(
-        ctx: Context<PlaceBracketOrder>,
-        stop_loss_price: u64,
-        take_profit_price: u64,
+    /// Revoke a session key immediately, returning its rent to the owner.
+    pub fn revoke_session_key(ctx: Context<RevokeSessionKey>) -> Result<()> {
+        emit!(SessionKeyRevoked {
+            owner: ctx.accounts.owner.key(),
+            signer: ctx.accounts.session_key.signer,
+        });
+        Ok(())
+    }
+
+    /// Create the market's (empty) collateral registry.
+    pub fn initialize_collateral_registry(ctx: Context<InitializeCollateralRegistry>) -> Result<()> {
+        let registry = &mut ctx.accounts.collateral_registry;
+        registry.market = ctx.accounts.market_state.key();
+        registry.asset_count = 0;
+        registry.bump = ctx.bumps.collateral_registry;
+        registry.quote_decimals = ctx.accounts.quote_asset_mint.decimals;
+        Ok(())
+    }
+
+    /// List a new collateral mint, or update the oracle, haircuts and deposit cap of a
+    /// listed one. Asset weights are in bps of oracle value; the initial weight may not
+    /// exceed the maintenance weight. `freeze_authority` acknowledges the mint's freeze
+    /// authority, if any.
+    pub fn configure_collateral_asset(
+        ctx: Context<ConfigureCollateralAsset>,
+        initial_asset_weight_bps: u64,
+        maintenance_asset_weight_bps: u64,
+        deposit_cap: u64,
+        freeze_authority: Option<Pubkey>,
     ) -> Result<()> {
-        let bracket_order = &mut ctx.accounts.bracket_order;
-        bracket_order.user = ctx.accounts.user.key();
-        bracket_order.market = ctx.accounts.market_state.key();
-        bracket_order.stop_loss_price = stop_loss_price;
-        bracket_order.take_profit_price = take_profit_price;
-        bracket_order.size = ctx.accounts.user_position.size;
-        bracket_order.is_long = ctx.accounts.user_position.is_long;
+        validate_collateral_mint(&ctx.accounts.mint, freeze_authority)?;
+
+        require!(
+            initial_asset_weight_bps <= maintenance_asset_weight_bps
+                && maintenance_asset_weight_bps <= BPS_DENOMINATOR,
+            PerpError::InvalidAssetWeight
+        );
+        let mint = &ctx.accounts.mint;
+        require_keys_neq!(mint.key(), ctx.accounts.market_state.quote_asset_mint, PerpError::InvalidMint);
+
+        let registry = &mut ctx.accounts.collateral_registry;
+        let index = match registry.find(&mint.key()) {
+            Some(index) => index,
+            None => {
+                require!(
+                    (registry.asset_count as usize) < MAX_COLLATERAL_ASSETS,
+                    PerpError::CollateralRegistryFull
+                );
+                registry.asset_count += 1;
+                registry.asset_count as usize - 1
+            }
+        };
 
-      // If user_position is 0 or not valid, the bracket order is meaningless, but for demonstration purposes, this is ignored.  
+        let asset = &mut registry.assets[index];
+        asset.mint = mint.key();
+        asset.oracle = ctx.accounts.oracle_price_feed_account.key();
+        asset.decimals = mint.decimals;
+        asset.initial_asset_weight_bps = initial_asset_weight_bps;
+        asset.maintenance_asset_weight_bps = maintenance_asset_weight_bps;
+        asset.deposit_cap = deposit_cap;
+
+        emit!(CollateralAssetConfigured {
+            market: ctx.accounts.market_state.key(),
+            mint: asset.mint,
+            oracle: asset.oracle,
+            initial_asset_weight_bps,
+            maintenance_asset_weight_bps,
+            deposit_cap,
+        });
 
-        msg!("Placed bracket order: stop_loss = {}, tp = {}", stop_loss_price, take_profit_price);
         Ok(())
     }
 
-    /// Trigger bracket order if conditions met (like stop_loss or take_profit).
-    /// If one trigger condition is met, the position is closed.
-    /// The other is effectively canceled.
+    /// Deposit a registered non-quote collateral asset into the user's per-mint vault.
+    pub fn deposit_collateral_asset(ctx: Context<DepositCollateralAsset>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-    pub fn trigger_bracket_order(ctx: Context<TriggerBracketOrder>) -> Result<()> {
-        let bracket_order = &mut ctx.accounts.bracket_order;
-        let user_position = &mut ctx.accounts.user_position;
-        let market_state = &mut ctx.accounts.market_state;
+        let mint = ctx.accounts.mint.key();
 
-        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        let amount = deposit_to_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.mint,
+            ctx.accounts.user_token_account.to_account_info(),
+            &mut ctx.accounts.collateral_vault,
+            ctx.accounts.user.to_account_info(),
+            amount,
+        )?;
 
-        // Check current price
-        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
-        let is_long = bracket_order.is_long;
-        // If is_long => stop_loss triggers if price <= bracket_order.stop_loss_price,
-        // or take_profit if price >= bracket_order.take_profit_price.
+        ctx.accounts.user_collateral.init_if_new(
+            ctx.accounts.user.key(),
+            ctx.accounts.market_state.key(),
+            ctx.bumps.user_collateral,
+        );
+        credit_collateral_asset(
+            &mut ctx.accounts.collateral_registry,
+            &mut ctx.accounts.user_collateral,
+            &mut ctx.accounts.user_position,
+            &mint,
+            amount,
+        )?;
 
-        let mut triggered = false;
+        emit!(CollateralAssetDeposited {
+            user: ctx.accounts.user.key(),
+            market: ctx.accounts.market_state.key(),
+            mint,
+            amount,
+        });
 
-        if is_long {
-            if current_price <= bracket_order.stop_loss_price {
-                msg!("Stop loss triggered.");
-                triggered = true;
-            } else if current_price >= bracket_order.take_profit_price {
-                msg!("Take profit triggered.");
-                triggered = true;
-            }
-        } else {
-            // short position
-            if current_price >= bracket_order.stop_loss_price {
-                msg!("Stop loss triggered (short). ");
-                triggered = true;
-            } else if current_price <= bracket_order.take_profit_price {
-                msg!("Take profit triggered (short). ");
-                triggered = true;
-            }
-        }
+        Ok(())
+    }
 
-        if !triggered {
-            return Ok(()); // no trigger
-        }
+    /// Withdraw a non-quote collateral asset. The position must stay at initial margin at
+    /// the market's oracle price with the remaining assets valued at their initial
+    /// weights (asset oracles as remaining accounts).
+    pub fn withdraw_collateral_asset(ctx: Context<WithdrawCollateralAsset>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-        // If triggered, close position.
-        let direction_multiplier = if user_position.is_long { 1 } else { -1 };
-        let realized_pnl = (user_position.size as i64)
-            .checked_mul((current_price as i64 - user_position.entry_price as i64))
-            .ok_or(PerpError::MathOverflow)?
-            .checked_mul(direction_multiplier)
-            .ok_or(PerpError::MathOverflow)?;
+        let mint = ctx.accounts.mint.key();
+        debit_collateral_asset(
+            &mut ctx.accounts.collateral_registry,
+            &mut ctx.accounts.user_collateral,
+            &ctx.accounts.user_position,
+            &ctx.accounts.market_state,
+            get_oracle_price(&ctx.accounts.oracle_price_feed_account)?,
+            ctx.remaining_accounts,
+            &mint,
+            amount,
+        )?;
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.mint.to_account_info(),
+            decimals: ctx.accounts.mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.collateral_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        vault.transfer(
+            &ctx.accounts.user.key(),
+            &ctx.accounts.market_state.key(),
+            ctx.accounts.user_token_account.to_account_info(),
+            amount,
+        )?;
 
-        let new_collateral = (user_position.collateral as i64)
-            .checked_add(realized_pnl)
-            .ok_or(PerpError::MathOverflow)?;
-        user_position.collateral = if new_collateral < 0 { 0 } else { new_collateral as u64 };
+        emit!(CollateralAssetWithdrawn {
+            user: ctx.accounts.user.key(),
+            market: ctx.accounts.market_state.key(),
+            mint,
+            amount,
+        });
 
-        // Update open interest
-        if user_position.is_long {
-            market_state.open_interest_long = market_state
-                .open_interest_long
-                .checked_sub(user_position.size)
-                .unwrap_or_default();
-        } else {
-            market_state.open_interest_short = market_state
-                .open_interest_short
-                .checked_sub(user_position.size)
-                .unwrap_or_default();
-        }
+        Ok(())
+    }
 
-        // Reset user position
-        user_position.size = 0;
-        user_position.entry_price = 0;
-        user_position.is_long = false;
-        user_position.unrealized_pnl = 0;
+    /// Deposit native SOL: lamports go straight into the user's program-owned wSOL
+    /// collateral vault, which is then synced. The native mint must be listed in the
+    /// collateral registry.
+    pub fn deposit_sol(ctx: Context<DepositSol>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
+
+        invoke(
+            &system_instruction::transfer(
+                &ctx.accounts.user.key(),
+                &ctx.accounts.collateral_vault.key(),
+                amount,
+            ),
+            &[
+                ctx.accounts.user.to_account_info(),
+                ctx.accounts.collateral_vault.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+        token_interface::sync_native(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.collateral_vault.to_account_info(),
+            },
+        ))?;
+
+        let mint = ctx.accounts.native_mint.key();
+        ctx.accounts.user_collateral.init_if_new(
+            ctx.accounts.user.key(),
+            ctx.accounts.market_state.key(),
+            ctx.bumps.user_collateral,
+        );
+        credit_collateral_asset(
+            &mut ctx.accounts.collateral_registry,
+            &mut ctx.accounts.user_collateral,
+            &mut ctx.accounts.user_position,
+            &mint,
+            amount,
+        )?;
+
+        emit!(CollateralAssetDeposited {
+            user: ctx.accounts.user.key(),
+            market: ctx.accounts.market_state.key(),
+            mint,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraw native SOL. The wSOL is moved into a temporary account that is closed
+    /// to the user in the same instruction, so the user receives plain lamports.
+    pub fn withdraw_sol(ctx: Context<WithdrawSol>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
+
+        let mint = ctx.accounts.native_mint.key();
+        debit_collateral_asset(
+            &mut ctx.accounts.collateral_registry,
+            &mut ctx.accounts.user_collateral,
+            &ctx.accounts.user_position,
+            &ctx.accounts.market_state,
+            get_oracle_price(&ctx.accounts.oracle_price_feed_account)?,
+            ctx.remaining_accounts,
+            &mint,
+            amount,
+        )?;
+
+        let user = ctx.accounts.user.key();
+        let market = ctx.accounts.market_state.key();
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.native_mint.to_account_info(),
+            decimals: ctx.accounts.native_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.collateral_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        vault.transfer(&user, &market, ctx.accounts.unwrap_account.to_account_info(), amount)?;
+        vault.close_account(
+            &user,
+            &market,
+            ctx.accounts.unwrap_account.to_account_info(),
+            ctx.accounts.user.to_account_info(),
+        )?;
+
+        emit!(CollateralAssetWithdrawn {
+            user,
+            market,
+            mint,
+            amount,
+        });
 
-        // Mark bracket as used
-        bracket_order.size = 0;
-        msg!("Bracket order executed, position closed.");
         Ok(())
     }
 
     ////////////////////////////////////////////////////////////////////////////
-    //  ADAPTIVE FUNDING RATE BASED ON OPEN INTEREST
+    //  CROSS MARGIN
     ////////////////////////////////////////////////////////////////////////////
-    /// The update_funding_rate function will be adjusted
-    /// to factor in open interest (OI) imbalance.
+    // A MarginAccount holds one pool of quote collateral and up to MAX_MARGIN_POSITIONS
+    // positions across markets sharing that quote mint. Margin is checked over the
+    // whole account; the MarketState of every other open position is passed as a
+    // remaining account. Collateral sits in a user vault scoped by the margin account
+    // (`[b"user_vault", owner, margin_account]`), owned by the matching vault authority.
+    //
+    // Each owner can open numbered subaccounts (`[b"margin_account", owner, id]`).
+    // Subaccounts are isolated from each other: losses in one can never draw on the
+    // collateral of another, and collateral only moves between them through
+    // `transfer_between_subaccounts`.
+
+    pub fn initialize_margin_account(ctx: Context<InitializeMarginAccount>, subaccount_id: u16) -> Result<()> {
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.owner = ctx.accounts.owner.key();
+        margin_account.subaccount_id = subaccount_id;
+        margin_account.quote_asset_mint = ctx.accounts.quote_asset_mint.key();
+        margin_account.collateral = 0;
+        margin_account.bump = ctx.bumps.margin_account;
+        Ok(())
+    }
 
-    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        let now = Clock::get()?.unix_timestamp;
+    pub fn deposit_margin(ctx: Context<DepositMargin>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-        let time_diff = (now - market_state.last_funding_time).max(0);
-        if time_diff == 0 {
-            return Ok(());
-        }
+        let amount = deposit_to_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.owner_token_account.to_account_info(),
+            &mut ctx.accounts.margin_vault,
+            ctx.accounts.owner.to_account_info(),
+            amount,
+        )?;
 
-        let mark_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
-        let index_price = market_state.index_price;
+        let margin_account = &mut ctx.accounts.margin_account;
+        margin_account.collateral = margin_account
+            .collateral
+            .checked_add(amount)
+            .ok_or(PerpError::MathOverflow)?;
 
-        let diff = mark_price as i64 - index_price as i64;
-        // This naive formula used to do (diff / 10) * time_diff.
-        // Open interest is now factored in. If OI long > OI short, longs incur higher charges.
+        emit!(MarginDeposited {
+            owner: margin_account.owner,
+            margin_account: margin_account.key(),
+            amount,
+        });
 
-        let oi_long = market_state.open_interest_long as i64;
-        let oi_short = market_state.open_interest_short as i64;
-        let oi_diff = oi_long.checked_sub(oi_short).unwrap_or_default();
+        Ok(())
+    }
 
-        // If oi_diff > 0 => more longs => funding rate is positive => longs pay.
-        // If oi_diff < 0 => more shorts => negative => shorts pay.
+    /// Withdraw cross-margin collateral. The account must meet initial margin afterwards;
+    /// the MarketState and oracle of every open position are passed as remaining accounts.
+    pub fn withdraw_margin<'info>(ctx: Context<'_, '_, 'info, 'info, WithdrawMargin<'info>>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-        let base_rate = (diff / 10).checked_mul(time_diff as i64).unwrap_or_default();
-        // An OI factor is included, e.g., 1 basis point per 100 difference in OI.
+        let lending_pool = accrue_lending_pool(ctx.accounts.lending_pool.as_deref_mut())?;
+        let margin_account = &mut ctx.accounts.margin_account;
+        require!(margin_account.collateral >= amount, PerpError::InsufficientCollateral);
+        margin_account.collateral -= amount;
 
-        let oi_factor = (oi_diff / 100).max(-1000).min(1000); // clamp for safety
-        let new_funding_rate = base_rate + oi_factor;
+        let risks = margin_account_risks(margin_account, None, ctx.remaining_accounts)?;
+        let collateral_value = margin_collateral_value(margin_account, lending_pool)?;
+        let (margin_ok, _) = portfolio_margin_health(collateral_value, &risks, true);
+        require!(margin_ok, PerpError::InsufficientMargin);
 
-        market_state.funding_rate = new_funding_rate;
-        market_state.last_funding_time = now;
+        let owner = margin_account.owner;
+        let margin_account_key = margin_account.key();
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.margin_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.margin_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.margin_vault_authority,
+        };
+        vault.transfer(
+            &owner,
+            &margin_account_key,
+            ctx.accounts.owner_token_account.to_account_info(),
+            amount,
+        )?;
 
-        emit!(FundingRateUpdated {
-            market: market_state.key(),
-            new_funding_rate,
+        emit!(MarginWithdrawn {
+            owner,
+            margin_account: margin_account_key,
+            amount,
         });
 
         Ok(())
     }
 
-    ////////////////////////////////////////////////////////////////////////////
-    //  LIQUIDATION AUTOMATION (For Future Keepers/Bots)
-    ////////////////////////////////////////////////////////////////////////////
-    ////Liquidation can be called by anyone, but it is primarily designed for a keeper.
-    /// Future integration with Switchboard could enable automatic execution of this function.
+    /// Move collateral from one of the owner's subaccounts to another. The source
+    /// must still meet initial margin afterwards; the MarketState and oracle of each of
+    /// its open positions are passed as remaining accounts.
+    pub fn transfer_between_subaccounts<'info>(
+        ctx: Context<'_, '_, 'info, 'info, TransferBetweenSubaccounts<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
 
-    pub fn liquidate_position(ctx: Context<LiquidatePosition>, liquidation_size: u64) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        let user_position = &mut ctx.accounts.user_position;
+        let lending_pool = accrue_lending_pool(ctx.accounts.lending_pool.as_deref_mut())?;
+        let from = &mut ctx.accounts.from_margin_account;
+        require!(from.collateral >= amount, PerpError::InsufficientCollateral);
+        from.collateral -= amount;
 
-        require!(user_position.size > 0, PerpError::NoOpenPosition);
-        require!(liquidation_size > 0, PerpError::InvalidAmount);
-        require!(liquidation_size <= user_position.size, PerpError::InvalidAmount);
+        let risks = margin_account_risks(from, None, ctx.remaining_accounts)?;
+        let collateral_value = margin_collateral_value(from, lending_pool)?;
+        let (margin_ok, _) = portfolio_margin_health(collateral_value, &risks, true);
+        require!(margin_ok, PerpError::InsufficientMargin);
 
-        let (margin_ok, net_equity) = is_margin_healthy(user_position, market_state, None);
-        if margin_ok {
-            return err!(PerpError::PositionNotLiquidatable);
-        }
+        let owner = from.owner;
+        let from_key = from.key();
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.from_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.from_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.from_vault_authority,
+        };
+        vault.transfer(&owner, &from_key, ctx.accounts.to_vault.to_account_info(), amount)?;
 
-        let discount_level_bps = market_state.dutch_auction_discount_bps;
-        let liquidator_reward_bps = 100; // 10%
-        let current_mark_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
-        let direction_multiplier = if user_position.is_long { 1 } else { -1 };
+        // Credit what actually arrived in case the mint charges a transfer fee.
+        let to_vault = &mut ctx.accounts.to_vault;
+        let balance_before = to_vault.amount;
+        to_vault.reload()?;
+        let received = to_vault.amount.saturating_sub(balance_before);
 
-        let partial_pnl = (liquidation_size as i64)
-            .checked_mul((current_mark_price as i64 - user_position.entry_price as i64))
-            .ok_or(PerpError::MathOverflow)?
-            .checked_mul(direction_multiplier)
-            .ok_or(PerpError::MathOverflow)?;
+        let to = &mut ctx.accounts.to_margin_account;
+        to.collateral = to.collateral.checked_add(received).ok_or(PerpError::MathOverflow)?;
 
-        let new_collateral_i64 = (user_position.collateral as i64)
-            .checked_add(partial_pnl)
-            .ok_or(PerpError::MathOverflow)?;
+        emit!(SubaccountTransfer {
+            owner,
+            from_subaccount_id: ctx.accounts.from_margin_account.subaccount_id,
+            to_subaccount_id: to.subaccount_id,
+            amount,
+            received,
+        });
 
-        // Dutch Auction discount.
-        let discount_amount = (new_collateral_i64
-            .checked_mul(discount_level_bps as i64)
-            .unwrap_or(0))
-            .checked_div(1000)
-            .unwrap_or(0);
-        let discounted_collateral = new_collateral_i64.checked_sub(discount_amount).unwrap_or(0);
-        let liquidator_reward = (discount_amount
-            .checked_mul(liquidator_reward_bps as i64)
-            .unwrap_or(0))
-            .checked_div(1000)
-            .unwrap_or(0);
-
-        let final_collateral = if discounted_collateral < 0 {
-            0
-        } else {
-            discounted_collateral as u64
-        };
+        Ok(())
+    }
 
-        user_position.collateral = final_collateral;
-        user_position.size = user_position.size.checked_sub(liquidation_size).unwrap_or(0);
+    ////////////////////////////////////////////////////////////////////////////
+    //  LENDING OF IDLE COLLATERAL
+    ////////////////////////////////////////////////////////////////////////////
+    // Margin accounts can opt in to lend a governance-set fraction of their idle quote
+    // collateral to a per-mint LendingPool, holding interest-bearing shares. Cross
+    // margin losses beyond an account's collateral are borrowed from the pool. The
+    // borrow rate follows a utilization curve; interest accrues into the pool's
+    // deposit and borrow indices lazily, whenever the pool is touched.
+
+    pub fn initialize_lending_pool(ctx: Context<InitializeLendingPool>, params: LendingParams) -> Result<()> {
+        validate_lending_params(&params)?;
+
+        let pool = &mut ctx.accounts.lending_pool;
+        pool.authority = ctx.accounts.authority.key();
+        pool.quote_asset_mint = ctx.accounts.quote_asset_mint.key();
+        pool.lending_vault = ctx.accounts.lending_vault.key();
+        pool.params = params;
+        pool.deposit_index = LENDING_INDEX_PRECISION;
+        pool.borrow_index = LENDING_INDEX_PRECISION;
+        pool.total_deposit_shares = 0;
+        pool.total_borrow_shares = 0;
+        pool.last_update_ts = Clock::get()?.unix_timestamp;
+        pool.bump = ctx.bumps.lending_pool;
+
+        emit!(LendingParamsUpdated {
+            quote_asset_mint: pool.quote_asset_mint,
+            params,
+        });
 
-        if user_position.size == 0 {
-            user_position.entry_price = 0;
-            user_position.is_long = false;
-            user_position.unrealized_pnl = 0;
-        }
+        Ok(())
+    }
 
-        if user_position.is_long {
-            market_state.open_interest_long = market_state
-                .open_interest_long
-                .checked_sub(liquidation_size)
-                .unwrap_or_default();
-        } else {
-            market_state.open_interest_short = market_state
-                .open_interest_short
-                .checked_sub(liquidation_size)
-                .unwrap_or_default();
-        }
+    pub fn set_lending_params(ctx: Context<SetLendingParams>, params: LendingParams) -> Result<()> {
+        validate_lending_params(&params)?;
 
-        // Increase discount for next time.
-        market_state.dutch_auction_discount_bps = market_state
-            .dutch_auction_discount_bps
-            .checked_add(50)
-            .unwrap_or(1000);
+        // Interest up to now accrues at the old rate.
+        let pool = &mut ctx.accounts.lending_pool;
+        pool.accrue(Clock::get()?.unix_timestamp)?;
+        pool.params = params;
 
-        emit!(PositionLiquidated {
-            user: user_position.user,
-            market: user_position.market,
-            penalty: discount_amount,
-            liquidation_size,
+        emit!(LendingParamsUpdated {
+            quote_asset_mint: pool.quote_asset_mint,
+            params,
         });
-        msg!("Liquidator reward: {}", liquidator_reward);
-
-        // Potentially integrate with Switchboard here for automation.
-        if market_state.auto_deleverage_enabled {
-            handle_auto_deleveraging(market_state)?;
-        }
 
         Ok(())
     }
 
-    ////////////////////////////////////////////////////////////////////////////
-    //  SMART LEVERAGE LIMITS (RISK & VOLATILITY)
-    ////////////////////////////////////////////////////////////////////////////
-    /// A simple approach will be incorporated in is_margin_healthy.
-    /// Dynamic margin logic has already been partially implemented. A maximum leverage check is now added.
-
-    /// Open or extend a position (overridden) with new leverage check.
-    pub fn open_position(ctx: Context<OpenPosition>, is_long: bool, size: u64) -> Result<()> {
-        let market_state = &mut ctx.accounts.market_state;
-        let user_position = &mut ctx.accounts.user_position;
-
-        require!(size > 0, PerpError::InvalidAmount);
+    /// Opt in to or out of lending and settle the margin account against the pool:
+    /// outstanding debt is repaid from idle collateral first, then the lent balance is
+    /// moved to the pool's lend fraction of the account's collateral (zero when
+    /// `enabled` is false). Equity is unchanged, so no margin check is needed.
+    pub fn update_lending(ctx: Context<UpdateLending>, enabled: bool) -> Result<()> {
+        let pool = &mut ctx.accounts.lending_pool;
+        pool.accrue(Clock::get()?.unix_timestamp)?;
+
+        let margin_account = &mut ctx.accounts.margin_account;
+        let owner = margin_account.owner;
+        let margin_account_key = margin_account.key();
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.margin_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.margin_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.margin_vault_authority,
+        };
 
-         // A basic approach assumes max_leverage = 10.
-        // Then user_position.collateral * 10 >= size * current_price.
-        let current_mark_price = 1000; // placeholder
-        let max_leverage = 10_u64;
-        let cost = size.checked_mul(current_mark_price).ok_or(PerpError::MathOverflow)?;
-        let max_allowed = user_position
-            .collateral
-            .checked_mul(max_leverage)
-            .ok_or(PerpError::MathOverflow)?;
-        require!(cost <= max_allowed, PerpError::InsufficientMargin);
+        // Repay debt from idle collateral.
+        let debt = pool.debt_value(margin_account.borrow_shares)?;
+        let repaid = debt.min(margin_account.collateral);
+        if repaid > 0 {
+            let shares = pool.repay(repaid, margin_account.borrow_shares)?;
+            margin_account.borrow_shares -= shares;
+            margin_account.collateral -= repaid;
+            vault.transfer(&owner, &margin_account_key, ctx.accounts.lending_vault.to_account_info(), repaid)?;
+        }
 
-        if user_position.size == 0 {
-            user_position.is_long = is_long;
-            user_position.entry_price = market_state.index_price;
-            user_position.size = size;
+        // Rebalance the lent balance toward its target.
+        let lent = pool.deposit_value(margin_account.lend_shares)?;
+        let target = if enabled {
+            bps_of(margin_account.collateral.saturating_add(lent), pool.params.lend_fraction_bps)
         } else {
-            require!(user_position.is_long == is_long, PerpError::OppositePositionNotSupported);
-            let old_size = user_position.size;
-            let old_entry_price = user_position.entry_price;
-            let total_size = old_size.checked_add(size).ok_or(PerpError::MathOverflow)?;
-            let new_entry_price = (old_entry_price as u128)
-                .checked_mul(old_size as u128)
-                .ok_or(PerpError::MathOverflow)?
-                .checked_add(
-                    (market_state.index_price as u128)
-                        .checked_mul(size as u128)
-                        .ok_or(PerpError::MathOverflow)?,
-                )
-                .ok_or(PerpError::MathOverflow)?
-                .checked_div(total_size as u128)
-                .ok_or(PerpError::MathOverflow)? as u64;
+            0
+        };
+        if target > lent {
+            let amount = target - lent;
+            margin_account.lend_shares = margin_account
+                .lend_shares
+                .checked_add(pool.deposit(amount)?)
+                .ok_or(PerpError::MathOverflow)?;
+            margin_account.collateral -= amount;
+            vault.transfer(&owner, &margin_account_key, ctx.accounts.lending_vault.to_account_info(), amount)?;
+        } else if lent > target {
+            let shares = if target == 0 {
+                margin_account.lend_shares
+            } else {
+                pool.shares_for_deposit(lent - target)?.min(margin_account.lend_shares)
+            };
+            let amount = pool.withdraw(shares)?;
+            margin_account.lend_shares -= shares;
+            margin_account.collateral = margin_account
+                .collateral
+                .checked_add(amount)
+                .ok_or(PerpError::MathOverflow)?;
 
-            user_position.entry_price = new_entry_price;
-            user_position.size = total_size;
+            transfer_from_lending_vault(
+                pool,
+                &ctx.accounts.lending_vault,
+                &ctx.accounts.quote_asset_mint,
+                ctx.accounts.margin_vault.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                amount,
+            )?;
         }
+        margin_account.lending_enabled = enabled;
+
+        emit!(LendingUpdated {
+            owner,
+            margin_account: margin_account_key,
+            enabled,
+            lend_shares: margin_account.lend_shares,
+            borrow_shares: margin_account.borrow_shares,
+            repaid,
+            collateral: margin_account.collateral,
+        });
 
-        // Update OI(open interest)
-        if is_long {
-            market_state.open_interest_long = market_state
-                .open_interest_long
-                .checked_add(size)
-                .ok_or(PerpError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Open or extend a cross-margin position in `market_state`.
+    pub fn open_cross_position<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CrossTrade<'info>>,
+        is_long: bool,
+        size: u64,
+    ) -> Result<()> {
+        require!(size > 0, PerpError::InvalidAmount);
+        require!(
+            is_trading_authority(
+                &ctx.accounts.owner.key(),
+                &ctx.accounts.authority.key(),
+                Some(&ctx.accounts.user_stats),
+                ctx.accounts.session_key.as_deref(),
+                &ctx.accounts.market_state.key(),
+                SESSION_OPEN_POSITION,
+            ),
+            PerpError::Unauthorized
+        );
+
+        let market_key = ctx.accounts.market_state.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
+        let market_state = &mut ctx.accounts.market_state;
+        let margin_account = &mut ctx.accounts.margin_account;
+
+        let slot = match margin_account.find_position(&market_key) {
+            Some(slot) => slot,
+            None => margin_account.free_slot().ok_or(PerpError::MarginAccountFull)?,
+        };
+
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let fill_price = fill_price_with_impact(market_state, is_long, size, oracle_price)?;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, fill_price)?,
+        )?;
+
+        let position = &mut margin_account.positions[slot];
+        if position.size == 0 {
+            position.market = market_key;
+            position.is_long = is_long;
+            position.entry_price = fill_price;
+            position.size = size;
         } else {
-            market_state.open_interest_short = market_state
-                .open_interest_short
-                .checked_add(size)
+            require!(position.is_long == is_long, PerpError::OppositePositionNotSupported);
+            let total_size = position.size.checked_add(size).ok_or(PerpError::MathOverflow)?;
+            let weighted = (position.entry_price as u128)
+                .checked_mul(position.size as u128)
+                .and_then(|existing| existing.checked_add((fill_price as u128).checked_mul(size as u128)?))
                 .ok_or(PerpError::MathOverflow)?;
+            position.entry_price = u64::try_from(weighted / total_size as u128)
+                .map_err(|_| error!(PerpError::MathOverflow))?;
+            position.size = total_size;
         }
-
-        // Final margin check
-        let (margin_ok, _) = is_margin_healthy(user_position, market_state, None);
+        add_open_interest(market_state, is_long, size)?;
+
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.margin_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.margin_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.margin_vault_authority,
+        };
+        charge_trading_fee(
+            &mut CrossFeePayer {
+                margin_account,
+                margin_account_key,
+                market: market_key,
+            },
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(size, fill_price)?,
+            false,
+        )?;
+
+        let lending_pool = accrue_lending_pool(ctx.accounts.lending_pool.as_deref_mut())?;
+        let risks = margin_account_risks(
+            margin_account,
+            Some((&market_key, market_state, oracle_price)),
+            ctx.remaining_accounts,
+        )?;
+        let collateral_value = margin_collateral_value(margin_account, lending_pool)?;
+        let (margin_ok, _) = portfolio_margin_health(collateral_value, &risks, true);
         require!(margin_ok, PerpError::InsufficientMargin);
 
-        emit!(PositionOpened {
-            user: user_position.user,
-            market: user_position.market,
-            size: user_position.size,
-            is_long: user_position.is_long,
+        emit!(CrossPositionUpdated {
+            owner: margin_account.owner,
+            margin_account: margin_account_key,
+            market: market_key,
+            is_long,
+            size: margin_account.positions[slot].size,
+            price: fill_price,
+            realized_pnl: 0,
         });
 
         Ok(())
     }
 
-    /// Close an existing position (fully) remains the same.
-    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
-        let user_position = &mut ctx.accounts.user_position;
+    /// Close the margin account's position in `market_state`, realizing PnL into the
+    /// shared collateral.
+    pub fn close_cross_position(ctx: Context<CrossTrade>) -> Result<()> {
+        require!(
+            is_trading_authority(
+                &ctx.accounts.owner.key(),
+                &ctx.accounts.authority.key(),
+                Some(&ctx.accounts.user_stats),
+                ctx.accounts.session_key.as_deref(),
+                &ctx.accounts.market_state.key(),
+                SESSION_CLOSE_POSITION,
+            ),
+            PerpError::Unauthorized
+        );
+        let market_key = ctx.accounts.market_state.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
         let market_state = &mut ctx.accounts.market_state;
+        let margin_account = &mut ctx.accounts.margin_account;
+
+        let lending_pool = accrue_lending_pool(ctx.accounts.lending_pool.as_deref_mut())?;
+        let slot = margin_account.find_position(&market_key).ok_or(PerpError::NoOpenPosition)?;
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let (fill_price, realized_pnl, size, is_long, from_lending_pool) =
+            close_margin_slot(margin_account, slot, market_state, lending_pool, oracle_price)?;
+        if from_lending_pool > 0 {
+            transfer_from_lending_vault(
+                ctx.accounts.lending_pool.as_ref().ok_or(PerpError::MissingLendingPool)?,
+                ctx.accounts.lending_vault.as_ref().ok_or(PerpError::MissingLendingPool)?,
+                &ctx.accounts.quote_asset_mint,
+                ctx.accounts.fee_vault.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                from_lending_pool,
+            )?;
+        }
 
-        require!(user_position.size > 0, PerpError::NoOpenPosition);
-
-        let current_mark_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
-        let direction_multiplier = if user_position.is_long { 1 } else { -1 };
-        let realized_pnl = (user_position.size as i64)
-            .checked_mul((current_mark_price as i64 - user_position.entry_price as i64))
-            .ok_or(PerpError::MathOverflow)?
-            .checked_mul(direction_multiplier)
-            .ok_or(PerpError::MathOverflow)?;
-
-        user_position.unrealized_pnl = realized_pnl;
-        let new_collateral = (user_position.collateral as i64)
-            .checked_add(realized_pnl)
-            .ok_or(PerpError::MathOverflow)?;
-        user_position.collateral = if new_collateral < 0 { 0 } else { new_collateral as u64 };
-
-        emit!(PositionClosed {
-            user: user_position.user,
-            market: user_position.market,
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.margin_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.margin_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.margin_vault_authority,
+        };
+        charge_trading_fee(
+            &mut CrossFeePayer {
+                margin_account,
+                margin_account_key,
+                market: market_key,
+            },
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(size, fill_price)?,
+            false,
+        )?;
+
+        emit!(CrossPositionUpdated {
+            owner: margin_account.owner,
+            margin_account: margin_account_key,
+            market: market_key,
+            is_long,
+            size: 0,
+            price: fill_price,
             realized_pnl,
         });
 
-        if user_position.is_long {
-            market_state.open_interest_long = market_state
-                .open_interest_long
-                .checked_sub(user_position.size)
-                .unwrap_or_default();
-        } else {
-            market_state.open_interest_short = market_state
-                .open_interest_short
-                .checked_sub(user_position.size)
-                .unwrap_or_default();
-        }
-
-        user_position.size = 0;
-        user_position.entry_price = 0;
-        user_position.is_long = false;
-        user_position.unrealized_pnl = 0;
-
         Ok(())
     }
 
-    /// Settle funding unchanged.
-    pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
+    /// Liquidate a margin account's position in `market_state` once the account as a
+    /// whole is below maintenance margin. Other markets and their oracles are remaining
+    /// accounts.
+    pub fn liquidate_cross_position<'info>(
+        ctx: Context<'_, '_, 'info, 'info, LiquidateCrossPosition<'info>>,
+    ) -> Result<()> {
+        let market_key = ctx.accounts.market_state.key();
+        let margin_account_key = ctx.accounts.margin_account.key();
         let market_state = &mut ctx.accounts.market_state;
-        let user_position = &mut ctx.accounts.user_position;
+        let margin_account = &mut ctx.accounts.margin_account;
+
+        let mut lending_pool = accrue_lending_pool(ctx.accounts.lending_pool.as_deref_mut())?;
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let risks = margin_account_risks(
+            margin_account,
+            Some((&market_key, market_state, oracle_price)),
+            ctx.remaining_accounts,
+        )?;
+        let collateral_value = margin_collateral_value(margin_account, lending_pool.as_deref())?;
+        let (margin_ok, net_equity) = portfolio_margin_health(collateral_value, &risks, false);
+        require!(!margin_ok, PerpError::PositionNotLiquidatable);
+
+        let slot = margin_account.find_position(&market_key).ok_or(PerpError::NoOpenPosition)?;
+        let (fill_price, realized_pnl, size, _, from_lending_pool) =
+            close_margin_slot(margin_account, slot, market_state, lending_pool.as_deref_mut(), oracle_price)?;
+        if from_lending_pool > 0 {
+            transfer_from_lending_vault(
+                ctx.accounts.lending_pool.as_ref().ok_or(PerpError::MissingLendingPool)?,
+                ctx.accounts.lending_vault.as_ref().ok_or(PerpError::MissingLendingPool)?,
+                &ctx.accounts.quote_asset_mint,
+                ctx.accounts.fee_vault.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                from_lending_pool,
+            )?;
+        }
 
-        let funding_payment = (user_position.size as i64)
-            .checked_mul(market_state.funding_rate)
-            .ok_or(PerpError::MathOverflow)?;
+        let vault = UserVaultSigner {
+            token_program: ctx.accounts.token_program.to_account_info(),
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            user_vault: ctx.accounts.margin_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.margin_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.margin_vault_authority,
+        };
+        charge_trading_fee(
+            &mut CrossFeePayer {
+                margin_account,
+                margin_account_key,
+                market: market_key,
+            },
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(size, fill_price)?,
+            false,
+        )?;
+
+        // Liquidator reward: a share of the Dutch auction discount on the remaining
+        // collateral, as in liquidate_position.
+        let liquidator_reward_bps: u64 = 100; // 10%
+        let liquidator_reward = margin_account
+            .collateral
+            .checked_mul(market_state.dutch_auction_discount_bps)
+            .and_then(|discount| discount.checked_div(1000))
+            .and_then(|discount| discount.checked_mul(liquidator_reward_bps))
+            .and_then(|reward| reward.checked_div(1000))
+            .ok_or(PerpError::MathOverflow)?
+            .min(margin_account.collateral);
+        margin_account.collateral -= liquidator_reward;
+        if liquidator_reward > 0 {
+            vault.transfer(
+                &margin_account.owner,
+                &margin_account_key,
+                ctx.accounts.liquidator_token_account.to_account_info(),
+                liquidator_reward,
+            )?;
+        }
 
-        let updated_collateral = (user_position.collateral as i64)
-            .checked_add(funding_payment)
-            .ok_or(PerpError::MathOverflow)?;
-        user_position.collateral = if updated_collateral < 0 { 0 } else { updated_collateral as u64 };
+        // Increase discount for next time.
+        market_state.dutch_auction_discount_bps = market_state
+            .dutch_auction_discount_bps
+            .checked_add(50)
+            .unwrap_or(1000);
 
-        emit!(FundingSettled {
-            user: user_position.user,
-            market: user_position.market,
-            funding_payment,
+        emit!(CrossPositionLiquidated {
+            owner: margin_account.owner,
+            margin_account: margin_account_key,
+            market: market_key,
+            liquidator: ctx.accounts.liquidator.key(),
+            size,
+            price: fill_price,
+            realized_pnl,
+            net_equity,
+            liquidator_reward,
         });
 
         Ok(())
     }
-    // The place_stop_order & trigger_stop_order functions will remain unchanged or serve as an alternative.  
-    // The bracket order offers a more advanced approach, while both options can coexist.  
-
-}
 
-// =======================================
-// HELPERS & INTERNAL LOGIC
-// =======================================
+    ////////////////////////////////////////////////////////////////////////////
+    //  OCO & Bracket Orders for HFT traders
+    ////////////////////////////////////////////////////////////////////////////
+    // The advanced order logic will be expanded to accept a bracket of (stop_loss, take_profit).
+    
+    /// Place a bracket order that includes both stop_loss and take_profit.
+    /// The bracket lives in a PDA seeded by user, market and the position's order sequence number.
+    /// `params.is_long` is the closing side and `params.price` the worst fill price (0 => none).
+    pub fn place_bracket_order(
+        ctx: Context<PlaceBracketOrder>,
+        params: OrderParams,
+        stop_loss_price: u64,
+        take_profit_price: u64,
+    ) -> Result<()> {
+        let expiry_ts = validate_conditional_params(&params, Clock::get()?.unix_timestamp)?;
+        let OrderParams { size, client_order_id, .. } = params;
 
-/// Checks margin, factoring in dynamic margin and basic volatility.
-fn is_margin_healthy(
-    user_position: &UserPosition,
-    market_state: &MarketState,
-    _maybe_mark_price: Option<u64>,
-) -> (bool, i64) {
-    let current_mark_price = 1000; // placeholder
-    let direction_multiplier = if user_position.is_long { 1 } else { -1 };
+        let user_position = &mut ctx.accounts.user_position;
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(params.is_long != user_position.is_long, PerpError::InvalidOrderSide);
+        require!(size <= user_position.size, PerpError::InvalidAmount);
+        user_position.record_client_order_id(client_order_id)?;
 
-    let unrealized_pnl = (user_position.size as i64)
-        .checked_mul((current_mark_price as i64 - user_position.entry_price as i64))
-        .unwrap_or_default()
-        .checked_mul(direction_multiplier)
-        .unwrap_or_default();
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        validate_bracket_prices(user_position.is_long, stop_loss_price, take_profit_price, current_price)?;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, stop_loss_price.max(take_profit_price))?,
+        )?;
 
-    let net_equity = (user_position.collateral as i64)
-        .checked_add(unrealized_pnl)
-        .unwrap_or_default();
+        let bracket_order = &mut ctx.accounts.bracket_order;
+        bracket_order.client_order_id = client_order_id;
+        bracket_order.user = ctx.accounts.user.key();
+        bracket_order.market = ctx.accounts.market_state.key();
+        bracket_order.stop_loss_price = stop_loss_price;
+        bracket_order.take_profit_price = take_profit_price;
+        bracket_order.size = size;
+        bracket_order.is_long = user_position.is_long;
+        bracket_order.order_seq = user_position.next_order_seq;
+        bracket_order.bump = ctx.bumps.bracket_order;
+        bracket_order.payer = ctx.accounts.authority.key();
+        bracket_order.position_id = user_position.position_id;
+        bracket_order.limit_price = params.price;
+        bracket_order.expiry_ts = expiry_ts;
+
+        user_position.next_order_seq = user_position
+            .next_order_seq
+            .checked_add(1)
+            .ok_or(PerpError::MathOverflow)?;
 
-    // Dynamic margin logic from base_margin_ratio_bps + size factor.
-    let dynamic_add = (user_position.size / 10) as u64;
-    let dynamic_margin_bps = market_state.base_margin_ratio_bps + dynamic_add;
+        emit!(BracketOrderPlaced {
+            user: bracket_order.user,
+            market: bracket_order.market,
+            stop_loss_price,
+            take_profit_price,
+            size: bracket_order.size,
+            client_order_id,
+        });
 
-    // A basic 'volatility' check can also be implemented.  
-    // For demonstration purposes, this implementation does not fetch data from oracles.  
-    // If base_asset_symbol == "SOL", the required margin is doubled.  
-    // This is a placeholder.  
-    let mut final_margin_bps = dynamic_margin_bps;
-    if market_state.base_asset_symbol == "SOL" {
-        final_margin_bps = final_margin_bps.saturating_mul(2);
+        Ok(())
     }
 
-    let mmr = (user_position.collateral as i64)
-        .checked_mul(final_margin_bps as i64)
-        .unwrap_or_default()
-        .checked_div(1000)
-        .unwrap_or_default();
+    /// Change the stop_loss and take_profit prices of an existing bracket.
+    pub fn modify_bracket_order(
+        ctx: Context<ModifyBracketOrder>,
+        stop_loss_price: u64,
+        take_profit_price: u64,
+    ) -> Result<()> {
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
 
-    (net_equity >= mmr, net_equity)
-}
+        let bracket_order = &mut ctx.accounts.bracket_order;
+        validate_bracket_prices(bracket_order.is_long, stop_loss_price, take_profit_price, current_price)?;
 
-fn handle_auto_deleveraging(market_state: &mut MarketState) -> Result<()> {
-    msg!("Auto-deleverage check: placeholder. In production, forcibly reduce large winning positions.");
-    Ok(())
-}
+        bracket_order.stop_loss_price = stop_loss_price;
+        bracket_order.take_profit_price = take_profit_price;
 
-/// Oracle price fetch placeholder.
-fn get_oracle_price(oracle_account: &AccountInfo) -> Result<u64> {
-    // Updated to use pyth-sdk-solana v0.8.0
-    let clock_ts_i64 = Clock::get()?.unix_timestamp;
-    // Convert i64 -> u64 safely (returning error on negative)
-    let clock_ts_u64 = u64::try_from(clock_ts_i64).map_err(|_| error!(PerpError::MathOverflow))?;
+        emit!(BracketOrderModified {
+            user: bracket_order.user,
+            market: bracket_order.market,
+            stop_loss_price,
+            take_profit_price,
+            client_order_id: bracket_order.client_order_id,
+        });
 
-    let price_feed = load_price_feed_from_account_info(oracle_account)
-        .map_err(|_| error!(PerpError::InvalidAmount))?;
+        Ok(())
+    }
 
-    //  allow up to 60 seconds of staleness, for example.
-    let max_staleness = 60;
-    let price_data = price_feed
-        .get_price_no_older_than(max_staleness, clock_ts_u64)
-        .ok_or(PerpError::InvalidAmount)?;
+    /// Cancel a bracket order and reclaim its rent.
+    pub fn cancel_bracket_order(ctx: Context<CancelBracketOrder>) -> Result<()> {
+        let bracket_order = &ctx.accounts.bracket_order;
 
-    // If price is negative, consider it invalid.
-    if price_data.price < 0 {
-        return Err(error!(PerpError::MathOverflow));
+        emit!(BracketOrderCancelled {
+            user: bracket_order.user,
+            market: bracket_order.market,
+            client_order_id: bracket_order.client_order_id,
+        });
+
+        Ok(())
     }
 
-    Ok(price_data.price as u64)
-}
+    /// Trigger bracket order if conditions met (like stop_loss or take_profit).
+    /// If one trigger condition is met, the position is closed.
+    /// The other is effectively canceled.
+    /// Permissionless: any keeper can execute and is paid the market's keeper fee.
+    /// The bracket account is closed to the user once executed.
 
+    pub fn trigger_bracket_order(ctx: Context<TriggerBracketOrder>) -> Result<()> {
+        let bracket_order = &ctx.accounts.bracket_order;
+        let user_position = &mut ctx.accounts.user_position;
+        let market_state = &mut ctx.accounts.market_state;
 
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(bracket_order.position_id == user_position.position_id, PerpError::StaleOrder);
+        check_order_expiry(bracket_order.expiry_ts, Clock::get()?.unix_timestamp)?;
 
-// =======================================
-// CONTEXTS & ACCOUNTS
-// =======================================
+        // Check current price
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let is_long = bracket_order.is_long;
+        let has_stop_loss = bracket_order.stop_loss_price != 0;
+        let has_take_profit = bracket_order.take_profit_price != 0;
+        // If is_long => stop_loss triggers if price <= bracket_order.stop_loss_price,
+        // or take_profit if price >= bracket_order.take_profit_price.
+
+        let mut triggered = false;
+        let mut is_take_profit = false;
+
+        if is_long {
+            if has_stop_loss && current_price <= bracket_order.stop_loss_price {
+                msg!("Stop loss triggered.");
+                triggered = true;
+            } else if has_take_profit && current_price >= bracket_order.take_profit_price {
+                msg!("Take profit triggered.");
+                triggered = true;
+                is_take_profit = true;
+            }
+        } else {
+            // short position
+            if has_stop_loss && current_price >= bracket_order.stop_loss_price {
+                msg!("Stop loss triggered (short). ");
+                triggered = true;
+            } else if has_take_profit && current_price <= bracket_order.take_profit_price {
+                msg!("Take profit triggered (short). ");
+                triggered = true;
+                is_take_profit = true;
+            }
+        }
+
+        // The bracket account is closed on exit, so an untriggered call must fail.
+        require!(triggered, PerpError::OrderTriggerConditionNotMet);
+
+        // If triggered, close the bracket's size (capped at the current position size).
+        // Shared with stop and trailing stop execution.
+        let (closed_size, realized_pnl, exit_price) =
+            reduce_position(user_position, market_state, bracket_order.size, current_price)?;
+        check_limit_price(!is_long, exit_price, bracket_order.limit_price)?;
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(closed_size, exit_price)?,
+            false,
+        )?;
+
+        msg!("Bracket order executed, closed {} of the position.", closed_size);
+        emit!(BracketOrderTriggered {
+            user: bracket_order.user,
+            market: bracket_order.market,
+            is_take_profit,
+            size: closed_size,
+            realized_pnl,
+            client_order_id: bracket_order.client_order_id,
+        });
+
+        pay_keeper_fee(
+            user_position,
+            market_state,
+            &vault,
+            ctx.accounts.keeper_token_account.to_account_info(),
+            ctx.accounts.keeper.key(),
+        )?;
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  ADAPTIVE FUNDING RATE BASED ON OPEN INTEREST
+    ////////////////////////////////////////////////////////////////////////////
+    /// The update_funding_rate function will be adjusted
+    /// to factor in open interest (OI) imbalance.
+
+    pub fn update_funding_rate(ctx: Context<UpdateFundingRate>) -> Result<()> {
+        let market_state = &mut ctx.accounts.market_state;
+        let now = Clock::get()?.unix_timestamp;
+
+        let time_diff = (now - market_state.last_funding_time).max(0);
+        if time_diff == 0 {
+            return Ok(());
+        }
+
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        // With the vAMM enabled its price is the mark and the oracle is the index,
+        // so funding pushes the vAMM's net position back toward the index.
+        let (mark_price, index_price) = if market_state.vamm_enabled {
+            (vamm_mark_price(market_state)?, oracle_price)
+        } else {
+            (oracle_price, market_state.index_price)
+        };
+
+        let diff = mark_price as i64 - index_price as i64;
+        // This naive formula used to do (diff / 10) * time_diff.
+        // Open interest is now factored in. If OI long > OI short, longs incur higher charges.
+
+        let oi_long = market_state.open_interest_long as i64;
+        let oi_short = market_state.open_interest_short as i64;
+        let oi_diff = oi_long.checked_sub(oi_short).unwrap_or_default();
+
+        // If oi_diff > 0 => more longs => funding rate is positive => longs pay.
+        // If oi_diff < 0 => more shorts => negative => shorts pay.
+
+        let base_rate = (diff / 10).checked_mul(time_diff as i64).unwrap_or_default();
+        // An OI factor is included, e.g., 1 basis point per 100 difference in OI.
+
+        let oi_factor = (oi_diff / 100).max(-1000).min(1000); // clamp for safety
+        let new_funding_rate = base_rate + oi_factor;
+
+        market_state.funding_rate = new_funding_rate;
+        market_state.last_funding_time = now;
+
+        emit!(FundingRateUpdated {
+            market: market_state.key(),
+            new_funding_rate,
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  VIRTUAL AMM (PRICE IMPACT FOR MARKET ORDERS)
+    ////////////////////////////////////////////////////////////////////////////
+    // Constant product vAMM: base_asset_reserve * quote_asset_reserve = sqrt_k^2.
+    // Price = quote_asset_reserve / base_asset_reserve * peg_multiplier / PEG_PRECISION.
+
+    /// Enable the vAMM for a market with equal virtual reserves of `sqrt_k`.
+    pub fn initialize_vamm(
+        ctx: Context<AdminUpdateMarket>,
+        sqrt_k: u128,
+        peg_multiplier: u128,
+    ) -> Result<()> {
+        require!(sqrt_k > 0 && peg_multiplier > 0, PerpError::InvalidAmount);
+
+        let market_state = &mut ctx.accounts.market_state;
+        require!(!market_state.vamm_enabled, PerpError::VammAlreadyEnabled);
+
+        market_state.vamm_enabled = true;
+        market_state.base_asset_reserve = sqrt_k;
+        market_state.quote_asset_reserve = sqrt_k;
+        market_state.sqrt_k = sqrt_k;
+        market_state.peg_multiplier = peg_multiplier;
+        market_state.vamm_net_base_position = 0;
+
+        msg!("vAMM initialized: sqrt_k = {}, peg = {}", sqrt_k, peg_multiplier);
+        Ok(())
+    }
+
+    /// Governance repeg: move the vAMM price by changing the peg multiplier.
+    /// Reserves (and therefore depth) are left untouched. Any increase in what the vAMM
+    /// owes traders' net position is booked against the fee vault.
+    pub fn repeg_vamm(ctx: Context<AdminUpdateVamm>, new_peg_multiplier: u128) -> Result<()> {
+        require!(new_peg_multiplier > 0, PerpError::InvalidAmount);
+
+        let fee_vault_amount = ctx.accounts.fee_vault.amount;
+        let market_state = &mut ctx.accounts.market_state;
+        require!(market_state.vamm_enabled, PerpError::VammNotEnabled);
+
+        let value_before = vamm_net_position_value(market_state)?;
+        let old_peg_multiplier = market_state.peg_multiplier;
+        market_state.peg_multiplier = new_peg_multiplier;
+        let cost = book_vamm_cost(market_state, fee_vault_amount, value_before)?;
+
+        emit!(VammRepegged {
+            market: market_state.key(),
+            old_peg_multiplier,
+            new_peg_multiplier,
+            cost,
+        });
+
+        Ok(())
+    }
+
+    /// Governance depth update: rescale both reserves to `new_sqrt_k`, keeping the price unchanged.
+    /// The change in what the vAMM owes traders is booked like a repeg.
+    pub fn update_vamm_depth(ctx: Context<AdminUpdateVamm>, new_sqrt_k: u128) -> Result<()> {
+        require!(new_sqrt_k > 0, PerpError::InvalidAmount);
+
+        let fee_vault_amount = ctx.accounts.fee_vault.amount;
+        let market_state = &mut ctx.accounts.market_state;
+        require!(market_state.vamm_enabled, PerpError::VammNotEnabled);
+
+        let value_before = vamm_net_position_value(market_state)?;
+        let old_sqrt_k = market_state.sqrt_k;
+        market_state.base_asset_reserve = market_state
+            .base_asset_reserve
+            .checked_mul(new_sqrt_k)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(old_sqrt_k)
+            .ok_or(PerpError::MathOverflow)?;
+        market_state.quote_asset_reserve = market_state
+            .quote_asset_reserve
+            .checked_mul(new_sqrt_k)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(old_sqrt_k)
+            .ok_or(PerpError::MathOverflow)?;
+        market_state.sqrt_k = new_sqrt_k;
+
+        // The net base held by traders must still be swappable back into the pool.
+        require!(
+            market_state.base_asset_reserve > market_state.vamm_net_base_position.unsigned_abs() as u128,
+            PerpError::InsufficientVammLiquidity
+        );
+        let cost = book_vamm_cost(market_state, fee_vault_amount, value_before)?;
+
+        emit!(VammDepthUpdated {
+            market: market_state.key(),
+            old_sqrt_k,
+            new_sqrt_k,
+            cost,
+        });
+
+        Ok(())
+    }
+
+    /// Set the flat fee (in quote units) paid to keepers that execute trigger orders.
+    pub fn set_keeper_fee(ctx: Context<AdminUpdateMarket>, keeper_fee: u64) -> Result<()> {
+        ctx.accounts.market_state.keeper_fee = keeper_fee;
+        msg!("Keeper fee set to {}", keeper_fee);
+        Ok(())
+    }
+
+    /// Set the price feed the market's instructions must read. Markets grown with
+    /// realloc_account have no oracle until this is called.
+    pub fn set_market_oracle(ctx: Context<AdminUpdateMarket>, oracle: Pubkey) -> Result<()> {
+        require_keys_neq!(oracle, Pubkey::default(), PerpError::InvalidOracle);
+        ctx.accounts.market_state.oracle = oracle;
+        msg!("Market oracle set to {}", oracle);
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  TRADING FEES
+    ////////////////////////////////////////////////////////////////////////////
+    // Taker/maker fees in bps of notional are charged on every open, close, trigger,
+    // fill and liquidation, and moved from the user vault into the market's fee vault.
+
+    /// Set the market's taker and maker fees (bps of notional). A negative maker fee is
+    /// a rebate and may not exceed the taker fee, so rebates are funded by taker fees.
+    pub fn set_trading_fees(
+        ctx: Context<AdminUpdateMarket>,
+        taker_fee_bps: u64,
+        maker_fee_bps: i64,
+    ) -> Result<()> {
+        validate_fee_pair(taker_fee_bps, maker_fee_bps)?;
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.taker_fee_bps = taker_fee_bps;
+        market_state.maker_fee_bps = maker_fee_bps;
+
+        msg!("Trading fees set: taker = {} bps, maker = {} bps", taker_fee_bps, maker_fee_bps);
+        Ok(())
+    }
+
+    /// Replace the market's volume-based fee tier table. Tiers must be sorted by
+    /// ascending `min_volume`; users below the first tier pay the base market fees.
+    pub fn set_fee_tiers(ctx: Context<AdminUpdateMarket>, tiers: Vec<FeeTier>) -> Result<()> {
+        require!(tiers.len() <= MAX_FEE_TIERS, PerpError::TooManyFeeTiers);
+        for (i, tier) in tiers.iter().enumerate() {
+            validate_fee_pair(tier.taker_fee_bps, tier.maker_fee_bps)?;
+            if i > 0 {
+                require!(tier.min_volume > tiers[i - 1].min_volume, PerpError::InvalidFeeTiers);
+            }
+        }
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        market_state.fee_tiers[..tiers.len()].copy_from_slice(&tiers);
+        market_state.fee_tier_count = tiers.len() as u8;
+
+        msg!("Fee tiers set: {} tiers", tiers.len());
+        Ok(())
+    }
+
+    /// Withdraw collected fees from the fee vault. Market authority only.
+    /// Unclaimed referral rewards stay in the vault.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        require!(amount > 0, PerpError::InvalidAmount);
+
+        let market_state = &ctx.accounts.market_state;
+        let available = ctx
+            .accounts
+            .fee_vault
+            .amount
+            .saturating_sub(market_state.unclaimed_referral_rewards)
+            .saturating_sub(market_state.vamm_cost_reserved);
+        require!(amount <= available, PerpError::InsufficientFeeVaultBalance);
+
+        let market_key = market_state.key();
+        transfer_from_market_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.destination.to_account_info(),
+            ctx.accounts.market_authority.to_account_info(),
+            &market_key,
+            market_state.market_authority_bump,
+            amount,
+        )?;
+
+        emit!(FeesWithdrawn {
+            market: market_key,
+            destination: ctx.accounts.destination.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  REFERRALS
+    ////////////////////////////////////////////////////////////////////////////
+    // A user registers a referrer once. Trades then get a fee discount, and a share of
+    // each fee is credited to the referrer's per-market ReferrerRewards account, claimable
+    // from the fee vault. Trades of a referred user fail without that account, so anyone
+    // can create it for the referrer.
+
+    /// Set the referrer's share of each referee fee and the referee discount (both bps).
+    pub fn set_referral_params(
+        ctx: Context<AdminUpdateMarket>,
+        referrer_fee_share_bps: u64,
+        referee_discount_bps: u64,
+    ) -> Result<()> {
+        require!(
+            referrer_fee_share_bps <= BPS_DENOMINATOR && referee_discount_bps <= BPS_DENOMINATOR,
+            PerpError::InvalidFee
+        );
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.referrer_fee_share_bps = referrer_fee_share_bps;
+        market_state.referee_discount_bps = referee_discount_bps;
+
+        msg!(
+            "Referral params set: referrer share = {} bps, referee discount = {} bps",
+            referrer_fee_share_bps,
+            referee_discount_bps
+        );
+        Ok(())
+    }
+
+    /// Register the caller's referrer. Can only be done once.
+    pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+        let user_stats = &mut ctx.accounts.user_stats;
+        require!(user_stats.referrer == Pubkey::default(), PerpError::ReferrerAlreadySet);
+
+        let referrer = ctx.accounts.referrer_stats.authority;
+        require!(referrer != user_stats.authority, PerpError::InvalidReferrer);
+        // No direct referral loops
+        require!(
+            ctx.accounts.referrer_stats.referrer != user_stats.authority,
+            PerpError::InvalidReferrer
+        );
+
+        user_stats.referrer = referrer;
+
+        emit!(ReferrerRegistered {
+            referee: user_stats.authority,
+            referrer,
+        });
+
+        Ok(())
+    }
+
+    /// Create the referrer's claimable balance for a market. Anyone can pay for it.
+    pub fn initialize_referrer_rewards(ctx: Context<InitializeReferrerRewards>) -> Result<()> {
+        let rewards = &mut ctx.accounts.referrer_rewards;
+        rewards.referrer = ctx.accounts.referrer.key();
+        rewards.market = ctx.accounts.market_state.key();
+        rewards.claimable = 0;
+        rewards.total_earned = 0;
+        rewards.bump = ctx.bumps.referrer_rewards;
+        Ok(())
+    }
+
+    /// Transfer the referrer's accrued rewards out of the fee vault.
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        let amount = ctx.accounts.referrer_rewards.claimable;
+        require!(amount > 0, PerpError::InvalidAmount);
+
+        let market_state = &mut ctx.accounts.market_state;
+        let market_key = market_state.key();
+        transfer_from_market_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.destination.to_account_info(),
+            ctx.accounts.market_authority.to_account_info(),
+            &market_key,
+            market_state.market_authority_bump,
+            amount,
+        )?;
+
+        ctx.accounts.referrer_rewards.claimable = 0;
+        market_state.unclaimed_referral_rewards =
+            market_state.unclaimed_referral_rewards.saturating_sub(amount);
+
+        emit!(ReferralRewardsClaimed {
+            referrer: ctx.accounts.referrer.key(),
+            market: market_key,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  LIQUIDITY MINING
+    ////////////////////////////////////////////////////////////////////////////
+    // Makers earn a share of each epoch's reward budget in proportion to their
+    // time-weighted resting size within `band_bps` of the oracle price. A permissionless
+    // crank samples the book; makers claim the previous epoch's rewards from a reward
+    // vault that governance funds with quote tokens.
+
+    /// Create the market's liquidity mining program and its reward vault.
+    pub fn initialize_liquidity_mining(
+        ctx: Context<InitializeLiquidityMining>,
+        epoch_duration: i64,
+        rewards_per_epoch: u64,
+        band_bps: u64,
+    ) -> Result<()> {
+        validate_liquidity_mining_params(epoch_duration, band_bps)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let config = &mut ctx.accounts.liquidity_mining;
+        config.market = ctx.accounts.market_state.key();
+        config.reward_vault = ctx.accounts.reward_vault.key();
+        config.epoch_duration = epoch_duration;
+        config.rewards_per_epoch = rewards_per_epoch;
+        config.band_bps = band_bps;
+        // Epochs start at 1 so a zeroed MakerScore never refers to a real epoch
+        config.current_epoch = 1;
+        config.epoch_start_ts = now;
+        config.last_sample_ts = now;
+        config.current_epoch_score = 0;
+        config.prev_epoch_score = 0;
+        config.prev_epoch_rewards = 0;
+        config.bump = ctx.bumps.liquidity_mining;
+
+        Ok(())
+    }
+
+    /// Update epoch length, reward budget and price band. The reward budget applies to
+    /// the epoch in progress when it ends.
+    pub fn update_liquidity_mining(
+        ctx: Context<UpdateLiquidityMining>,
+        epoch_duration: i64,
+        rewards_per_epoch: u64,
+        band_bps: u64,
+    ) -> Result<()> {
+        validate_liquidity_mining_params(epoch_duration, band_bps)?;
+
+        let config = &mut ctx.accounts.liquidity_mining;
+        config.epoch_duration = epoch_duration;
+        config.rewards_per_epoch = rewards_per_epoch;
+        config.band_bps = band_bps;
+
+        msg!(
+            "Liquidity mining updated: epoch = {}s, rewards = {}, band = {} bps",
+            epoch_duration,
+            rewards_per_epoch,
+            band_bps
+        );
+        Ok(())
+    }
+
+    /// Create the maker's score account for a market.
+    pub fn initialize_maker_score(ctx: Context<InitializeMakerScore>) -> Result<()> {
+        let maker_score = &mut ctx.accounts.maker_score;
+        maker_score.owner = ctx.accounts.owner.key();
+        maker_score.market = ctx.accounts.market_state.key();
+        maker_score.bump = ctx.bumps.maker_score;
+        Ok(())
+    }
+
+    /// Crank: credit every in-band resting order with `size * seconds since the last
+    /// sample`, then roll the epoch if it has ended. Makers' MakerScore accounts are
+    /// passed (writable) as remaining accounts; orders without one earn nothing.
+    pub fn sample_liquidity_mining<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SampleLiquidityMining<'info>>,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let market_key = ctx.accounts.market_state.key();
+        let config = &mut ctx.accounts.liquidity_mining;
+
+        let epoch_end = config
+            .epoch_start_ts
+            .checked_add(config.epoch_duration)
+            .ok_or(PerpError::MathOverflow)?;
+        let sample_end = now.min(epoch_end);
+        let elapsed = sample_end.saturating_sub(config.last_sample_ts);
+
+        if elapsed > 0 {
+            let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+            let band = bps_of(oracle_price, config.band_bps);
+            let order_book = ctx.accounts.order_book.load()?;
+
+            let orders = order_book.bids[..order_book.bid_count as usize]
+                .iter()
+                .chain(order_book.asks[..order_book.ask_count as usize].iter());
+            for order in orders {
+                if order.is_expired(now) || order.price.abs_diff(oracle_price) > band {
+                    continue;
+                }
+
+                let (score_key, _) = Pubkey::find_program_address(
+                    &[b"maker_score", market_key.as_ref(), order.owner.as_ref()],
+                    ctx.program_id,
+                );
+                let score_info = match ctx.remaining_accounts.iter().find(|acc| acc.key() == score_key) {
+                    Some(info) => info,
+                    None => continue,
+                };
+
+                let mut maker_score: Account<MakerScore> = Account::try_from(score_info)?;
+                maker_score.roll_epoch(config.current_epoch);
+                let weight = (order.size as u128)
+                    .checked_mul(elapsed as u128)
+                    .ok_or(PerpError::MathOverflow)?;
+                maker_score.score = maker_score.score.checked_add(weight).ok_or(PerpError::MathOverflow)?;
+                config.current_epoch_score = config
+                    .current_epoch_score
+                    .checked_add(weight)
+                    .ok_or(PerpError::MathOverflow)?;
+                maker_score.exit(&crate::ID)?;
+            }
+
+            config.last_sample_ts = sample_end;
+        }
+
+        if now >= epoch_end {
+            emit!(LiquidityMiningEpochEnded {
+                market: market_key,
+                epoch: config.current_epoch,
+                total_score: config.current_epoch_score,
+                rewards: config.rewards_per_epoch,
+            });
+
+            config.prev_epoch_score = config.current_epoch_score;
+            config.prev_epoch_rewards = config.rewards_per_epoch;
+            config.current_epoch += 1;
+            config.current_epoch_score = 0;
+            config.epoch_start_ts = now;
+            config.last_sample_ts = now;
+        }
+
+        Ok(())
+    }
+
+    /// Claim the maker's share of the previous epoch's rewards. Rewards not claimed
+    /// before the following epoch ends are forfeited.
+    pub fn claim_liquidity_mining_rewards(ctx: Context<ClaimLiquidityMiningRewards>) -> Result<()> {
+        let config = &ctx.accounts.liquidity_mining;
+        let maker_score = &mut ctx.accounts.maker_score;
+        maker_score.roll_epoch(config.current_epoch);
+
+        require!(
+            maker_score.pending_epoch + 1 == config.current_epoch && maker_score.pending_score > 0,
+            PerpError::NoRewardsToClaim
+        );
+
+        let reward = (config.prev_epoch_rewards as u128)
+            .checked_mul(maker_score.pending_score)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(config.prev_epoch_score)
+            .ok_or(PerpError::MathOverflow)?;
+        let reward = u64::try_from(reward)
+            .unwrap_or(u64::MAX)
+            .min(ctx.accounts.reward_vault.amount);
+        maker_score.pending_score = 0;
+
+        let market_key = ctx.accounts.market_state.key();
+        transfer_from_market_vault(
+            ctx.accounts.token_program.to_account_info(),
+            &ctx.accounts.quote_asset_mint,
+            ctx.accounts.reward_vault.to_account_info(),
+            ctx.accounts.destination.to_account_info(),
+            ctx.accounts.market_authority.to_account_info(),
+            &market_key,
+            ctx.accounts.market_state.market_authority_bump,
+            reward,
+        )?;
+
+        emit!(LiquidityMiningRewardsClaimed {
+            owner: maker_score.owner,
+            market: market_key,
+            epoch: maker_score.pending_epoch,
+            amount: reward,
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  LIQUIDATION AUTOMATION (For Future Keepers/Bots)
+    ////////////////////////////////////////////////////////////////////////////
+    ////Liquidation can be called by anyone, but it is primarily designed for a keeper.
+    /// Future integration with Switchboard could enable automatic execution of this function.
+
+    pub fn liquidate_position(ctx: Context<LiquidatePosition>, liquidation_size: u64) -> Result<()> {
+        // Maintenance-weighted value of registered collateral; oracles are remaining accounts
+        let asset_value = collateral_asset_value(
+            &ctx.accounts.user_position,
+            ctx.accounts.collateral_registry.as_deref(),
+            ctx.accounts.user_collateral.as_deref(),
+            ctx.remaining_accounts,
+            false,
+        )?;
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let market_state = &mut ctx.accounts.market_state;
+        let user_position = &mut ctx.accounts.user_position;
+
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(liquidation_size > 0, PerpError::InvalidAmount);
+        require!(liquidation_size <= user_position.size, PerpError::InvalidAmount);
+
+        // Maintenance margin on the whole position at the mark price
+        let risks = isolated_position_risks(user_position, market_state, oracle_price)?;
+        let equity = user_position.collateral.saturating_add(asset_value).min(i64::MAX as u64) as i64;
+        let (margin_ok, net_equity) = portfolio_margin_health(equity, &risks, false);
+        if margin_ok {
+            return err!(PerpError::PositionNotLiquidatable);
+        }
+
+        let discount_level_bps = market_state.dutch_auction_discount_bps;
+        let liquidator_reward_bps = 100; // 10%
+        let current_mark_price = fill_price_with_impact(
+            market_state,
+            !user_position.is_long,
+            liquidation_size,
+            oracle_price,
+        )?;
+        let direction_multiplier = if user_position.is_long { 1 } else { -1 };
+
+        let partial_pnl = (liquidation_size as i64)
+            .checked_mul((current_mark_price as i64 - user_position.entry_price as i64))
+            .ok_or(PerpError::MathOverflow)?
+            .checked_mul(direction_multiplier)
+            .ok_or(PerpError::MathOverflow)?;
+
+        let new_collateral_i64 = (user_position.collateral as i64)
+            .checked_add(partial_pnl)
+            .ok_or(PerpError::MathOverflow)?;
+
+        // Dutch Auction discount.
+        let discount_amount = (new_collateral_i64
+            .checked_mul(discount_level_bps as i64)
+            .unwrap_or(0))
+            .checked_div(1000)
+            .unwrap_or(0);
+        let discounted_collateral = new_collateral_i64.checked_sub(discount_amount).unwrap_or(0);
+        let liquidator_reward = (discount_amount
+            .checked_mul(liquidator_reward_bps as i64)
+            .unwrap_or(0))
+            .checked_div(1000)
+            .unwrap_or(0);
+
+        let final_collateral = if discounted_collateral < 0 {
+            0
+        } else {
+            discounted_collateral as u64
+        };
+
+        user_position.collateral = final_collateral;
+        user_position.size = user_position.size.checked_sub(liquidation_size).unwrap_or(0);
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(liquidation_size, current_mark_price)?,
+            false,
+        )?;
+
+        if user_position.size == 0 {
+            user_position.entry_price = 0;
+            user_position.is_long = false;
+            user_position.unrealized_pnl = 0;
+        }
+
+        if user_position.is_long {
+            market_state.open_interest_long = market_state
+                .open_interest_long
+                .checked_sub(liquidation_size)
+                .unwrap_or_default();
+        } else {
+            market_state.open_interest_short = market_state
+                .open_interest_short
+                .checked_sub(liquidation_size)
+                .unwrap_or_default();
+        }
+
+        // Increase discount for next time.
+        market_state.dutch_auction_discount_bps = market_state
+            .dutch_auction_discount_bps
+            .checked_add(50)
+            .unwrap_or(1000);
+
+        emit!(PositionLiquidated {
+            user: user_position.user,
+            market: user_position.market,
+            penalty: discount_amount,
+            liquidation_size,
+        });
+        msg!("Liquidator reward: {}", liquidator_reward);
+
+        // Potentially integrate with Switchboard here for automation.
+        if market_state.auto_deleverage_enabled {
+            handle_auto_deleveraging(market_state)?;
+        }
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  SMART LEVERAGE LIMITS (RISK & VOLATILITY)
+    ////////////////////////////////////////////////////////////////////////////
+    /// Margin is checked on notional at the mark price: initial margin at MAX_LEVERAGE
+    /// when opening, maintenance margin at the market's ratio when liquidating.
+
+    /// Open or extend a position (overridden) with new leverage check.
+    pub fn open_position(ctx: Context<OpenPosition>, is_long: bool, size: u64) -> Result<()> {
+        // Initial-weighted value of registered collateral; oracles are remaining accounts
+        let asset_value = collateral_asset_value(
+            &ctx.accounts.user_position,
+            ctx.accounts.collateral_registry.as_deref(),
+            ctx.accounts.user_collateral.as_deref(),
+            ctx.remaining_accounts,
+            true,
+        )?;
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        let market_state = &mut ctx.accounts.market_state;
+        let user_position = &mut ctx.accounts.user_position;
+
+        let fill_price = increase_position(user_position, market_state, is_long, size, oracle_price, asset_value)?;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, fill_price)?,
+        )?;
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(size, fill_price)?,
+            false,
+        )?;
+
+        emit!(PositionOpened {
+            user: user_position.user,
+            market: user_position.market,
+            size: user_position.size,
+            is_long: user_position.is_long,
+        });
+
+        Ok(())
+    }
+
+    /// Close an existing position (fully) remains the same.
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let user_position = &mut ctx.accounts.user_position;
+        let market_state = &mut ctx.accounts.market_state;
+
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        // Closing a long sells base into the vAMM, closing a short buys it back.
+        let current_mark_price = fill_price_with_impact(
+            market_state,
+            !user_position.is_long,
+            user_position.size,
+            oracle_price,
+        )?;
+        let direction_multiplier = if user_position.is_long { 1 } else { -1 };
+        let realized_pnl = (user_position.size as i64)
+            .checked_mul((current_mark_price as i64 - user_position.entry_price as i64))
+            .ok_or(PerpError::MathOverflow)?
+            .checked_mul(direction_multiplier)
+            .ok_or(PerpError::MathOverflow)?;
+
+        user_position.unrealized_pnl = realized_pnl;
+        let new_collateral = (user_position.collateral as i64)
+            .checked_add(realized_pnl)
+            .ok_or(PerpError::MathOverflow)?;
+        user_position.collateral = if new_collateral < 0 { 0 } else { new_collateral as u64 };
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        let close_notional = notional(user_position.size, current_mark_price)?;
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            close_notional,
+            false,
+        )?;
+
+        emit!(PositionClosed {
+            user: user_position.user,
+            market: user_position.market,
+            realized_pnl,
+        });
+
+        if user_position.is_long {
+            market_state.open_interest_long = market_state
+                .open_interest_long
+                .checked_sub(user_position.size)
+                .unwrap_or_default();
+        } else {
+            market_state.open_interest_short = market_state
+                .open_interest_short
+                .checked_sub(user_position.size)
+                .unwrap_or_default();
+        }
+
+        user_position.size = 0;
+        user_position.entry_price = 0;
+        user_position.is_long = false;
+        user_position.unrealized_pnl = 0;
+
+        Ok(())
+    }
+
+    /// Settle funding unchanged.
+    pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
+        let market_state = &mut ctx.accounts.market_state;
+        let user_position = &mut ctx.accounts.user_position;
+
+        let funding_payment = (user_position.size as i64)
+            .checked_mul(market_state.funding_rate)
+            .ok_or(PerpError::MathOverflow)?;
+
+        let updated_collateral = (user_position.collateral as i64)
+            .checked_add(funding_payment)
+            .ok_or(PerpError::MathOverflow)?;
+        user_position.collateral = if updated_collateral < 0 { 0 } else { updated_collateral as u64 };
+
+        emit!(FundingSettled {
+            user: user_position.user,
+            market: user_position.market,
+            funding_payment,
+        });
+
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  CENTRAL LIMIT ORDER BOOK (MAKER / TAKER)
+    ////////////////////////////////////////////////////////////////////////////
+    // Each market can have a zero-copy order book (sorted bids/asks arrays) and an
+    // event queue. Matching happens on placement; fills are pushed to the event
+    // queue and settled into UserPositions by the permissionless consume_events crank.
+
+    /// Attach an order book and event queue to a market.
+    /// Both accounts are large and must be pre-allocated (zeroed) by the client.
+    pub fn initialize_order_book(ctx: Context<InitializeOrderBook>) -> Result<()> {
+        let market_key = ctx.accounts.market_state.key();
+
+        // A market gets one book: replacing it would orphan resting orders and fills.
+        require_keys_eq!(
+            ctx.accounts.market_state.order_book,
+            Pubkey::default(),
+            PerpError::OrderBookAlreadyInitialized
+        );
+        require_keys_eq!(
+            ctx.accounts.market_state.event_queue,
+            Pubkey::default(),
+            PerpError::OrderBookAlreadyInitialized
+        );
+
+        let mut order_book = ctx.accounts.order_book.load_init()?;
+        order_book.market = market_key;
+        order_book.next_order_id = 1;
+
+        let mut event_queue = ctx.accounts.event_queue.load_init()?;
+        event_queue.market = market_key;
+
+        let market_state = &mut ctx.accounts.market_state;
+        market_state.order_book = ctx.accounts.order_book.key();
+        market_state.event_queue = ctx.accounts.event_queue.key();
+
+        msg!("Order book initialized for market {}", market_key);
+        Ok(())
+    }
+
+    /// Place an order described by `OrderParams`. Depending on the time in force, the
+    /// crossing part is matched immediately against resting orders (fills go to the
+    /// event queue) and the remainder rests on the book or is cancelled.
+    pub fn place_limit_order(ctx: Context<PlaceLimitOrder>, params: OrderParams) -> Result<()> {
+        let OrderParams {
+            is_long,
+            size,
+            time_in_force,
+            expiry_ts,
+            client_order_id,
+            self_trade_behavior,
+            ..
+        } = params;
+        let mut price = params.price;
+        require!(price > 0 && size > 0, PerpError::InvalidAmount);
+
+        let now = Clock::get()?.unix_timestamp;
+        if time_in_force == TimeInForce::GoodTillTime {
+            require!(expiry_ts > now, PerpError::OrderExpired);
+        }
+
+        // Client order ids cannot repeat within the recent window, so bots can safely
+        // retry a placement after a dropped transaction even if the order already filled.
+        ctx.accounts.user_position.record_client_order_id(client_order_id)?;
+
+        let user_position = &ctx.accounts.user_position;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, price)?,
+        )?;
+
+        let owner = ctx.accounts.user.key();
+        let market_key = ctx.accounts.market_state.key();
+        let position_key = user_position.key();
+
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+        let mut event_queue = ctx.accounts.event_queue.load_mut()?;
+
+        // Leverage check against the full order notional. Orders already resting for this
+        // position and its fills still waiting in the event queue reserve initial margin
+        // too, so resting size cannot add up past MAX_LEVERAGE before it fills.
+        let notional = (user_position.size as u128)
+            .checked_add(size as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_mul(price as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_add(order_book.open_notional(&position_key)?)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_add(event_queue.pending_notional(&position_key)?)
+            .ok_or(PerpError::MathOverflow)?;
+        let max_allowed = (user_position.collateral as u128)
+            .checked_mul(MAX_LEVERAGE as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        require!(notional <= max_allowed, PerpError::InsufficientMargin);
+
+        // Orders older than the window may still be resting, so check the book as well.
+        if client_order_id != 0 {
+            require!(
+                order_book.find(|order| order.owner == owner && order.client_order_id == client_order_id).is_none(),
+                PerpError::DuplicateClientOrderId
+            );
+        }
+
+        let order_id = order_book.next_order_id;
+        order_book.next_order_id = order_id.checked_add(1).ok_or(PerpError::MathOverflow)?;
+
+        // Expired makers are swept before anything can match against them.
+        for (expired_is_long, expired) in order_book.remove_expired(!is_long, now) {
+            emit!(LimitOrderExpired {
+                user: expired.owner,
+                market: market_key,
+                order_id: expired.order_id,
+                client_order_id: expired.client_order_id,
+                is_long: expired_is_long,
+                remaining_size: expired.size,
+            });
+        }
+
+        let best_opposite = order_book.best(!is_long).map(|order| order.price);
+        let crosses = |resting_price: u64, limit: u64| {
+            if is_long { resting_price <= limit } else { resting_price >= limit }
+        };
+
+        match time_in_force {
+            TimeInForce::PostOnly => {
+                if let Some(best) = best_opposite {
+                    require!(!crosses(best, price), PerpError::PostOnlyWouldCross);
+                }
+            }
+            TimeInForce::PostOnlySlide => {
+                // Reprice one tick behind the best opposite order instead of crossing.
+                if let Some(best) = best_opposite {
+                    if crosses(best, price) {
+                        price = if is_long { best.checked_sub(1) } else { best.checked_add(1) }
+                            .filter(|p| *p > 0)
+                            .ok_or(PerpError::PostOnlyWouldCross)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // Match against the opposite side, best price first.
+        let mut remaining = size;
+        let mut filled_size = 0u64;
+        {
+            let (orders, count) = order_book.side_mut(!is_long);
+            while remaining > 0 && *count > 0 {
+                let best = orders[0];
+                if !crosses(best.price, price) {
+                    break;
+                }
+
+                // Self-trade prevention: the taker's mode decides what happens when it
+                // would match one of the same owner's resting orders.
+                if best.owner == owner {
+                    let cancel_maker = match self_trade_behavior {
+                        SelfTradeBehavior::CancelMaker | SelfTradeBehavior::CancelBoth => true,
+                        SelfTradeBehavior::CancelTaker => false,
+                        SelfTradeBehavior::DecrementAndCancel => {
+                            // Shrink both sides by the overlap without trading;
+                            // whichever side is smaller is cancelled.
+                            let overlap = remaining.min(best.size);
+                            remaining -= overlap;
+                            if overlap < best.size {
+                                orders[0].size -= overlap;
+                                false
+                            } else {
+                                true
+                            }
+                        }
+                    };
+                    if cancel_maker {
+                        emit_order_cancelled(market_key, !is_long, &best);
+                        OrderBook::remove_at(orders, count, 0);
+                    }
+                    if matches!(
+                        self_trade_behavior,
+                        SelfTradeBehavior::CancelTaker | SelfTradeBehavior::CancelBoth
+                    ) {
+                        remaining = 0;
+                    }
+                    continue;
+                }
+
+                let fill_size = remaining.min(best.size);
+                event_queue.push(FillEvent {
+                    maker_owner: best.owner,
+                    maker_position: best.user_position,
+                    taker_owner: owner,
+                    taker_position: position_key,
+                    maker_order_id: best.order_id,
+                    maker_client_order_id: best.client_order_id,
+                    price: best.price,
+                    size: fill_size,
+                    timestamp: now,
+                    taker_is_long: is_long as u8,
+                    _padding: [0; 7],
+                })?;
+
+                remaining -= fill_size;
+                filled_size += fill_size;
+                if fill_size == best.size {
+                    OrderBook::remove_at(orders, count, 0);
+                } else {
+                    orders[0].size -= fill_size;
+                }
+            }
+        }
+
+        // Nothing is committed if a fill-or-kill order did not fill completely.
+        if time_in_force == TimeInForce::FillOrKill {
+            require!(filled_size == size, PerpError::FillOrKillNotFilled);
+        }
+
+        // Rest whatever did not cross, unless the order is immediate.
+        if remaining > 0 && time_in_force.can_rest() {
+            order_book.insert(
+                is_long,
+                RestingOrder {
+                    order_id,
+                    owner,
+                    user_position: position_key,
+                    price,
+                    size: remaining,
+                    expiry_ts: if time_in_force == TimeInForce::GoodTillTime { expiry_ts } else { 0 },
+                    client_order_id,
+                },
+            )?;
+        }
+
+        emit!(LimitOrderPlaced {
+            user: owner,
+            market: market_key,
+            order_id,
+            client_order_id,
+            is_long,
+            price,
+            size,
+            filled_size,
+            time_in_force,
+        });
+
+        Ok(())
+    }
+
+    /// Cancel a resting order owned by the signer by its exchange-assigned order id.
+    pub fn cancel_order(ctx: Context<CancelOrder>, order_id: u64) -> Result<()> {
+        let owner = ctx.accounts.user.key();
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let (is_long, order) = order_book
+            .remove_by(|order| order.order_id == order_id)
+            .ok_or(PerpError::OrderNotFound)?;
+        require_keys_eq!(order.owner, owner, PerpError::Unauthorized);
+
+        emit_order_cancelled(ctx.accounts.market_state.key(), is_long, &order);
+        Ok(())
+    }
+
+    /// Cancel a resting order owned by the signer by its user-supplied client order id.
+    pub fn cancel_order_by_client_id(ctx: Context<CancelOrder>, client_order_id: u64) -> Result<()> {
+        require!(client_order_id != 0, PerpError::InvalidAmount);
+
+        let owner = ctx.accounts.user.key();
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let (is_long, order) = order_book
+            .remove_by(|order| order.owner == owner && order.client_order_id == client_order_id)
+            .ok_or(PerpError::OrderNotFound)?;
+
+        emit_order_cancelled(ctx.accounts.market_state.key(), is_long, &order);
+        Ok(())
+    }
+
+    /// Cancel every resting order of the signer in this market, optionally only one side.
+    pub fn cancel_all_orders(ctx: Context<CancelOrder>, is_long: Option<bool>) -> Result<()> {
+        let owner = ctx.accounts.user.key();
+        let market_key = ctx.accounts.market_state.key();
+        let mut order_book = ctx.accounts.order_book.load_mut()?;
+
+        let mut cancelled = 0u32;
+        for side in [true, false] {
+            if is_long.is_some_and(|only| only != side) {
+                continue;
+            }
+            for (side_is_long, order) in order_book.remove_where(side, |order| order.owner == owner) {
+                emit_order_cancelled(market_key, side_is_long, &order);
+                cancelled += 1;
+            }
+        }
+
+        msg!("Cancelled {} orders", cancelled);
+        Ok(())
+    }
+
+    /// Crank: settle up to `limit` fills from the event queue into the maker and taker
+    /// UserPositions. Fills whose positions are not passed are skipped and stay queued.
+    /// Positions must be passed (writable) as remaining accounts together
+    /// with their owners' user vaults, vault authorities and UserStats (for
+    /// maker/taker fees), and
+    /// their referrers' ReferrerRewards when referred.
+    pub fn consume_events<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ConsumeEvents<'info>>,
+        limit: u16,
+    ) -> Result<()> {
+        let market_state = &mut ctx.accounts.market_state;
+        let market_key = market_state.key();
+        let mut event_queue = ctx.accounts.event_queue.load_mut()?;
+
+        let mut consumed = 0u16;
+        // Positions with a fill held back in this call. Their later fills are held back
+        // too, so every position still sees its fills in queue order.
+        let mut held_positions: Vec<Pubkey> = Vec::new();
+        let mut unexamined = event_queue.count;
+        while consumed < limit && unexamined > 0 {
+            unexamined -= 1;
+            let event = event_queue.pop()?;
+
+            let maker_info = ctx
+                .remaining_accounts
+                .iter()
+                .find(|acc| acc.key() == event.maker_position);
+            let taker_info = ctx
+                .remaining_accounts
+                .iter()
+                .find(|acc| acc.key() == event.taker_position);
+            let held = held_positions
+                .iter()
+                .any(|key| *key == event.maker_position || *key == event.taker_position);
+            let (maker_info, taker_info) = match (maker_info, taker_info) {
+                (Some(maker), Some(taker)) if !held => (maker, taker),
+                // Skip events whose accounts were not provided instead of stalling the
+                // queue behind them; they stay queued for a later crank.
+                _ => {
+                    held_positions.push(event.maker_position);
+                    held_positions.push(event.taker_position);
+                    event_queue.requeue(event)?;
+                    continue;
+                }
+            };
+
+            let taker_is_long = event.taker_is_long != 0;
+            let fill_notional = notional(event.size, event.price)?;
+            // The taker pays first so its fee can fund the maker rebate.
+            for (info, is_long, is_maker) in [
+                (taker_info, taker_is_long, false),
+                (maker_info, !taker_is_long, true),
+            ] {
+                let mut position: Account<UserPosition> = Account::try_from(info)?;
+                require_keys_eq!(position.market, market_key, PerpError::InvalidMarket);
+                apply_fill_to_position(&mut position, market_state, is_long, event.size, event.price)?;
+
+                // Fees are paid from the owner's vault, which must also be passed
+                // as a remaining account together with its vault authority.
+                let (vault_key, _) = Pubkey::find_program_address(
+                    &[b"user_vault", position.user.as_ref(), market_key.as_ref()],
+                    ctx.program_id,
+                );
+                let (vault_authority_key, vault_authority_bump) = Pubkey::find_program_address(
+                    &[b"vault_authority", position.user.as_ref(), market_key.as_ref()],
+                    ctx.program_id,
+                );
+                let vault_info = ctx
+                    .remaining_accounts
+                    .iter()
+                    .find(|acc| acc.key() == vault_key)
+                    .ok_or(PerpError::MissingRemainingAccount)?;
+                let vault_authority_info = ctx
+                    .remaining_accounts
+                    .iter()
+                    .find(|acc| acc.key() == vault_authority_key)
+                    .ok_or(PerpError::MissingRemainingAccount)?;
+                let vault = UserVaultSigner {
+                    mint: ctx.accounts.quote_asset_mint.to_account_info(),
+                    decimals: ctx.accounts.quote_asset_mint.decimals,
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    user_vault: vault_info.clone(),
+                    user_vault_authority: vault_authority_info.clone(),
+                    vault_authority_bump,
+                };
+                let (stats_key, _) = Pubkey::find_program_address(
+                    &[b"user_stats", position.user.as_ref()],
+                    ctx.program_id,
+                );
+                let stats_info = ctx
+                    .remaining_accounts
+                    .iter()
+                    .find(|acc| acc.key() == stats_key)
+                    .ok_or(PerpError::MissingRemainingAccount)?;
+                let mut user_stats: Account<UserStats> = Account::try_from(stats_info)?;
+
+                // Referred owners must also pass their referrer's rewards account.
+                let mut referrer_rewards = None;
+                if user_stats.referrer != Pubkey::default() {
+                    let (rewards_key, _) = Pubkey::find_program_address(
+                        &[b"referrer_rewards", user_stats.referrer.as_ref(), market_key.as_ref()],
+                        ctx.program_id,
+                    );
+                    let info = ctx
+                        .remaining_accounts
+                        .iter()
+                        .find(|acc| acc.key() == rewards_key)
+                        .ok_or(PerpError::MissingReferrerRewards)?;
+                    referrer_rewards = Some(Account::<ReferrerRewards>::try_from(info)?);
+                }
+
+                let fee = charge_trading_fee(
+                    &mut position,
+                    &mut user_stats,
+                    referrer_rewards.as_deref_mut(),
+                    market_state,
+                    &vault,
+                    ctx.accounts.fee_vault.to_account_info(),
+                    fill_notional,
+                    is_maker,
+                )?;
+                if fee < 0 {
+                    pay_maker_rebate(
+                        &mut position,
+                        market_state,
+                        ctx.accounts.token_program.to_account_info(),
+                        &ctx.accounts.quote_asset_mint,
+                        &mut ctx.accounts.fee_vault,
+                        vault_info.clone(),
+                        ctx.accounts.market_authority.to_account_info(),
+                        fee.unsigned_abs(),
+                    )?;
+                }
+
+                if let Some(rewards) = referrer_rewards {
+                    rewards.exit(&crate::ID)?;
+                }
+                user_stats.exit(&crate::ID)?;
+                position.exit(&crate::ID)?;
+            }
+
+            emit!(OrderFilled {
+                market: market_key,
+                maker: event.maker_owner,
+                taker: event.taker_owner,
+                maker_order_id: event.maker_order_id,
+                maker_client_order_id: event.maker_client_order_id,
+                price: event.price,
+                size: event.size,
+                taker_is_long,
+            });
+
+            consumed += 1;
+        }
+
+        // Rotate the events this call did not reach behind the held-back ones, which
+        // restores the original queue order.
+        for _ in 0..unexamined {
+            let event = event_queue.pop()?;
+            event_queue.requeue(event)?;
+        }
+
+        msg!("Consumed {} fill events", consumed);
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  SINGLE-LEG STOP-LOSS / TAKE-PROFIT ORDERS
+    ////////////////////////////////////////////////////////////////////////////
+    // The simpler alternative to bracket orders. Each stop order lives in its own PDA
+    // seeded by user, market and the position's order sequence number, and closes
+    // `size` of the position (capped at the current size) when triggered.
+
+    /// Place a stop-loss (`is_take_profit = false`) or take-profit order on the open position.
+    /// `params.is_long` is the closing side and `params.price` the worst fill price (0 => none).
+    pub fn place_stop_order(
+        ctx: Context<PlaceStopOrder>,
+        params: OrderParams,
+        trigger_price: u64,
+        is_take_profit: bool,
+    ) -> Result<()> {
+        let expiry_ts = validate_conditional_params(&params, Clock::get()?.unix_timestamp)?;
+        let OrderParams { size, client_order_id, .. } = params;
+        require!(trigger_price > 0, PerpError::InvalidAmount);
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, trigger_price)?,
+        )?;
+
+        let user_position = &mut ctx.accounts.user_position;
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(params.is_long != user_position.is_long, PerpError::InvalidOrderSide);
+        require!(size <= user_position.size, PerpError::InvalidAmount);
+        user_position.record_client_order_id(client_order_id)?;
+
+        let stop_order = &mut ctx.accounts.stop_order;
+        stop_order.user = ctx.accounts.user.key();
+        stop_order.market = ctx.accounts.market_state.key();
+        stop_order.trigger_price = trigger_price;
+        stop_order.is_take_profit = is_take_profit;
+        stop_order.size = size;
+        stop_order.is_long = user_position.is_long;
+        stop_order.client_order_id = client_order_id;
+        stop_order.order_seq = user_position.next_order_seq;
+        stop_order.bump = ctx.bumps.stop_order;
+        stop_order.payer = ctx.accounts.authority.key();
+        stop_order.position_id = user_position.position_id;
+        stop_order.limit_price = params.price;
+        stop_order.expiry_ts = expiry_ts;
+
+        user_position.next_order_seq = user_position
+            .next_order_seq
+            .checked_add(1)
+            .ok_or(PerpError::MathOverflow)?;
+
+        emit!(StopOrderPlaced {
+            user: stop_order.user,
+            market: stop_order.market,
+            trigger_price,
+            is_take_profit,
+            size,
+            client_order_id,
+        });
+
+        Ok(())
+    }
+
+    /// Trigger a stop order once the oracle price crosses its trigger price.
+    /// Closes the configured size and closes the stop order account.
+    /// Permissionless: any keeper can execute and is paid the market's keeper fee.
+    pub fn trigger_stop_order(ctx: Context<TriggerStopOrder>) -> Result<()> {
+        let stop_order = &ctx.accounts.stop_order;
+        let user_position = &mut ctx.accounts.user_position;
+        let market_state = &mut ctx.accounts.market_state;
+
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(stop_order.position_id == user_position.position_id, PerpError::StaleOrder);
+
+        check_order_expiry(stop_order.expiry_ts, Clock::get()?.unix_timestamp)?;
+
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        require!(stop_order.is_triggered(current_price), PerpError::OrderTriggerConditionNotMet);
+
+        let (closed_size, realized_pnl, exit_price) =
+            reduce_position(user_position, market_state, stop_order.size, current_price)?;
+        check_limit_price(!stop_order.is_long, exit_price, stop_order.limit_price)?;
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(closed_size, exit_price)?,
+            false,
+        )?;
+
+        emit!(StopOrderTriggered {
+            user: stop_order.user,
+            market: stop_order.market,
+            size: closed_size,
+            realized_pnl,
+        });
+
+        pay_keeper_fee(
+            user_position,
+            market_state,
+            &vault,
+            ctx.accounts.keeper_token_account.to_account_info(),
+            ctx.accounts.keeper.key(),
+        )?;
+        Ok(())
+    }
+
+    /// Cancel a stop order and reclaim its rent.
+    pub fn cancel_stop_order(ctx: Context<CancelStopOrder>) -> Result<()> {
+        let stop_order = &ctx.accounts.stop_order;
+
+        emit!(StopOrderCancelled {
+            user: stop_order.user,
+            market: stop_order.market,
+            client_order_id: stop_order.client_order_id,
+        });
+
+        Ok(())
+    }
+
+    /// Place a trailing stop. The trigger follows the best price seen since placement
+    /// at `trail_amount` (absolute, or in bps of the watermark when `trail_is_bps`).
+    /// `params.is_long` is the closing side and `params.price` the worst fill price (0 => none).
+    pub fn place_trailing_stop_order(
+        ctx: Context<PlaceTrailingStopOrder>,
+        params: OrderParams,
+        trail_amount: u64,
+        trail_is_bps: bool,
+    ) -> Result<()> {
+        let expiry_ts = validate_conditional_params(&params, Clock::get()?.unix_timestamp)?;
+        let OrderParams { size, client_order_id, .. } = params;
+        require!(trail_amount > 0, PerpError::InvalidAmount);
+        if trail_is_bps {
+            require!(trail_amount < BPS_DENOMINATOR, PerpError::InvalidAmount);
+        }
+
+        let user_position = &mut ctx.accounts.user_position;
+        require!(user_position.size > 0, PerpError::NoOpenPosition);
+        require!(params.is_long != user_position.is_long, PerpError::InvalidOrderSide);
+        user_position.record_client_order_id(client_order_id)?;
+
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &ctx.accounts.authority.key(),
+            notional(size, current_price)?,
+        )?;
+
+        let stop_order = &mut ctx.accounts.stop_order;
+        stop_order.user = ctx.accounts.user.key();
+        stop_order.market = ctx.accounts.market_state.key();
+        stop_order.is_take_profit = false;
+        stop_order.size = size;
+        stop_order.is_long = user_position.is_long;
+        stop_order.client_order_id = client_order_id;
+        stop_order.order_seq = user_position.next_order_seq;
+        stop_order.bump = ctx.bumps.stop_order;
+        stop_order.payer = ctx.accounts.authority.key();
+        stop_order.position_id = user_position.position_id;
+        stop_order.limit_price = params.price;
+        stop_order.expiry_ts = expiry_ts;
+        stop_order.is_trailing = true;
+        stop_order.trail_amount = trail_amount;
+        stop_order.trail_is_bps = trail_is_bps;
+        stop_order.watermark_price = current_price;
+        stop_order.trigger_price = stop_order.trailing_trigger_price()?;
+
+        user_position.next_order_seq = user_position
+            .next_order_seq
+            .checked_add(1)
+            .ok_or(PerpError::MathOverflow)?;
+
+        emit!(StopOrderPlaced {
+            user: stop_order.user,
+            market: stop_order.market,
+            trigger_price: stop_order.trigger_price,
+            is_take_profit: false,
+            size,
+            client_order_id,
+        });
+
+        Ok(())
+    }
+
+    /// Keeper crank: move a trailing stop's watermark (and trigger price) to the best
+    /// oracle price seen so far. Execution goes through trigger_stop_order.
+    pub fn update_trailing_stop(ctx: Context<UpdateTrailingStop>) -> Result<()> {
+        let current_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+
+        let stop_order = &mut ctx.accounts.stop_order;
+        require!(stop_order.is_trailing, PerpError::NotTrailingStop);
+
+        if stop_order.update_watermark(current_price)? {
+            emit!(TrailingStopUpdated {
+                user: stop_order.user,
+                market: stop_order.market,
+                watermark_price: stop_order.watermark_price,
+                trigger_price: stop_order.trigger_price,
+            });
+        }
+
+        Ok(())
+    }
+
+    // The bracket order offers a more advanced approach, while both options can coexist.  
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  TWAP / SCHEDULED EXECUTION
+    ////////////////////////////////////////////////////////////////////////////
+    // A TWAP order splits `total_size` into `num_slices` executed at most once per
+    // `interval_seconds` by a permissionless crank. Slices in the position's direction
+    // go through the open path, slices against it through the close path; both fill at
+    // the market's oracle price (with vAMM impact when enabled).
+
+    /// Place a TWAP order for `params.size` in the `params.is_long` direction, with
+    /// `params.price` as the worst acceptable fill price (0 => no limit).
+    pub fn place_twap_order(
+        ctx: Context<PlaceTwapOrder>,
+        params: OrderParams,
+        num_slices: u32,
+        interval_seconds: i64,
+    ) -> Result<()> {
+        let expiry_ts = validate_conditional_params(&params, Clock::get()?.unix_timestamp)?;
+        let OrderParams {
+            is_long,
+            size: total_size,
+            price: limit_price,
+            client_order_id,
+            ..
+        } = params;
+        require!(num_slices > 0, PerpError::InvalidAmount);
+        require!(total_size >= num_slices as u64, PerpError::InvalidAmount);
+        require!(interval_seconds > 0, PerpError::InvalidAmount);
+
+        // A session key's per-trade cap applies to every slice; the crank enforces it at
+        // each slice's fill price, and the largest slice is checked here against the limit.
+        let authority = ctx.accounts.authority.key();
+        let session_max_notional = match ctx.accounts.session_key.as_deref().filter(|key| key.signer == authority) {
+            Some(key) => {
+                require!(key.max_notional > 0, PerpError::SessionNotionalExceeded);
+                key.max_notional
+            }
+            None => 0,
+        };
+        let largest_slice = total_size / num_slices as u64 + total_size % num_slices as u64;
+        check_session_notional(
+            ctx.accounts.session_key.as_deref(),
+            &authority,
+            notional(largest_slice, limit_price)?,
+        )?;
+
+        let user_position = &mut ctx.accounts.user_position;
+        user_position.record_client_order_id(client_order_id)?;
+
+        let twap_order = &mut ctx.accounts.twap_order;
+        twap_order.user = ctx.accounts.user.key();
+        twap_order.market = ctx.accounts.market_state.key();
+        twap_order.is_long = is_long;
+        twap_order.total_size = total_size;
+        twap_order.executed_size = 0;
+        twap_order.num_slices = num_slices;
+        twap_order.slices_executed = 0;
+        twap_order.interval_seconds = interval_seconds;
+        twap_order.limit_price = limit_price;
+        twap_order.last_execution_ts = 0;
+        twap_order.client_order_id = client_order_id;
+        twap_order.order_seq = user_position.next_order_seq;
+        twap_order.bump = ctx.bumps.twap_order;
+        twap_order.payer = ctx.accounts.authority.key();
+        twap_order.expiry_ts = expiry_ts;
+        twap_order.session_max_notional = session_max_notional;
+
+        user_position.next_order_seq = user_position
+            .next_order_seq
+            .checked_add(1)
+            .ok_or(PerpError::MathOverflow)?;
+
+        emit!(TwapOrderPlaced {
+            user: twap_order.user,
+            market: twap_order.market,
+            is_long,
+            total_size,
+            num_slices,
+            interval_seconds,
+            limit_price,
+            client_order_id,
+        });
+
+        Ok(())
+    }
+
+    /// Keeper crank: execute the next TWAP slice if its interval has elapsed and the
+    /// fill price is within the limit. Pays the keeper fee; closes the order after the last slice.
+    pub fn execute_twap_slice(ctx: Context<ExecuteTwapSlice>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let twap_order = &mut ctx.accounts.twap_order;
+        let user_position = &mut ctx.accounts.user_position;
+        let market_state = &mut ctx.accounts.market_state;
+
+        require!(twap_order.slices_executed < twap_order.num_slices, PerpError::TwapCompleted);
+        check_order_expiry(twap_order.expiry_ts, now)?;
+        if twap_order.last_execution_ts != 0 {
+            let next_execution_ts = twap_order
+                .last_execution_ts
+                .checked_add(twap_order.interval_seconds)
+                .ok_or(PerpError::MathOverflow)?;
+            require!(now >= next_execution_ts, PerpError::TwapIntervalNotElapsed);
+        }
+
+        let slice_size = twap_order.next_slice_size();
+        let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+
+        let opens = user_position.size == 0 || user_position.is_long == twap_order.is_long;
+        let (filled_size, fill_price) = if opens {
+            let fill_price =
+                increase_position(user_position, market_state, twap_order.is_long, slice_size, oracle_price, 0)?;
+            (slice_size, fill_price)
+        } else {
+            // Reducing slices never flip the position; they are capped at its size.
+            let reduce_size = slice_size.min(user_position.size);
+            let exit_price = fill_price_with_impact(market_state, twap_order.is_long, reduce_size, oracle_price)?;
+            apply_fill_to_position(user_position, market_state, twap_order.is_long, reduce_size, exit_price)?;
+            (reduce_size, exit_price)
+        };
+
+        check_limit_price(twap_order.is_long, fill_price, twap_order.limit_price)?;
+        if twap_order.session_max_notional > 0 {
+            require!(
+                notional(filled_size, fill_price)? <= twap_order.session_max_notional as u128,
+                PerpError::SessionNotionalExceeded
+            );
+        }
+
+        let vault = UserVaultSigner {
+            mint: ctx.accounts.quote_asset_mint.to_account_info(),
+            decimals: ctx.accounts.quote_asset_mint.decimals,
+            token_program: ctx.accounts.token_program.to_account_info(),
+            user_vault: ctx.accounts.user_vault.to_account_info(),
+            user_vault_authority: ctx.accounts.user_vault_authority.to_account_info(),
+            vault_authority_bump: ctx.bumps.user_vault_authority,
+        };
+        charge_trading_fee(
+            user_position,
+            &mut ctx.accounts.user_stats,
+            ctx.accounts.referrer_rewards.as_deref_mut(),
+            market_state,
+            &vault,
+            ctx.accounts.fee_vault.to_account_info(),
+            notional(filled_size, fill_price)?,
+            false,
+        )?;
+
+        twap_order.executed_size = twap_order
+            .executed_size
+            .checked_add(filled_size)
+            .ok_or(PerpError::MathOverflow)?;
+        twap_order.slices_executed += 1;
+        twap_order.last_execution_ts = now;
+
+        emit!(TwapSliceExecuted {
+            user: twap_order.user,
+            market: twap_order.market,
+            slice_size: filled_size,
+            fill_price,
+            slices_executed: twap_order.slices_executed,
+            num_slices: twap_order.num_slices,
+        });
+
+        pay_keeper_fee(
+            user_position,
+            market_state,
+            &vault,
+            ctx.accounts.keeper_token_account.to_account_info(),
+            ctx.accounts.keeper.key(),
+        )?;
+
+        if twap_order.slices_executed == twap_order.num_slices {
+            ctx.accounts.twap_order.close(ctx.accounts.rent_receiver.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the remaining slices of a TWAP order and reclaim its rent.
+    pub fn cancel_twap_order(ctx: Context<CancelTwapOrder>) -> Result<()> {
+        let twap_order = &ctx.accounts.twap_order;
+
+        emit!(TwapOrderCancelled {
+            user: twap_order.user,
+            market: twap_order.market,
+            executed_size: twap_order.executed_size,
+            client_order_id: twap_order.client_order_id,
+        });
+
+        Ok(())
+    }
+
+    /// Cancel the owner's stop, bracket or TWAP order in this market with the given
+    /// client order id. Candidate orders are passed as remaining accounts.
+    pub fn cancel_conditional_order_by_client_id<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CancelConditionalOrders<'info>>,
+        client_order_id: u64,
+    ) -> Result<()> {
+        require!(client_order_id != 0, PerpError::InvalidAmount);
+
+        let cancelled = cancel_conditional_orders(
+            &ctx.accounts.user,
+            &ctx.accounts.market_state.key(),
+            ctx.remaining_accounts,
+            Some(client_order_id),
+        )?;
+        require!(cancelled > 0, PerpError::OrderNotFound);
+        Ok(())
+    }
+
+    /// Cancel every stop, bracket and TWAP order of the owner in this market passed as
+    /// remaining accounts, reclaiming their rent.
+    pub fn cancel_all_conditional_orders<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CancelConditionalOrders<'info>>,
+    ) -> Result<()> {
+        let cancelled = cancel_conditional_orders(
+            &ctx.accounts.user,
+            &ctx.accounts.market_state.key(),
+            ctx.remaining_accounts,
+            None,
+        )?;
+
+        msg!("Cancelled {} conditional orders", cancelled);
+        Ok(())
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    //  ACCOUNT MIGRATIONS
+    ////////////////////////////////////////////////////////////////////////////
+    // Fields are only ever appended to account layouts, so an account created under an
+    // older version is migrated by growing it to the current size; the appended fields
+    // read as zero. Markets from before the fee vault existed also need their fee
+    // settings and fee vault filled in, which migrate_legacy_market does.
+
+    /// Grow an account created under an older layout to the current one.
+    /// Permissionless; `payer` funds the extra rent.
+    pub fn realloc_account(ctx: Context<ReallocAccount>) -> Result<()> {
+        let account = ctx.accounts.account.to_account_info();
+        let old_len = account.data_len();
+        let new_len = current_layout_len(&account)?;
+        require!(old_len < new_len, PerpError::AccountAlreadyMigrated);
+
+        grow_account(
+            &account,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            new_len,
+        )?;
+
+        emit!(AccountReallocated {
+            account: account.key(),
+            old_len: old_len as u64,
+            new_len: new_len as u64,
+        });
+
+        Ok(())
+    }
+
+    /// Migrate a market created under the original layout: grow it to the current size,
+    /// create its fee vault, apply the default trading fees and record its oracle.
+    pub fn migrate_legacy_market(ctx: Context<MigrateLegacyMarket>) -> Result<()> {
+        let info = ctx.accounts.market_state.to_account_info();
+        require!(
+            info.data_len() == 8 + MarketState::LEGACY_SIZE,
+            PerpError::AccountAlreadyMigrated
+        );
+
+        grow_account(
+            &info,
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            8 + MarketState::MAX_SIZE,
+        )?;
+
+        let mut market_state = {
+            let data = info.try_borrow_data()?;
+            MarketState::try_deserialize(&mut &data[..])?
+        };
+        require_keys_eq!(
+            market_state.authority,
+            ctx.accounts.authority.key(),
+            PerpError::Unauthorized
+        );
+        require_keys_eq!(
+            market_state.quote_asset_mint,
+            ctx.accounts.quote_asset_mint.key(),
+            PerpError::InvalidMint
+        );
+
+        market_state.fee_vault = ctx.accounts.fee_vault.key();
+        market_state.taker_fee_bps = DEFAULT_TAKER_FEE_BPS;
+        market_state.maker_fee_bps = DEFAULT_MAKER_FEE_BPS;
+        market_state.market_authority_bump = ctx.bumps.market_authority;
+        market_state.oracle = ctx.accounts.oracle_price_feed_account.key();
+        {
+            let mut data = info.try_borrow_mut_data()?;
+            market_state.try_serialize(&mut &mut data[..])?;
+        }
+
+        emit!(LegacyMarketMigrated {
+            market: info.key(),
+            fee_vault: market_state.fee_vault,
+        });
+
+        Ok(())
+    }
+
+}
+
+// =======================================
+// HELPERS & INTERNAL LOGIC
+// =======================================
+
+/// Largest quote collateral withdrawal that leaves the position at initial margin at the
+/// market's oracle price, counting registered collateral assets at their initial weights.
+fn withdrawable_collateral(ctx: &Context<WithdrawCollateral>) -> Result<u64> {
+    let user_position = &ctx.accounts.user_position;
+    let asset_value = collateral_asset_value(
+        user_position,
+        ctx.accounts.collateral_registry.as_deref(),
+        ctx.accounts.user_collateral.as_deref(),
+        ctx.remaining_accounts,
+        true,
+    )?;
+    let oracle_price = get_oracle_price(&ctx.accounts.oracle_price_feed_account)?;
+    let risks = isolated_position_risks(user_position, &ctx.accounts.market_state, oracle_price)?;
+    Ok(max_withdrawable(
+        user_position.collateral.saturating_add(asset_value),
+        user_position.collateral,
+        &risks,
+    ))
+}
+
+/// Credit a quote deposit to `user`'s position, creating the position and the user's
+/// UserStats on first deposit.
+fn record_deposit(
+    user_position: &mut UserPosition,
+    user_stats: &mut UserStats,
+    user_stats_bump: u8,
+    user: Pubkey,
+    market: Pubkey,
+    amount: u64,
+) -> Result<()> {
+    user_position.user = user;
+    user_position.market = market;
+    user_position.collateral = user_position
+        .collateral
+        .checked_add(amount)
+        .ok_or(PerpError::MathOverflow)?;
+
+    // Volume stats are per user and shared across markets
+    if user_stats.authority == Pubkey::default() {
+        user_stats.authority = user;
+        user_stats.last_volume_update_ts = Clock::get()?.unix_timestamp;
+        user_stats.bump = user_stats_bump;
+    }
+
+    emit!(CollateralDeposited { user, amount });
+
+    Ok(())
+}
+
+/// Whether `signer` may run an instruction needing `permission` in `market` for
+/// `owner`: the owner itself, the delegate set in the owner's UserStats, or an
+/// unexpired session key of the owner scoped to the market and instruction.
+fn is_trading_authority(
+    owner: &Pubkey,
+    signer: &Pubkey,
+    user_stats: Option<&UserStats>,
+    session_key: Option<&SessionKey>,
+    market: &Pubkey,
+    permission: u16,
+) -> bool {
+    if signer == owner {
+        return true;
+    }
+    let is_delegate = user_stats.is_some_and(|stats| {
+        stats.authority == *owner && stats.delegate != Pubkey::default() && stats.delegate == *signer
+    });
+    if is_delegate {
+        return true;
+    }
+    let now = match Clock::get() {
+        Ok(clock) => clock.unix_timestamp,
+        Err(_) => return false,
+    };
+    session_key.is_some_and(|key| {
+        key.owner == *owner && key.signer == *signer && key.allows(market, permission, now)
+    })
+}
+
+/// Enforce a session key's per-trade notional cap when the session key is the signer.
+fn check_session_notional(session_key: Option<&SessionKey>, signer: &Pubkey, notional: u128) -> Result<()> {
+    if let Some(key) = session_key.filter(|key| key.signer == *signer) {
+        require!(notional <= key.max_notional as u128, PerpError::SessionNotionalExceeded);
+    }
+    Ok(())
+}
+
+/// Transfer `amount` from the user into a vault and return the amount actually
+/// received, which is lower for Token-2022 mints with a transfer fee.
+fn deposit_to_vault<'info>(
+    token_program: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: AccountInfo<'info>,
+    vault: &mut InterfaceAccount<'info, TokenAccount>,
+    authority: AccountInfo<'info>,
+    amount: u64,
+) -> Result<u64> {
+    let balance_before = vault.amount;
+
+    let cpi_accounts = TransferChecked {
+        from,
+        mint: mint.to_account_info(),
+        to: vault.to_account_info(),
+        authority,
+    };
+    let cpi_ctx = CpiContext::new(token_program, cpi_accounts);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)?;
+
+    vault.reload()?;
+    let received = vault
+        .amount
+        .checked_sub(balance_before)
+        .ok_or(PerpError::MathOverflow)?;
+    require!(received > 0, PerpError::InvalidAmount);
+    Ok(received)
+}
+
+/// Reject collateral mints that could move, freeze or lock funds held by the program:
+/// a freeze authority other than `allowed_freeze_authority`, and the Token-2022
+/// permanent delegate, default frozen state, transfer hook, non-transferable, mint
+/// close authority and confidential transfer extensions.
+fn validate_collateral_mint(
+    mint: &InterfaceAccount<Mint>,
+    allowed_freeze_authority: Option<Pubkey>,
+) -> Result<()> {
+    if let Some(freeze_authority) = Option::<Pubkey>::from(mint.freeze_authority) {
+        require!(
+            allowed_freeze_authority == Some(freeze_authority),
+            PerpError::UnsupportedMint
+        );
+    }
+
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner == spl_token_2022::ID {
+        let data = mint_info.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        for extension in state.get_extension_types()? {
+            require!(
+                !matches!(
+                    extension,
+                    ExtensionType::PermanentDelegate
+                        | ExtensionType::DefaultAccountState
+                        | ExtensionType::TransferHook
+                        | ExtensionType::NonTransferable
+                        | ExtensionType::MintCloseAuthority
+                        | ExtensionType::ConfidentialTransferMint
+                ),
+                PerpError::UnsupportedMint
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Credit a deposit of a registered collateral asset, enforcing its deposit cap.
+fn credit_collateral_asset(
+    registry: &mut CollateralRegistry,
+    user_collateral: &mut UserCollateral,
+    user_position: &mut UserPosition,
+    mint: &Pubkey,
+    amount: u64,
+) -> Result<()> {
+    let index = registry.find(mint).ok_or(PerpError::CollateralNotListed)?;
+    let asset = &mut registry.assets[index];
+    let total_deposits = asset.total_deposits.checked_add(amount).ok_or(PerpError::MathOverflow)?;
+    require!(total_deposits <= asset.deposit_cap, PerpError::DepositCapExceeded);
+    asset.total_deposits = total_deposits;
+
+    user_collateral.balances[index] = user_collateral.balances[index]
+        .checked_add(amount)
+        .ok_or(PerpError::MathOverflow)?;
+    user_position.has_collateral_assets = true;
+    Ok(())
+}
+
+/// Debit a withdrawal of a registered collateral asset. The position must stay at
+/// initial margin at `oracle_price` with the remaining assets valued at their initial
+/// weights.
+#[allow(clippy::too_many_arguments)]
+fn debit_collateral_asset(
+    registry: &mut CollateralRegistry,
+    user_collateral: &mut UserCollateral,
+    user_position: &UserPosition,
+    market_state: &MarketState,
+    oracle_price: u64,
+    oracles: &[AccountInfo],
+    mint: &Pubkey,
+    amount: u64,
+) -> Result<()> {
+    let index = registry.find(mint).ok_or(PerpError::CollateralNotListed)?;
+    require!(user_collateral.balances[index] >= amount, PerpError::InsufficientCollateral);
+    user_collateral.balances[index] -= amount;
+    registry.assets[index].total_deposits = registry.assets[index].total_deposits.saturating_sub(amount);
+
+    let asset_value = collateral_asset_value(
+        user_position,
+        Some(registry),
+        Some(user_collateral),
+        oracles,
+        true,
+    )?;
+    let risks = isolated_position_risks(user_position, market_state, oracle_price)?;
+    let equity = user_position.collateral.saturating_add(asset_value).min(i64::MAX as u64) as i64;
+    let (margin_ok, _) = portfolio_margin_health(equity, &risks, true);
+    require!(margin_ok, PerpError::InsufficientMargin);
+    Ok(())
+}
+
+/// Haircut-weighted oracle value (in native quote units) of the user's registered collateral
+/// assets, using initial or maintenance weights. Oracle price feeds are looked up in
+/// `oracles` by the registry's oracle keys. Returns 0 if the accounts are not passed,
+/// which is only allowed while the position has never deposited such assets.
+fn collateral_asset_value(
+    user_position: &UserPosition,
+    registry: Option<&CollateralRegistry>,
+    user_collateral: Option<&UserCollateral>,
+    oracles: &[AccountInfo],
+    use_initial_weight: bool,
+) -> Result<u64> {
+    let (registry, user_collateral) = match (registry, user_collateral) {
+        (Some(registry), Some(user_collateral)) => (registry, user_collateral),
+        _ => {
+            require!(!user_position.has_collateral_assets, PerpError::MissingCollateralAccounts);
+            return Ok(0);
+        }
+    };
+    require_keys_eq!(registry.market, user_position.market, PerpError::InvalidMarket);
+    require_keys_eq!(user_collateral.market, user_position.market, PerpError::InvalidMarket);
+    require_keys_eq!(user_collateral.user, user_position.user, PerpError::Unauthorized);
+
+    let mut total: u128 = 0;
+    let assets = &registry.assets[..registry.asset_count as usize];
+    for (asset, &balance) in assets.iter().zip(user_collateral.balances.iter()) {
+        if balance == 0 {
+            continue;
+        }
+        let oracle = oracles
+            .iter()
+            .find(|acc| acc.key() == asset.oracle)
+            .ok_or(PerpError::MissingRemainingAccount)?;
+        let (price, expo) = get_oracle_price_and_expo(oracle)?;
+        let weight_bps = if use_initial_weight {
+            asset.initial_asset_weight_bps
+        } else {
+            asset.maintenance_asset_weight_bps
+        };
+
+        // balance / 10^asset_decimals whole tokens, each worth price * 10^expo quote,
+        // i.e. price * 10^(quote_decimals + expo) native quote units, times the weight.
+        let scale = registry.quote_decimals as i32 + expo;
+        let mut numerator = (balance as u128)
+            .checked_mul(price as u128)
+            .and_then(|v| v.checked_mul(weight_bps as u128))
+            .ok_or(PerpError::MathOverflow)?;
+        let mut denominator = 10u128
+            .checked_pow(asset.decimals as u32)
+            .and_then(|v| v.checked_mul(BPS_DENOMINATOR as u128))
+            .ok_or(PerpError::MathOverflow)?;
+        let power = 10u128.checked_pow(scale.unsigned_abs()).ok_or(PerpError::MathOverflow)?;
+        if scale >= 0 {
+            numerator = numerator.checked_mul(power).ok_or(PerpError::MathOverflow)?;
+        } else {
+            denominator = denominator.checked_mul(power).ok_or(PerpError::MathOverflow)?;
+        }
+        let value = numerator / denominator;
+        total = total.checked_add(value).ok_or(PerpError::MathOverflow)?;
+    }
+
+    Ok(u64::try_from(total).unwrap_or(u64::MAX))
+}
+
+/// Close a margin account slot at the fill price (with vAMM impact when enabled),
+/// realize its PnL into the account's collateral and release open interest. A loss
+/// beyond the account's collateral is covered from the account's lent balance first,
+/// then borrowed from the lending pool as far as its liquidity allows; any remainder
+/// is written off. The pool is required once the account has lent or borrowed, or
+/// when the loss exceeds its collateral.
+/// Returns (fill price, realized pnl, closed size, was long, amount to move from the
+/// lending vault into the market's fee vault, which holds the counterparty's side of
+/// the loss).
+fn close_margin_slot(
+    margin_account: &mut MarginAccount,
+    slot: usize,
+    market_state: &mut MarketState,
+    lending_pool: Option<&mut LendingPool>,
+    oracle_price: u64,
+) -> Result<(u64, i64, u64, bool, u64)> {
+    let position = margin_account.positions[slot];
+    require!(position.size > 0, PerpError::NoOpenPosition);
+
+    // Closing a long sells base into the vAMM, closing a short buys it back.
+    let fill_price = fill_price_with_impact(market_state, !position.is_long, position.size, oracle_price)?;
+    let direction_multiplier = if position.is_long { 1 } else { -1 };
+    let realized_pnl = (position.size as i64)
+        .checked_mul(fill_price as i64 - position.entry_price as i64)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_mul(direction_multiplier)
+        .ok_or(PerpError::MathOverflow)?;
+
+    let new_collateral = (margin_account.collateral as i64)
+        .checked_add(realized_pnl)
+        .ok_or(PerpError::MathOverflow)?;
+    margin_account.collateral = new_collateral.max(0) as u64;
+    let mut from_lending_pool = 0;
+    if new_collateral < 0 || margin_account.lend_shares > 0 || margin_account.borrow_shares > 0 {
+        let pool = lending_pool.ok_or(PerpError::MissingLendingPool)?;
+        require_keys_eq!(pool.quote_asset_mint, margin_account.quote_asset_mint, PerpError::InvalidMint);
+        if new_collateral < 0 {
+            let mut deficit = new_collateral.unsigned_abs();
+
+            // Lent collateral covers the loss before anything is borrowed.
+            let lent = pool.deposit_value(margin_account.lend_shares)?;
+            let from_lent = deficit.min(lent).min(pool.available_liquidity()?);
+            if from_lent > 0 {
+                let shares = if from_lent == lent {
+                    margin_account.lend_shares
+                } else {
+                    pool.shares_for_deposit(from_lent)?.min(margin_account.lend_shares)
+                };
+                let withdrawn = pool.withdraw(shares)?;
+                margin_account.lend_shares -= shares;
+                deficit = deficit.saturating_sub(withdrawn);
+                from_lending_pool += withdrawn;
+            }
+
+            let borrowed = deficit.min(pool.available_liquidity()?);
+            if borrowed > 0 {
+                margin_account.borrow_shares = margin_account
+                    .borrow_shares
+                    .checked_add(pool.borrow(borrowed)?)
+                    .ok_or(PerpError::MathOverflow)?;
+                from_lending_pool += borrowed;
+            }
+        }
+    }
+    margin_account.positions[slot] = MarginPosition::default();
+    sub_open_interest(market_state, position.is_long, position.size);
+
+    Ok((fill_price, realized_pnl, position.size, position.is_long, from_lending_pool))
+}
+
+/// Accrue the lending pool's indices (if passed) and return it for valuation.
+fn accrue_lending_pool(lending_pool: Option<&mut LendingPool>) -> Result<Option<&mut LendingPool>> {
+    match lending_pool {
+        Some(pool) => {
+            pool.accrue(Clock::get()?.unix_timestamp)?;
+            Ok(Some(pool))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Move `amount` from the quote mint's lending vault into `destination`, signed by the
+/// lending pool.
+fn transfer_from_lending_vault<'info>(
+    lending_pool: &Account<'info, LendingPool>,
+    lending_vault: &InterfaceAccount<'info, TokenAccount>,
+    quote_asset_mint: &InterfaceAccount<'info, Mint>,
+    destination: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    require_keys_eq!(lending_vault.key(), lending_pool.lending_vault, PerpError::InvalidLendingVault);
+    require!(amount <= lending_vault.amount, PerpError::InsufficientLendingLiquidity);
+
+    let seeds = &[b"lending_pool", lending_pool.quote_asset_mint.as_ref(), &[lending_pool.bump]];
+    let signer = &[&seeds[..]];
+    let cpi_accounts = TransferChecked {
+        from: lending_vault.to_account_info(),
+        mint: quote_asset_mint.to_account_info(),
+        to: destination,
+        authority: lending_pool.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program, cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, quote_asset_mint.decimals)
+}
+
+/// Quote collateral of a margin account for margin purposes: idle collateral plus
+/// the value of its lent shares minus its debt. The LendingPool is required once
+/// the account has lent or borrowed.
+fn margin_collateral_value<P: std::ops::Deref<Target = LendingPool>>(
+    margin_account: &MarginAccount,
+    lending_pool: Option<P>,
+) -> Result<i64> {
+    let mut value = margin_account.collateral as i128;
+    if margin_account.lend_shares > 0 || margin_account.borrow_shares > 0 {
+        let pool = lending_pool.ok_or(PerpError::MissingLendingPool)?;
+        require_keys_eq!(pool.quote_asset_mint, margin_account.quote_asset_mint, PerpError::InvalidMint);
+        value += pool.deposit_value(margin_account.lend_shares)? as i128;
+        value -= pool.debt_value(margin_account.borrow_shares)? as i128;
+    }
+    Ok(value.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+}
+
+fn validate_lending_params(params: &LendingParams) -> Result<()> {
+    require!(
+        params.lend_fraction_bps <= BPS_DENOMINATOR
+            && params.optimal_utilization_bps > 0
+            && params.optimal_utilization_bps < BPS_DENOMINATOR
+            && params.base_rate_bps.saturating_add(params.slope1_bps).saturating_add(params.slope2_bps)
+                <= MAX_BORROW_RATE_BPS,
+        PerpError::InvalidLendingParams
+    );
+    Ok(())
+}
+
+/// Margin inputs of one position in a portfolio.
+struct PositionRisk {
+    size: u64,
+    is_long: bool,
+    entry_price: u64,
+    mark_price: u64,
+    // Per-mille of notional, like MarketState::base_margin_ratio_bps
+    maintenance_ratio: u64,
+}
+
+/// Account-level margin: equity is collateral plus the unrealized PnL of every
+/// position, and the requirement is the sum of each position's requirement (initial
+/// = notional / MAX_LEVERAGE, maintenance = the market's margin ratio). Returns
+/// (healthy, net equity).
+fn portfolio_margin_health(collateral: i64, positions: &[PositionRisk], use_initial: bool) -> (bool, i64) {
+    let (net_equity, requirement) = portfolio_margin(collateral, positions, use_initial);
+    (net_equity >= requirement, net_equity)
+}
+
+/// Collateral that can be removed while still meeting initial margin, capped at
+/// `withdrawable_collateral` (assets that are not withdrawn still count as equity).
+fn max_withdrawable(equity_collateral: u64, withdrawable_collateral: u64, positions: &[PositionRisk]) -> u64 {
+    let equity_collateral = equity_collateral.min(i64::MAX as u64) as i64;
+    let (net_equity, requirement) = portfolio_margin(equity_collateral, positions, true);
+    let free = net_equity.saturating_sub(requirement).max(0) as u64;
+    free.min(withdrawable_collateral)
+}
+
+/// (net equity, margin requirement) of a set of positions backed by `collateral`,
+/// which is negative for a margin account whose debt exceeds its assets.
+fn portfolio_margin(collateral: i64, positions: &[PositionRisk], use_initial: bool) -> (i64, i64) {
+    let mut net_equity = collateral as i128;
+    let mut requirement: i128 = 0;
+    for position in positions {
+        let direction = if position.is_long { 1 } else { -1 };
+        net_equity += position.size as i128
+            * (position.mark_price as i128 - position.entry_price as i128)
+            * direction;
+
+        let notional = position.size as i128 * position.mark_price as i128;
+        requirement += if use_initial {
+            notional / MAX_LEVERAGE as i128
+        } else {
+            notional * position.maintenance_ratio as i128 / 1000
+        };
+    }
+
+    let clamp = |value: i128| value.clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+    (clamp(net_equity), clamp(requirement))
+}
+
+/// Risk inputs of an isolated position, valued at the market's margin mark price
+/// (falling back to the entry price before the market has a price).
+fn isolated_position_risks(
+    user_position: &UserPosition,
+    market_state: &MarketState,
+    oracle_price: u64,
+) -> Result<Vec<PositionRisk>> {
+    if user_position.size == 0 {
+        return Ok(Vec::new());
+    }
+    let mark_price = match margin_mark_price(market_state, oracle_price)? {
+        0 => user_position.entry_price,
+        price => price,
+    };
+    Ok(vec![PositionRisk {
+        size: user_position.size,
+        is_long: user_position.is_long,
+        entry_price: user_position.entry_price,
+        mark_price,
+        maintenance_ratio: market_state.base_margin_ratio_bps,
+    }])
+}
+
+/// Price used to value a market's positions for margin: the vAMM mark when enabled,
+/// else the market's oracle price.
+fn margin_mark_price(market_state: &MarketState, oracle_price: u64) -> Result<u64> {
+    if market_state.vamm_enabled {
+        vamm_mark_price(market_state)
+    } else {
+        Ok(oracle_price)
+    }
+}
+
+/// Risk inputs for every open position of a margin account. The market being traded
+/// is passed directly with its oracle price (if any); the MarketState and oracle of
+/// every other open position must be in `markets` (remaining accounts), and the
+/// markets must share the account's quote mint.
+fn margin_account_risks<'info>(
+    margin_account: &MarginAccount,
+    current_market: Option<(&Pubkey, &MarketState, u64)>,
+    markets: &'info [AccountInfo<'info>],
+) -> Result<Vec<PositionRisk>> {
+    let mut risks = Vec::new();
+    for position in margin_account.positions.iter().filter(|p| p.size > 0) {
+        let risk_for = |market_state: &MarketState, oracle_price: u64| -> Result<PositionRisk> {
+            require_keys_eq!(
+                market_state.quote_asset_mint,
+                margin_account.quote_asset_mint,
+                PerpError::InvalidMint
+            );
+            Ok(PositionRisk {
+                size: position.size,
+                is_long: position.is_long,
+                entry_price: position.entry_price,
+                mark_price: margin_mark_price(market_state, oracle_price)?,
+                maintenance_ratio: market_state.base_margin_ratio_bps,
+            })
+        };
+
+        if let Some((_, market_state, oracle_price)) =
+            current_market.filter(|(key, _, _)| **key == position.market)
+        {
+            risks.push(risk_for(market_state, oracle_price)?);
+        } else {
+            let info = markets
+                .iter()
+                .find(|acc| acc.key() == position.market)
+                .ok_or(PerpError::MissingRemainingAccount)?;
+            let market_state: Account<MarketState> = Account::try_from(info)?;
+            let oracle_info = markets
+                .iter()
+                .find(|acc| acc.key() == market_state.oracle)
+                .ok_or(PerpError::MissingRemainingAccount)?;
+            risks.push(risk_for(&market_state, get_oracle_price(oracle_info)?)?);
+        }
+    }
+    Ok(risks)
+}
+
+/// Current vAMM price (no impact), in oracle price units.
+fn vamm_mark_price(market_state: &MarketState) -> Result<u64> {
+    let price = market_state
+        .quote_asset_reserve
+        .checked_mul(market_state.peg_multiplier)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(market_state.base_asset_reserve)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(PEG_PRECISION)
+        .ok_or(PerpError::MathOverflow)?;
+    u64::try_from(price).map_err(|_| error!(PerpError::MathOverflow))
+}
+
+/// Average execution price for a taker trade of `size` base against the vAMM.
+/// Updates the reserves and the vAMM's net position. If the vAMM is disabled,
+/// the trade fills at `fallback_price` with no impact.
+fn fill_price_with_impact(
+    market_state: &mut MarketState,
+    taker_is_long: bool,
+    size: u64,
+    fallback_price: u64,
+) -> Result<u64> {
+    if !market_state.vamm_enabled || size == 0 {
+        return Ok(fallback_price);
+    }
+
+    let k = market_state
+        .sqrt_k
+        .checked_mul(market_state.sqrt_k)
+        .ok_or(PerpError::MathOverflow)?;
+    let base_reserve = market_state.base_asset_reserve;
+    let quote_reserve = market_state.quote_asset_reserve;
+
+    // Taker long => base leaves the pool, quote enters it.
+    // The new quote reserve is rounded up either way: a long taker pays the extra unit
+    // and a short taker receives one unit less, so rounding never favours the taker.
+    let (new_base_reserve, quote_delta) = if taker_is_long {
+        let new_base = base_reserve
+            .checked_sub(size as u128)
+            .filter(|b| *b > 0)
+            .ok_or(PerpError::InsufficientVammLiquidity)?;
+        let new_quote = k.div_ceil(new_base);
+        (new_base, new_quote.checked_sub(quote_reserve).ok_or(PerpError::MathOverflow)?)
+    } else {
+        let new_base = base_reserve
+            .checked_add(size as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        let new_quote = k.div_ceil(new_base);
+        (new_base, quote_reserve.checked_sub(new_quote).ok_or(PerpError::MathOverflow)?)
+    };
+
+    let new_quote_reserve = if taker_is_long {
+        quote_reserve.checked_add(quote_delta)
+    } else {
+        quote_reserve.checked_sub(quote_delta)
+    }
+    .ok_or(PerpError::MathOverflow)?;
+
+    // Long takers' fill price rounds up, short takers' rounds down.
+    let pegged_quote = quote_delta
+        .checked_mul(market_state.peg_multiplier)
+        .ok_or(PerpError::MathOverflow)?;
+    let divisor = PEG_PRECISION
+        .checked_mul(size as u128)
+        .ok_or(PerpError::MathOverflow)?;
+    let fill_price = if taker_is_long {
+        pegged_quote.div_ceil(divisor)
+    } else {
+        pegged_quote / divisor
+    };
+
+    market_state.base_asset_reserve = new_base_reserve;
+    market_state.quote_asset_reserve = new_quote_reserve;
+
+    let signed_size = if taker_is_long { size as i64 } else { -(size as i64) };
+    market_state.vamm_net_base_position = market_state
+        .vamm_net_base_position
+        .checked_add(signed_size)
+        .ok_or(PerpError::MathOverflow)?;
+
+    u64::try_from(fill_price).map_err(|_| error!(PerpError::MathOverflow))
+}
+
+/// Current size (with discriminator) of a migratable account, identified by its discriminator.
+fn current_layout_len(account: &AccountInfo) -> Result<usize> {
+    let data = account.try_borrow_data()?;
+    require!(data.len() >= 8, PerpError::UnknownAccountLayout);
+    let discriminator = &data[..8];
+
+    let size = if discriminator == MarketState::DISCRIMINATOR {
+        // The original layout has no fee vault; it goes through migrate_legacy_market.
+        require!(
+            data.len() != 8 + MarketState::LEGACY_SIZE,
+            PerpError::LegacyMarketLayout
+        );
+        MarketState::MAX_SIZE
+    } else if discriminator == UserPosition::DISCRIMINATOR {
+        UserPosition::MAX_SIZE
+    } else if discriminator == StopOrder::DISCRIMINATOR {
+        StopOrder::MAX_SIZE
+    } else if discriminator == BracketOrder::DISCRIMINATOR {
+        BracketOrder::MAX_SIZE
+    } else if discriminator == TwapOrder::DISCRIMINATOR {
+        TwapOrder::MAX_SIZE
+    } else if discriminator == UserStats::DISCRIMINATOR {
+        UserStats::MAX_SIZE
+    } else if discriminator == MarginAccount::DISCRIMINATOR {
+        MarginAccount::MAX_SIZE
+    } else {
+        return err!(PerpError::UnknownAccountLayout);
+    };
+
+    Ok(8 + size)
+}
+
+/// Realloc a program account to `new_len`, zero-filling the new bytes and topping up
+/// its rent from `payer`.
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    let rent_due = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account.lamports());
+    if rent_due > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, rent_due),
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+    }
+    account.realloc(new_len, true)?;
+    Ok(())
+}
+
+/// Quote value, at the current peg, the vAMM would pay traders to close their net
+/// position against the pool. Negative when traders are net short and would pay in.
+fn vamm_net_position_value(market_state: &MarketState) -> Result<i128> {
+    let net = market_state.vamm_net_base_position;
+    if !market_state.vamm_enabled || net == 0 {
+        return Ok(0);
+    }
+
+    let k = market_state
+        .sqrt_k
+        .checked_mul(market_state.sqrt_k)
+        .ok_or(PerpError::MathOverflow)?;
+    let base_reserve = market_state.base_asset_reserve;
+    let quote_reserve = market_state.quote_asset_reserve;
+
+    let quote_delta = if net > 0 {
+        // Longs sell their base back into the pool.
+        let new_base = base_reserve
+            .checked_add(net as u128)
+            .ok_or(PerpError::MathOverflow)?;
+        let new_quote = k.checked_div(new_base).ok_or(PerpError::MathOverflow)?;
+        i128::try_from(quote_reserve.saturating_sub(new_quote))
+            .map_err(|_| error!(PerpError::MathOverflow))?
+    } else {
+        // Shorts buy their base back out of the pool.
+        let new_base = base_reserve
+            .checked_sub(net.unsigned_abs() as u128)
+            .filter(|b| *b > 0)
+            .ok_or(PerpError::InsufficientVammLiquidity)?;
+        let new_quote = k.checked_div(new_base).ok_or(PerpError::MathOverflow)?;
+        -i128::try_from(new_quote.saturating_sub(quote_reserve))
+            .map_err(|_| error!(PerpError::MathOverflow))?
+    };
+
+    let value = quote_delta
+        .checked_mul(market_state.peg_multiplier as i128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(PEG_PRECISION as i128)
+        .ok_or(PerpError::MathOverflow)?;
+    Ok(value)
+}
+
+/// Book the change in what the vAMM owes traders after a repeg or depth update.
+/// A cost is reserved out of the fee vault's free balance (and fails if the fees
+/// cannot cover it); a gain releases previously reserved cost. Returns the cost.
+fn book_vamm_cost(market_state: &mut MarketState, fee_vault_amount: u64, value_before: i128) -> Result<i64> {
+    let value_after = vamm_net_position_value(market_state)?;
+    let cost = i64::try_from(value_after - value_before).map_err(|_| error!(PerpError::MathOverflow))?;
+
+    if cost > 0 {
+        let available = fee_vault_amount
+            .saturating_sub(market_state.unclaimed_referral_rewards)
+            .saturating_sub(market_state.vamm_cost_reserved);
+        require!(cost as u64 <= available, PerpError::InsufficientFeeVaultBalance);
+        market_state.vamm_cost_reserved = market_state
+            .vamm_cost_reserved
+            .checked_add(cost as u64)
+            .ok_or(PerpError::MathOverflow)?;
+    } else {
+        market_state.vamm_cost_reserved =
+            market_state.vamm_cost_reserved.saturating_sub(cost.unsigned_abs());
+    }
+
+    Ok(cost)
+}
+
+fn emit_order_cancelled(market: Pubkey, is_long: bool, order: &RestingOrder) {
+    emit!(LimitOrderCancelled {
+        user: order.owner,
+        market,
+        order_id: order.order_id,
+        client_order_id: order.client_order_id,
+        is_long,
+        remaining_size: order.size,
+    });
+}
+
+/// Apply a fill of `size` at `price` to a position: increases move the average entry,
+/// opposite-side fills realize PnL into collateral and may flip the position.
+/// Returns the realized PnL.
+fn apply_fill_to_position(
+    user_position: &mut UserPosition,
+    market_state: &mut MarketState,
+    is_long: bool,
+    size: u64,
+    price: u64,
+) -> Result<i64> {
+    if user_position.size == 0 || user_position.is_long == is_long {
+        if user_position.size == 0 {
+            user_position.start_new_position()?;
+        }
+        let total_size = user_position.size.checked_add(size).ok_or(PerpError::MathOverflow)?;
+        let new_entry_price = (user_position.entry_price as u128)
+            .checked_mul(user_position.size as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_add((price as u128).checked_mul(size as u128).ok_or(PerpError::MathOverflow)?)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(total_size as u128)
+            .ok_or(PerpError::MathOverflow)? as u64;
+
+        user_position.is_long = is_long;
+        user_position.entry_price = new_entry_price;
+        user_position.size = total_size;
+        add_open_interest(market_state, is_long, size)?;
+        return Ok(0);
+    }
+
+    // Opposite side: reduce first, then flip with any remainder.
+    let reduce_size = size.min(user_position.size);
+    let direction_multiplier = if user_position.is_long { 1 } else { -1 };
+    let realized_pnl = (reduce_size as i64)
+        .checked_mul(price as i64 - user_position.entry_price as i64)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_mul(direction_multiplier)
+        .ok_or(PerpError::MathOverflow)?;
+    let new_collateral = (user_position.collateral as i64)
+        .checked_add(realized_pnl)
+        .ok_or(PerpError::MathOverflow)?;
+    user_position.collateral = if new_collateral < 0 { 0 } else { new_collateral as u64 };
+
+    sub_open_interest(market_state, user_position.is_long, reduce_size);
+    user_position.size -= reduce_size;
+
+    let flip_size = size - reduce_size;
+    if flip_size > 0 {
+        user_position.start_new_position()?;
+        user_position.is_long = is_long;
+        user_position.entry_price = price;
+        user_position.size = flip_size;
+        add_open_interest(market_state, is_long, flip_size)?;
+    } else if user_position.size == 0 {
+        user_position.entry_price = 0;
+        user_position.is_long = false;
+        user_position.unrealized_pnl = 0;
+    }
+
+    Ok(realized_pnl)
+}
+
+/// Open or extend a position in the same direction: leverage check, fill (with vAMM
+/// impact when enabled), average entry, open interest and the final margin check.
+/// Shared by open_position and TWAP execution. Returns the fill price.
+/// `collateral_asset_value` is added to the quote collateral (0 to ignore other assets).
+fn increase_position(
+    user_position: &mut UserPosition,
+    market_state: &mut MarketState,
+    is_long: bool,
+    size: u64,
+    oracle_price: u64,
+    collateral_asset_value: u64,
+) -> Result<u64> {
+    require!(size > 0, PerpError::InvalidAmount);
+
+    // With the vAMM enabled the fill price includes price impact,
+    // otherwise the position fills at the oracle price.
+    let fill_price = fill_price_with_impact(market_state, is_long, size, oracle_price)?;
+
+    // A basic approach assumes max_leverage = 10.
+    // Then user_position.collateral * 10 >= size * fill_price.
+    let max_leverage = MAX_LEVERAGE;
+    let cost = size.checked_mul(fill_price).ok_or(PerpError::MathOverflow)?;
+    let max_allowed = user_position
+        .collateral
+        .saturating_add(collateral_asset_value)
+        .checked_mul(max_leverage)
+        .ok_or(PerpError::MathOverflow)?;
+    require!(cost <= max_allowed, PerpError::InsufficientMargin);
+
+    if user_position.size == 0 {
+        user_position.start_new_position()?;
+        user_position.is_long = is_long;
+        user_position.entry_price = fill_price;
+        user_position.size = size;
+    } else {
+        require!(user_position.is_long == is_long, PerpError::OppositePositionNotSupported);
+        let old_size = user_position.size;
+        let old_entry_price = user_position.entry_price;
+        let total_size = old_size.checked_add(size).ok_or(PerpError::MathOverflow)?;
+        let new_entry_price = (old_entry_price as u128)
+            .checked_mul(old_size as u128)
+            .ok_or(PerpError::MathOverflow)?
+            .checked_add(
+                (fill_price as u128)
+                    .checked_mul(size as u128)
+                    .ok_or(PerpError::MathOverflow)?,
+            )
+            .ok_or(PerpError::MathOverflow)?
+            .checked_div(total_size as u128)
+            .ok_or(PerpError::MathOverflow)? as u64;
+
+        user_position.entry_price = new_entry_price;
+        user_position.size = total_size;
+    }
+
+    // Update OI(open interest)
+    if is_long {
+        market_state.open_interest_long = market_state
+            .open_interest_long
+            .checked_add(size)
+            .ok_or(PerpError::MathOverflow)?;
+    } else {
+        market_state.open_interest_short = market_state
+            .open_interest_short
+            .checked_add(size)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+
+    // Final margin check: initial margin on the whole position at the mark price
+    let risks = isolated_position_risks(user_position, market_state, oracle_price)?;
+    let equity = user_position.collateral.saturating_add(collateral_asset_value).min(i64::MAX as u64) as i64;
+    let (margin_ok, _) = portfolio_margin_health(equity, &risks, true);
+    require!(margin_ok, PerpError::InsufficientMargin);
+
+    Ok(fill_price)
+}
+
+/// Reduce a position by `size` (capped at the current size) at `oracle_price`, with
+/// vAMM price impact when enabled. Returns the closed size, the realized PnL and the
+/// exit price.
+fn reduce_position(
+    user_position: &mut UserPosition,
+    market_state: &mut MarketState,
+    size: u64,
+    oracle_price: u64,
+) -> Result<(u64, i64, u64)> {
+    let close_size = size.min(user_position.size);
+    require!(close_size > 0, PerpError::NoOpenPosition);
+
+    let closing_side = !user_position.is_long;
+    let exit_price = fill_price_with_impact(market_state, closing_side, close_size, oracle_price)?;
+    let realized_pnl = apply_fill_to_position(user_position, market_state, closing_side, close_size, exit_price)?;
+
+    Ok((close_size, realized_pnl, exit_price))
+}
+
+fn add_open_interest(market_state: &mut MarketState, is_long: bool, size: u64) -> Result<()> {
+    if is_long {
+        market_state.open_interest_long = market_state
+            .open_interest_long
+            .checked_add(size)
+            .ok_or(PerpError::MathOverflow)?;
+    } else {
+        market_state.open_interest_short = market_state
+            .open_interest_short
+            .checked_add(size)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+    Ok(())
+}
+
+fn sub_open_interest(market_state: &mut MarketState, is_long: bool, size: u64) {
+    if is_long {
+        market_state.open_interest_long = market_state
+            .open_interest_long
+            .checked_sub(size)
+            .unwrap_or_default();
+    } else {
+        market_state.open_interest_short = market_state
+            .open_interest_short
+            .checked_sub(size)
+            .unwrap_or_default();
+    }
+}
+
+/// A long bracket needs stop_loss < current price < take_profit, a short the reverse.
+/// A leg priced at 0 is unset, but at least one leg must be set.
+fn validate_bracket_prices(
+    is_long: bool,
+    stop_loss_price: u64,
+    take_profit_price: u64,
+    current_price: u64,
+) -> Result<()> {
+    require!(stop_loss_price != 0 || take_profit_price != 0, PerpError::InvalidTriggerPrice);
+
+    let stop_loss_ok = stop_loss_price == 0
+        || if is_long { stop_loss_price < current_price } else { stop_loss_price > current_price };
+    let take_profit_ok = take_profit_price == 0
+        || if is_long { take_profit_price > current_price } else { take_profit_price < current_price };
+    require!(stop_loss_ok && take_profit_ok, PerpError::InvalidTriggerPrice);
+    Ok(())
+}
+
+/// Checks shared by orders that wait for a trigger or a crank instead of matching the
+/// book (stops, brackets, trailing stops and TWAP). They rest until executed, so only
+/// good-till-cancel and good-till-time apply. Returns the expiry to store (0 => none).
+fn validate_conditional_params(params: &OrderParams, now: i64) -> Result<i64> {
+    require!(params.size > 0, PerpError::InvalidAmount);
+    match params.time_in_force {
+        TimeInForce::GoodTillCancel => Ok(0),
+        TimeInForce::GoodTillTime => {
+            require!(params.expiry_ts > now, PerpError::OrderExpired);
+            Ok(params.expiry_ts)
+        }
+        _ => err!(PerpError::UnsupportedTimeInForce),
+    }
+}
+
+/// Close each stop, bracket or TWAP order in `orders`, or only those with
+/// `client_order_id` when given, returning the rent to whoever paid it. Every order must
+/// belong to `user` in `market`. Rent payers other than `user` are passed in `orders`
+/// too; accounts not owned by this program are only looked up as rent payers.
+fn cancel_conditional_orders<'info>(
+    user: &AccountInfo<'info>,
+    market: &Pubkey,
+    orders: &'info [AccountInfo<'info>],
+    client_order_id: Option<u64>,
+) -> Result<u32> {
+    let rent_receiver = |payer: Pubkey| -> Result<AccountInfo<'info>> {
+        if payer == user.key() {
+            return Ok(user.clone());
+        }
+        orders
+            .iter()
+            .find(|acc| acc.key() == payer)
+            .cloned()
+            .ok_or_else(|| error!(PerpError::MissingRemainingAccount))
+    };
+
+    let mut cancelled = 0u32;
+    for info in orders.iter().filter(|acc| *acc.owner == crate::ID) {
+        let discriminator: [u8; 8] = info
+            .try_borrow_data()?
+            .get(..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(PerpError::NotConditionalOrder)?;
+
+        if discriminator == StopOrder::DISCRIMINATOR {
+            let order = Account::<StopOrder>::try_from(info)?;
+            require_keys_eq!(order.user, user.key(), PerpError::Unauthorized);
+            require_keys_eq!(order.market, *market, PerpError::InvalidMarket);
+            if client_order_id.is_some_and(|id| id != order.client_order_id) {
+                continue;
+            }
+            emit!(StopOrderCancelled {
+                user: order.user,
+                market: order.market,
+                client_order_id: order.client_order_id,
+            });
+            let receiver = rent_receiver(order.rent_payer())?;
+            order.close(receiver)?;
+        } else if discriminator == BracketOrder::DISCRIMINATOR {
+            let order = Account::<BracketOrder>::try_from(info)?;
+            require_keys_eq!(order.user, user.key(), PerpError::Unauthorized);
+            require_keys_eq!(order.market, *market, PerpError::InvalidMarket);
+            if client_order_id.is_some_and(|id| id != order.client_order_id) {
+                continue;
+            }
+            emit!(BracketOrderCancelled {
+                user: order.user,
+                market: order.market,
+                client_order_id: order.client_order_id,
+            });
+            let receiver = rent_receiver(order.rent_payer())?;
+            order.close(receiver)?;
+        } else if discriminator == TwapOrder::DISCRIMINATOR {
+            let order = Account::<TwapOrder>::try_from(info)?;
+            require_keys_eq!(order.user, user.key(), PerpError::Unauthorized);
+            require_keys_eq!(order.market, *market, PerpError::InvalidMarket);
+            if client_order_id.is_some_and(|id| id != order.client_order_id) {
+                continue;
+            }
+            emit!(TwapOrderCancelled {
+                user: order.user,
+                market: order.market,
+                executed_size: order.executed_size,
+                client_order_id: order.client_order_id,
+            });
+            let receiver = rent_receiver(order.rent_payer())?;
+            order.close(receiver)?;
+        } else {
+            return err!(PerpError::NotConditionalOrder);
+        }
+        cancelled += 1;
+    }
+    Ok(cancelled)
+}
+
+/// A conditional order cannot execute at or after its expiry (0 => no expiry).
+fn check_order_expiry(expiry_ts: i64, now: i64) -> Result<()> {
+    require!(expiry_ts == 0 || now < expiry_ts, PerpError::OrderExpired);
+    Ok(())
+}
+
+/// A buy (`is_long`) may not fill above `limit_price`, a sell not below it (0 => no limit).
+fn check_limit_price(is_long: bool, fill_price: u64, limit_price: u64) -> Result<()> {
+    if limit_price != 0 {
+        let within_limit = if is_long {
+            fill_price <= limit_price
+        } else {
+            fill_price >= limit_price
+        };
+        require!(within_limit, PerpError::PriceLimitExceeded);
+    }
+    Ok(())
+}
+
+/// Accounts needed to move quote tokens out of a user's vault, signed by the vault
+/// authority PDA `[b"vault_authority", user, scope]`. The scope is the market for
+/// isolated positions and the margin account for cross margin.
+struct UserVaultSigner<'info> {
+    token_program: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    decimals: u8,
+    user_vault: AccountInfo<'info>,
+    user_vault_authority: AccountInfo<'info>,
+    vault_authority_bump: u8,
+}
+
+impl<'info> UserVaultSigner<'info> {
+    fn transfer(
+        &self,
+        user: &Pubkey,
+        market: &Pubkey,
+        destination: AccountInfo<'info>,
+        amount: u64,
+    ) -> Result<()> {
+        let seeds = &[
+            b"vault_authority",
+            user.as_ref(),
+            market.as_ref(),
+            &[self.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: self.user_vault.clone(),
+            mint: self.mint.clone(),
+            to: destination,
+            authority: self.user_vault_authority.clone(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(self.token_program.clone(), cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, self.decimals)
+    }
+
+    /// Close a token account owned by the vault authority, sending its lamports
+    /// (including unwrapped SOL) to `destination`.
+    fn close_account(
+        &self,
+        user: &Pubkey,
+        market: &Pubkey,
+        account: AccountInfo<'info>,
+        destination: AccountInfo<'info>,
+    ) -> Result<()> {
+        let seeds = &[
+            b"vault_authority",
+            user.as_ref(),
+            market.as_ref(),
+            &[self.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = CloseAccount {
+            account,
+            destination,
+            authority: self.user_vault_authority.clone(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(self.token_program.clone(), cpi_accounts, signer);
+        token_interface::close_account(cpi_ctx)
+    }
+}
+
+/// Collateral that trading fees are charged against: an isolated UserPosition or a
+/// cross-margin MarginAccount trading one of its markets.
+trait FeePayer {
+    fn user(&self) -> Pubkey;
+    fn market(&self) -> Pubkey;
+    /// Second seed of the vault holding the collateral (`[b"user_vault", user, scope]`).
+    fn vault_scope(&self) -> Pubkey;
+    fn collateral_mut(&mut self) -> &mut u64;
+}
+
+impl FeePayer for UserPosition {
+    fn user(&self) -> Pubkey {
+        self.user
+    }
+    fn market(&self) -> Pubkey {
+        self.market
+    }
+    fn vault_scope(&self) -> Pubkey {
+        self.market
+    }
+    fn collateral_mut(&mut self) -> &mut u64 {
+        &mut self.collateral
+    }
+}
+
+impl<T> FeePayer for Account<'_, T>
+where
+    T: FeePayer + AccountSerialize + AccountDeserialize + Owner + Clone,
+{
+    fn user(&self) -> Pubkey {
+        (**self).user()
+    }
+    fn market(&self) -> Pubkey {
+        (**self).market()
+    }
+    fn vault_scope(&self) -> Pubkey {
+        (**self).vault_scope()
+    }
+    fn collateral_mut(&mut self) -> &mut u64 {
+        (**self).collateral_mut()
+    }
+}
+
+/// A margin account paying fees for a trade in `market`. Its vault is scoped by the
+/// margin account itself.
+struct CrossFeePayer<'a> {
+    margin_account: &'a mut MarginAccount,
+    margin_account_key: Pubkey,
+    market: Pubkey,
+}
+
+impl FeePayer for CrossFeePayer<'_> {
+    fn user(&self) -> Pubkey {
+        self.margin_account.owner
+    }
+    fn market(&self) -> Pubkey {
+        self.market
+    }
+    fn vault_scope(&self) -> Pubkey {
+        self.margin_account_key
+    }
+    fn collateral_mut(&mut self) -> &mut u64 {
+        &mut self.margin_account.collateral
+    }
+}
+
+/// Pay the market's keeper fee out of the position's collateral (capped at what is left).
+fn pay_keeper_fee<'info>(
+    user_position: &mut UserPosition,
+    market_state: &MarketState,
+    vault: &UserVaultSigner<'info>,
+    keeper_token_account: AccountInfo<'info>,
+    keeper: Pubkey,
+) -> Result<()> {
+    let fee = market_state.keeper_fee.min(user_position.collateral);
+    if fee == 0 {
+        return Ok(());
+    }
+
+    user_position.collateral -= fee;
+    vault.transfer(&user_position.user, &user_position.market, keeper_token_account, fee)?;
+
+    emit!(KeeperRewardPaid {
+        keeper,
+        user: user_position.user,
+        market: user_position.market,
+        amount: fee,
+    });
+
+    Ok(())
+}
+
+fn notional(size: u64, price: u64) -> Result<u128> {
+    (size as u128)
+        .checked_mul(price as u128)
+        .ok_or_else(|| error!(PerpError::MathOverflow))
+}
+
+/// Charge the taker or maker fee of the user's volume tier on `notional` from the
+/// position's collateral (capped at what is left) and move it from the user vault into
+/// the fee vault. The traded notional is then added to the user's 30-day volume.
+/// A referred user must pass the referrer's rewards account for the market: the referee
+/// gets a discount and the referrer is credited a share of the fee.
+///
+/// A negative maker fee is a rebate: nothing is charged, and the rebate owed is returned
+/// as a negative amount for the caller to pay with `pay_maker_rebate`.
+#[allow(clippy::too_many_arguments)]
+fn charge_trading_fee<'info>(
+    payer: &mut impl FeePayer,
+    user_stats: &mut UserStats,
+    referrer_rewards: Option<&mut ReferrerRewards>,
+    market_state: &mut MarketState,
+    vault: &UserVaultSigner<'info>,
+    fee_vault: AccountInfo<'info>,
+    notional: u128,
+    is_maker: bool,
+) -> Result<i64> {
+    if user_stats.referrer != Pubkey::default() {
+        let rewards = referrer_rewards.as_ref().ok_or(PerpError::MissingReferrerRewards)?;
+        require!(
+            rewards.referrer == user_stats.referrer && rewards.market == payer.market(),
+            PerpError::InvalidReferrer
+        );
+    } else {
+        require!(referrer_rewards.is_none(), PerpError::InvalidReferrer);
+    }
+
+    let now = Clock::get()?.unix_timestamp;
+    user_stats.decay_volume(now);
+    let (fee_bps, fee_tier) = market_state.fee_for_volume(user_stats.total_volume_30d(), is_maker);
+    user_stats.record_volume(u64::try_from(notional).unwrap_or(u64::MAX), is_maker);
+
+    let fee = notional
+        .checked_mul(fee_bps.unsigned_abs() as u128)
+        .ok_or(PerpError::MathOverflow)?
+        .checked_div(BPS_DENOMINATOR as u128)
+        .ok_or(PerpError::MathOverflow)?;
+    let mut fee = u64::try_from(fee).unwrap_or(u64::MAX);
+
+    if fee_bps < 0 {
+        let rebate = i64::try_from(fee).map_err(|_| error!(PerpError::MathOverflow))?;
+        return Ok(-rebate);
+    }
+
+    if referrer_rewards.is_some() {
+        fee -= bps_of(fee, market_state.referee_discount_bps);
+    }
+
+    let fee = fee.min(*payer.collateral_mut());
+    if fee == 0 {
+        return Ok(0);
+    }
+    let fee_i64 = i64::try_from(fee).map_err(|_| error!(PerpError::MathOverflow))?;
+
+    *payer.collateral_mut() -= fee;
+    vault.transfer(&payer.user(), &payer.vault_scope(), fee_vault, fee)?;
+
+    let mut referrer_reward = 0;
+    if let Some(rewards) = referrer_rewards {
+        referrer_reward = bps_of(fee, market_state.referrer_fee_share_bps);
+        rewards.claimable = rewards.claimable.checked_add(referrer_reward).ok_or(PerpError::MathOverflow)?;
+        rewards.total_earned = rewards.total_earned.saturating_add(referrer_reward);
+        market_state.unclaimed_referral_rewards = market_state
+            .unclaimed_referral_rewards
+            .checked_add(referrer_reward)
+            .ok_or(PerpError::MathOverflow)?;
+    }
+
+    emit!(FeeCharged {
+        user: payer.user(),
+        market: payer.market(),
+        amount: fee,
+        fee_bps,
+        fee_tier,
+        is_maker,
+        referrer_reward,
+    });
+
+    Ok(fee_i64)
+}
+
+/// Pay a maker rebate from the fee vault into the maker's user vault, capped at the
+/// fee vault balance not reserved for referral rewards or vAMM costs. Returns the
+/// amount paid.
+#[allow(clippy::too_many_arguments)]
+fn pay_maker_rebate<'info>(
+    user_position: &mut UserPosition,
+    market_state: &MarketState,
+    token_program: AccountInfo<'info>,
+    quote_asset_mint: &InterfaceAccount<'info, Mint>,
+    fee_vault: &mut InterfaceAccount<'info, TokenAccount>,
+    user_vault: AccountInfo<'info>,
+    market_authority: AccountInfo<'info>,
+    rebate: u64,
+) -> Result<u64> {
+    // Taker fees from the same fill were transferred in earlier in this instruction.
+    fee_vault.reload()?;
+    let available = fee_vault
+        .amount
+        .saturating_sub(market_state.unclaimed_referral_rewards)
+        .saturating_sub(market_state.vamm_cost_reserved);
+    let rebate = rebate.min(available);
+    if rebate == 0 {
+        return Ok(0);
+    }
+
+    transfer_from_market_vault(
+        token_program,
+        quote_asset_mint,
+        fee_vault.to_account_info(),
+        user_vault,
+        market_authority,
+        &user_position.market,
+        market_state.market_authority_bump,
+        rebate,
+    )?;
+    user_position.collateral = user_position
+        .collateral
+        .checked_add(rebate)
+        .ok_or(PerpError::MathOverflow)?;
+
+    emit!(MakerRebatePaid {
+        user: user_position.user,
+        market: user_position.market,
+        amount: rebate,
+    });
+
+    Ok(rebate)
+}
+
+fn validate_liquidity_mining_params(epoch_duration: i64, band_bps: u64) -> Result<()> {
+    require!(
+        epoch_duration > 0 && band_bps <= BPS_DENOMINATOR,
+        PerpError::InvalidLiquidityMiningParams
+    );
+    Ok(())
+}
+
+/// Fee bounds shared by the base fees and every fee tier.
+fn validate_fee_pair(taker_fee_bps: u64, maker_fee_bps: i64) -> Result<()> {
+    require!(taker_fee_bps <= MAX_FEE_BPS, PerpError::InvalidFee);
+    require!(maker_fee_bps <= MAX_FEE_BPS as i64, PerpError::InvalidFee);
+    // Rebates are funded by taker fees
+    require!(
+        maker_fee_bps >= 0 || maker_fee_bps.unsigned_abs() <= taker_fee_bps,
+        PerpError::InvalidFee
+    );
+    Ok(())
+}
+
+/// `bps` of `amount`, rounded down. `bps` is at most BPS_DENOMINATOR.
+fn bps_of(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+/// Transfer `amount` out of a vault owned by the market authority PDA (the fee vault
+/// or the liquidity mining reward vault).
+#[allow(clippy::too_many_arguments)]
+fn transfer_from_market_vault<'info>(
+    token_program: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    source: AccountInfo<'info>,
+    destination: AccountInfo<'info>,
+    market_authority: AccountInfo<'info>,
+    market: &Pubkey,
+    market_authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let seeds = &[b"market_authority", market.as_ref(), &[market_authority_bump]];
+    let signer = &[&seeds[..]];
+
+    let cpi_accounts = TransferChecked {
+        from: source,
+        mint: mint.to_account_info(),
+        to: destination,
+        authority: market_authority,
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program, cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+fn handle_auto_deleveraging(market_state: &mut MarketState) -> Result<()> {
+    msg!("Auto-deleverage check: placeholder. In production, forcibly reduce large winning positions.");
+    Ok(())
+}
+
+/// Oracle price fetch placeholder.
+fn get_oracle_price(oracle_account: &AccountInfo) -> Result<u64> {
+    get_oracle_price_and_expo(oracle_account).map(|(price, _)| price)
+}
+
+/// Oracle price together with its Pyth exponent: the value is `price * 10^expo`.
+fn get_oracle_price_and_expo(oracle_account: &AccountInfo) -> Result<(u64, i32)> {
+    // Updated to use pyth-sdk-solana v0.8.0
+    let clock_ts_i64 = Clock::get()?.unix_timestamp;
+    // Convert i64 -> u64 safely (returning error on negative)
+    let clock_ts_u64 = u64::try_from(clock_ts_i64).map_err(|_| error!(PerpError::MathOverflow))?;
+
+    let price_feed = load_price_feed_from_account_info(oracle_account)
+        .map_err(|_| error!(PerpError::InvalidAmount))?;
+
+    //  allow up to 60 seconds of staleness, for example.
+    let max_staleness = 60;
+    let price_data = price_feed
+        .get_price_no_older_than(max_staleness, clock_ts_u64)
+        .ok_or(PerpError::InvalidAmount)?;
+
+    // If price is negative, consider it invalid.
+    if price_data.price < 0 {
+        return Err(error!(PerpError::MathOverflow));
+    }
+
+    Ok((price_data.price as u64, price_data.expo))
+}
+
+
+
+// =======================================
+// CONTEXTS & ACCOUNTS
+// =======================================
+
+#[derive(Accounts)]
+#[instruction(initial_funding_rate: i64, base_asset_symbol: String, quote_freeze_authority: Option<Pubkey>)]
+pub struct InitializeMarket<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(init, payer = authority, space = 8 + MarketState::MAX_SIZE)]
+    pub market_state: Account<'info, MarketState>,
+
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the market's fee vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = quote_asset_mint,
+        token::authority = market_authority,
+        seeds = [b"fee_vault", market_state.key().as_ref()],
+        bump
+    )]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Placeholder vault for insurance fund
+    #[account(init, payer = authority, space = 8 + 165)]
+    pub insurance_vault: AccountInfo<'info>,
+
+    /// CHECK: Pyth price feed for the base asset, stored as the market's oracle
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
+    )]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserPosition::MAX_SIZE,
+        seeds = [
+            b"user_position",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStats::MAX_SIZE,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    #[account(mut)]
+    pub user_collateral_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = quote_asset_mint,
+        token::authority = user_vault_authority,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(beneficiary: Pubkey)]
+pub struct DepositFor<'info> {
+    #[account(mut)]
+    pub depositor: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
+    )]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        space = 8 + UserPosition::MAX_SIZE,
+        seeds = [
+            b"user_position",
+            beneficiary.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        space = 8 + UserStats::MAX_SIZE,
+        seeds = [b"user_stats", beneficiary.as_ref()],
+        bump
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    #[account(mut)]
+    pub depositor_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        token::mint = quote_asset_mint,
+        token::authority = user_vault_authority,
+        seeds = [
+            b"user_vault",
+            beneficiary.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the beneficiary's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            beneficiary.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(session_signer: Pubkey)]
+pub struct RegisterSessionKey<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + SessionKey::MAX_SIZE,
+        seeds = [b"session_key", owner.key().as_ref(), session_signer.as_ref()],
+        bump
+    )]
+    pub session_key: Account<'info, SessionKey>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevokeSessionKey<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"session_key", owner.key().as_ref(), session_key.signer.as_ref()],
+        bump = session_key.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub session_key: Account<'info, SessionKey>,
+}
+
+#[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+        constraint = user_stats.authority == user.key() @ PerpError::Unauthorized,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCollateral<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
+    )]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user_position.user.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user_position.user.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == quote_asset_mint.key() @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_collateral_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub collateral_registry: Option<Account<'info, CollateralRegistry>>,
+
+    pub user_collateral: Option<Account<'info, UserCollateral>>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(scope: Pubkey)]
+pub struct MigrateVaultAuthority<'info> {
+    /// CHECK: owner of the vault being migrated; only used as a seed
+    pub user: AccountInfo<'info>,
+
+    /// CHECK: legacy authority, which is also the address of the user's quote vault
+    #[account(
+        seeds = [b"user_vault", user.key().as_ref(), scope.as_ref()],
+        bump
+    )]
+    pub legacy_vault_authority: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the user's vaults for `scope`
+    #[account(
+        seeds = [b"vault_authority", user.key().as_ref(), scope.as_ref()],
+        bump
+    )]
+    pub vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        constraint = vault.owner == legacy_vault_authority.key() @ PerpError::VaultAlreadyMigrated
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ReallocAccount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: program account identified by its discriminator in realloc_account
+    #[account(mut, owner = crate::ID)]
+    pub account: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateLegacyMarket<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: legacy-layout MarketState; authority and mint are checked after the realloc
+    #[account(mut, owner = crate::ID)]
+    pub market_state: UncheckedAccount<'info>,
+
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the market's fee vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = quote_asset_mint,
+        token::authority = market_authority,
+        seeds = [b"fee_vault", market_state.key().as_ref()],
+        bump
+    )]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: Pyth price feed for the base asset, stored as the market's oracle
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            Some(&user_stats),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_OPEN_POSITION,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    pub collateral_registry: Option<Account<'info, CollateralRegistry>>,
+
+    pub user_collateral: Option<Account<'info, UserCollateral>>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            Some(&user_stats),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_CLOSE_POSITION,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidatePosition<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    pub collateral_registry: Option<Account<'info, CollateralRegistry>>,
+
+    pub user_collateral: Option<Account<'info, UserCollateral>>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user_position.user.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user_position.user.as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user_position.user.as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFundingRate<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFunding<'info> {
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut)]
+    pub user_position: Account<'info, UserPosition>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceBracketOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_CONDITIONAL,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + BracketOrder::MAX_SIZE,
+        seeds = [
+            b"bracket_order",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            &user_position.next_order_seq.to_le_bytes()
+        ],
+        bump
+    )]
+    pub bracket_order: Account<'info, BracketOrder>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ModifyBracketOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_CONDITIONAL,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = bracket_order.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub bracket_order: Account<'info, BracketOrder>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelBracketOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &bracket_order.market,
+            SESSION_CANCEL_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(mut, close = rent_receiver, has_one = user @ PerpError::Unauthorized)]
+    pub bracket_order: Account<'info, BracketOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = bracket_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TriggerBracketOrder<'info> {
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        close = rent_receiver,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = bracket_order.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub bracket_order: Account<'info, BracketOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = bracket_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: Position owner and rent recipient, validated through has_one on the position and the order.
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    /// Anyone can execute triggers.
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub keeper_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUpdateMarket<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUpdateVamm<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority @ PerpError::Unauthorized, has_one = fee_vault @ PerpError::InvalidFeeVault)]
+    pub market_state: Account<'info, MarketState>,
+
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeOrderBook<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(zero)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    #[account(zero)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceLimitOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(
+        has_one = order_book @ PerpError::InvalidMarket,
+        has_one = event_queue @ PerpError::InvalidMarket,
+    )]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    #[account(mut)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_CANCEL_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(has_one = order_book @ PerpError::InvalidMarket)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(mut)]
+    pub order_book: AccountLoader<'info, OrderBook>,
+}
+
+#[derive(Accounts)]
+pub struct ConsumeEvents<'info> {
+    #[account(mut, has_one = event_queue @ PerpError::InvalidMarket)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+
+    /// CHECK: PDA that owns the market's fee vault, signs maker rebates
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump = market_state.market_authority_bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceStopOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_CONDITIONAL,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + StopOrder::MAX_SIZE,
+        seeds = [
+            b"stop_order",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            &user_position.next_order_seq.to_le_bytes()
+        ],
+        bump
+    )]
+    pub stop_order: Account<'info, StopOrder>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceTrailingStopOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_CONDITIONAL,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + StopOrder::MAX_SIZE,
+        seeds = [
+            b"stop_order",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            &user_position.next_order_seq.to_le_bytes()
+        ],
+        bump
+    )]
+    pub stop_order: Account<'info, StopOrder>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTrailingStop<'info> {
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        constraint = stop_order.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub stop_order: Account<'info, StopOrder>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceTwapOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_PLACE_CONDITIONAL,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + TwapOrder::MAX_SIZE,
+        seeds = [
+            b"twap_order",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            &user_position.next_order_seq.to_le_bytes()
+        ],
+        bump
+    )]
+    pub twap_order: Account<'info, TwapOrder>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTwapSlice<'info> {
+    /// CHECK: Order owner, validated through has_one.
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = twap_order.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub twap_order: Account<'info, TwapOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = twap_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// Anyone can execute slices.
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub keeper_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTwapOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &twap_order.market,
+            SESSION_CANCEL_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(mut, close = rent_receiver, has_one = user @ PerpError::Unauthorized)]
+    pub twap_order: Account<'info, TwapOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = twap_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelConditionalOrders<'info> {
+    /// CHECK: position owner and rent recipient; `authority` must be the owner or their delegate
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &market_state.key(),
+            SESSION_CANCEL_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    pub market_state: Account<'info, MarketState>,
+}
+
+#[derive(Accounts)]
+pub struct TriggerStopOrder<'info> {
+    /// CHECK: Position owner and rent recipient, validated through has_one.
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = user_position.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        close = rent_receiver,
+        has_one = user @ PerpError::Unauthorized,
+        constraint = stop_order.market == market_state.key() @ PerpError::InvalidMarket,
+    )]
+    pub stop_order: Account<'info, StopOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = stop_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// Anyone can execute triggers.
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = keeper_token_account.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub keeper_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump,
+        constraint = user_vault.mint == market_state.quote_asset_mint @ PerpError::InvalidMint,
+        constraint = user_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub user_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelStopOrder<'info> {
+    /// CHECK: position owner; `authority` must be the owner or their delegate
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        constraint = is_trading_authority(
+            &user.key(),
+            &authority.key(),
+            user_stats.as_deref(),
+            session_key.as_deref(),
+            &stop_order.market,
+            SESSION_CANCEL_ORDER,
+        ) @ PerpError::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", user.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    /// Owner's UserStats; required when a delegate signs
+    #[account(seeds = [b"user_stats", user.key().as_ref()], bump = user_stats.bump)]
+    pub user_stats: Option<Account<'info, UserStats>>,
+
+    #[account(mut, close = rent_receiver, has_one = user @ PerpError::Unauthorized)]
+    pub stop_order: Account<'info, StopOrder>,
+
+    /// CHECK: account that paid the order's rent, which gets it back
+    #[account(mut, address = stop_order.rent_payer() @ PerpError::InvalidRentReceiver)]
+    pub rent_receiver: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the market's fee vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump = market_state.market_authority_bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = destination.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", user.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    #[account(
+        seeds = [b"user_stats", referrer_stats.authority.as_ref()],
+        bump = referrer_stats.bump,
+    )]
+    pub referrer_stats: Account<'info, UserStats>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeReferrerRewards<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: referrer the rewards are credited to; only it can claim them
+    pub referrer: UncheckedAccount<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + ReferrerRewards::MAX_SIZE,
+        seeds = [b"referrer_rewards", referrer.key().as_ref(), market_state.key().as_ref()],
+        bump
+    )]
+    pub referrer_rewards: Account<'info, ReferrerRewards>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    pub referrer: Signer<'info>,
+
+    #[account(mut)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"referrer_rewards", referrer.key().as_ref(), market_state.key().as_ref()],
+        bump = referrer_rewards.bump,
+    )]
+    pub referrer_rewards: Account<'info, ReferrerRewards>,
+
+    /// CHECK: PDA that owns the market's fee vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump = market_state.market_authority_bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = destination.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeLiquidityMining<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
+    )]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the reward vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump = market_state.market_authority_bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + LiquidityMiningConfig::MAX_SIZE,
+        seeds = [b"liquidity_mining", market_state.key().as_ref()],
+        bump
+    )]
+    pub liquidity_mining: Account<'info, LiquidityMiningConfig>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = quote_asset_mint,
+        token::authority = market_authority,
+        seeds = [b"lm_reward_vault", market_state.key().as_ref()],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLiquidityMining<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"liquidity_mining", market_state.key().as_ref()],
+        bump = liquidity_mining.bump
+    )]
+    pub liquidity_mining: Account<'info, LiquidityMiningConfig>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeMakerScore<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + MakerScore::MAX_SIZE,
+        seeds = [b"maker_score", market_state.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub maker_score: Account<'info, MakerScore>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SampleLiquidityMining<'info> {
+    #[account(has_one = order_book @ PerpError::InvalidMarket)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"liquidity_mining", market_state.key().as_ref()],
+        bump = liquidity_mining.bump
+    )]
+    pub liquidity_mining: Account<'info, LiquidityMiningConfig>,
+
+    pub order_book: AccountLoader<'info, OrderBook>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimLiquidityMiningRewards<'info> {
+    pub owner: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"liquidity_mining", market_state.key().as_ref()],
+        bump = liquidity_mining.bump
+    )]
+    pub liquidity_mining: Account<'info, LiquidityMiningConfig>,
+
+    #[account(
+        mut,
+        seeds = [b"maker_score", market_state.key().as_ref(), owner.key().as_ref()],
+        bump = maker_score.bump
+    )]
+    pub maker_score: Account<'info, MakerScore>,
+
+    /// CHECK: PDA that owns the reward vault
+    #[account(
+        seeds = [b"market_authority", market_state.key().as_ref()],
+        bump = market_state.market_authority_bump
+    )]
+    pub market_authority: AccountInfo<'info>,
+
+    #[account(mut, address = liquidity_mining.reward_vault)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = destination.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub destination: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeCollateralRegistry<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + CollateralRegistry::MAX_SIZE,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureCollateralAsset<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump = collateral_registry.bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: price feed for `mint`, read with get_oracle_price
+    pub oracle_price_feed_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCollateralAsset<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump = collateral_registry.bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"user_position", user.key().as_ref(), market_state.key().as_ref()],
+        bump,
+        has_one = user @ PerpError::Unauthorized,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserCollateral::MAX_SIZE,
+        seeds = [b"user_collateral", user.key().as_ref(), market_state.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+
+    #[account(mut, constraint = user_token_account.mint == mint.key() @ PerpError::InvalidMint)]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = mint,
+        token::authority = user_vault_authority,
+        seeds = [
+            b"collateral_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCollateralAsset<'info> {
+    pub user: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump = collateral_registry.bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    pub mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"user_position", user.key().as_ref(), market_state.key().as_ref()],
+        bump,
+        has_one = user @ PerpError::Unauthorized,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [b"user_collateral", user.key().as_ref(), market_state.key().as_ref()],
+        bump = user_collateral.bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    #[account(mut, constraint = user_token_account.mint == mint.key() @ PerpError::InvalidMint)]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collateral_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            mint.key().as_ref()
+        ],
+        bump,
+        constraint = collateral_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct DepositSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump = collateral_registry.bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    #[account(address = anchor_spl::token::spl_token::native_mint::ID @ PerpError::InvalidMint)]
+    pub native_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"user_position", user.key().as_ref(), market_state.key().as_ref()],
+        bump,
+        has_one = user @ PerpError::Unauthorized,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserCollateral::MAX_SIZE,
+        seeds = [b"user_collateral", user.key().as_ref(), market_state.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        token::mint = native_mint,
+        token::authority = user_vault_authority,
+        seeds = [
+            b"collateral_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            native_mint.key().as_ref()
+        ],
+        bump
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(
+        mut,
+        seeds = [b"collateral_registry", market_state.key().as_ref()],
+        bump = collateral_registry.bump
+    )]
+    pub collateral_registry: Account<'info, CollateralRegistry>,
+
+    #[account(address = anchor_spl::token::spl_token::native_mint::ID @ PerpError::InvalidMint)]
+    pub native_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        seeds = [b"user_position", user.key().as_ref(), market_state.key().as_ref()],
+        bump,
+        has_one = user @ PerpError::Unauthorized,
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        mut,
+        seeds = [b"user_collateral", user.key().as_ref(), market_state.key().as_ref()],
+        bump = user_collateral.bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the user's vaults for this market
+    #[account(
+        seeds = [
+            b"vault_authority",
+            user.key().as_ref(),
+            market_state.key().as_ref()
+        ],
+        bump
+    )]
+    pub user_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"collateral_vault",
+            user.key().as_ref(),
+            market_state.key().as_ref(),
+            native_mint.key().as_ref()
+        ],
+        bump,
+        constraint = collateral_vault.owner == user_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Temporary wSOL account, closed to the user at the end of the instruction
+    #[account(
+        init,
+        payer = user,
+        token::mint = native_mint,
+        token::authority = user_vault_authority,
+        seeds = [b"sol_unwrap", user.key().as_ref(), market_state.key().as_ref()],
+        bump
+    )]
+    pub unwrap_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(subaccount_id: u16)]
+pub struct InitializeMarginAccount<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + MarginAccount::MAX_SIZE,
+        seeds = [b"margin_account", owner.key().as_ref(), &subaccount_id.to_le_bytes()],
+        bump
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    /// CHECK: PDA that owns the margin account's vault
+    #[account(
+        seeds = [b"vault_authority", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        token::mint = quote_asset_mint,
+        token::authority = margin_vault_authority,
+        seeds = [b"user_vault", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
 
 #[derive(Accounts)]
-#[instruction(initial_funding_rate: i64, base_asset_symbol: String, quote_asset_mint: Pubkey)]
-pub struct InitializeMarket<'info> {
+pub struct DepositMargin<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &margin_account.subaccount_id.to_le_bytes()],
+        bump = margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(address = margin_account.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawMargin<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &margin_account.subaccount_id.to_le_bytes()],
+        bump = margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(address = margin_account.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the margin account's vault
+    #[account(
+        seeds = [b"vault_authority", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump,
+        constraint = margin_vault.owner == margin_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub owner_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// Quote mint's LendingPool; required once the account has lent or borrowed
+    #[account(
+        mut,
+        seeds = [b"lending_pool", margin_account.quote_asset_mint.as_ref()],
+        bump = lending_pool.bump,
+    )]
+    pub lending_pool: Option<Account<'info, LendingPool>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct TransferBetweenSubaccounts<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &from_margin_account.subaccount_id.to_le_bytes()],
+        bump = from_margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub from_margin_account: Account<'info, MarginAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &to_margin_account.subaccount_id.to_le_bytes()],
+        bump = to_margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+        constraint = to_margin_account.key() != from_margin_account.key() @ PerpError::InvalidSubaccount,
+        constraint = to_margin_account.quote_asset_mint == from_margin_account.quote_asset_mint @ PerpError::InvalidMint,
+    )]
+    pub to_margin_account: Account<'info, MarginAccount>,
+
+    #[account(address = from_margin_account.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the source subaccount's vault
+    #[account(
+        seeds = [b"vault_authority", owner.key().as_ref(), from_margin_account.key().as_ref()],
+        bump
+    )]
+    pub from_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), from_margin_account.key().as_ref()],
+        bump,
+        constraint = from_vault.owner == from_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub from_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), to_margin_account.key().as_ref()],
+        bump
+    )]
+    pub to_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Quote mint's LendingPool; required once the account has lent or borrowed
+    #[account(
+        mut,
+        seeds = [b"lending_pool", from_margin_account.quote_asset_mint.as_ref()],
+        bump = lending_pool.bump,
+    )]
+    pub lending_pool: Option<Account<'info, LendingPool>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeLendingPool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// A market quoted in the pool's mint; only its authority can create the pool
+    #[account(has_one = authority @ PerpError::Unauthorized)]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + LendingPool::MAX_SIZE,
+        seeds = [b"lending_pool", quote_asset_mint.key().as_ref()],
+        bump
+    )]
+    pub lending_pool: Account<'info, LendingPool>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = quote_asset_mint,
+        token::authority = lending_pool,
+        seeds = [b"lending_vault", quote_asset_mint.key().as_ref()],
+        bump
+    )]
+    pub lending_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetLendingParams<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(mut, has_one = authority @ PerpError::Unauthorized)]
+    pub lending_pool: Account<'info, LendingPool>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLending<'info> {
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &margin_account.subaccount_id.to_le_bytes()],
+        bump = margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"lending_pool", margin_account.quote_asset_mint.as_ref()],
+        bump = lending_pool.bump,
+        has_one = lending_vault @ PerpError::InvalidLendingVault,
+    )]
+    pub lending_pool: Account<'info, LendingPool>,
+
+    #[account(address = margin_account.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA that owns the margin account's vault
+    #[account(
+        seeds = [b"vault_authority", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump,
+        constraint = margin_vault.owner == margin_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
     #[account(mut)]
+    pub lending_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CrossTrade<'info> {
+    /// CHECK: margin account owner; `authority` must be the owner, their delegate or a
+    /// session key, checked by the handler
+    pub owner: UncheckedAccount<'info>,
+
     pub authority: Signer<'info>,
 
-    #[account(init, payer = authority, space = 8 + MarketState::MAX_SIZE)]
-    pub market_state: Account<'info, MarketState>,
+    /// Session key registered by the owner for `authority`, when a session key signs
+    #[account(seeds = [b"session_key", owner.key().as_ref(), authority.key().as_ref()], bump = session_key.bump)]
+    pub session_key: Option<Account<'info, SessionKey>>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", owner.key().as_ref(), &margin_account.subaccount_id.to_le_bytes()],
+        bump = margin_account.bump,
+        has_one = owner @ PerpError::Unauthorized,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(
+        mut,
+        constraint = market_state.quote_asset_mint == margin_account.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the margin account's vault
+    #[account(
+        seeds = [b"vault_authority", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", owner.key().as_ref(), margin_account.key().as_ref()],
+        bump,
+        constraint = margin_vault.owner == margin_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", owner.key().as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Quote mint's LendingPool; required once the account has lent or borrowed, or
+    /// when closing at a loss beyond its collateral
+    #[account(
+        mut,
+        seeds = [b"lending_pool", margin_account.quote_asset_mint.as_ref()],
+        bump = lending_pool.bump,
+    )]
+    pub lending_pool: Option<Account<'info, LendingPool>>,
+
+    /// The pool's lending vault; required with `lending_pool` when closing draws on it
+    #[account(mut)]
+    pub lending_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateCrossPosition<'info> {
+    pub liquidator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"margin_account", margin_account.owner.as_ref(), &margin_account.subaccount_id.to_le_bytes()],
+        bump = margin_account.bump,
+    )]
+    pub margin_account: Account<'info, MarginAccount>,
+
+    #[account(
+        mut,
+        constraint = market_state.quote_asset_mint == margin_account.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub market_state: Account<'info, MarketState>,
+
+    #[account(address = market_state.quote_asset_mint @ PerpError::InvalidMint)]
+    pub quote_asset_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        constraint = liquidator_token_account.mint == market_state.quote_asset_mint @ PerpError::InvalidMint
+    )]
+    pub liquidator_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: price feed, must be the market's oracle
+    #[account(address = market_state.oracle @ PerpError::InvalidOracle)]
+    pub oracle_price_feed_account: AccountInfo<'info>,
+
+    /// CHECK: PDA that owns the margin account's vault
+    #[account(
+        seeds = [b"vault_authority", margin_account.owner.as_ref(), margin_account.key().as_ref()],
+        bump
+    )]
+    pub margin_vault_authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_vault", margin_account.owner.as_ref(), margin_account.key().as_ref()],
+        bump,
+        constraint = margin_vault.owner == margin_vault_authority.key() @ PerpError::VaultNotMigrated
+    )]
+    pub margin_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stats", margin_account.owner.as_ref()],
+        bump = user_stats.bump,
+    )]
+    pub user_stats: Account<'info, UserStats>,
+
+    /// Referrer's rewards for this market; required when `user_stats.referrer` is set
+    #[account(mut)]
+    pub referrer_rewards: Option<Account<'info, ReferrerRewards>>,
+
+    #[account(mut, address = market_state.fee_vault @ PerpError::InvalidFeeVault)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Quote mint's LendingPool; required once the account has lent or borrowed, or
+    /// when closing at a loss beyond its collateral
+    #[account(
+        mut,
+        seeds = [b"lending_pool", margin_account.quote_asset_mint.as_ref()],
+        bump = lending_pool.bump,
+    )]
+    pub lending_pool: Option<Account<'info, LendingPool>>,
+
+    /// The pool's lending vault; required with `lending_pool` when closing draws on it
+    #[account(mut)]
+    pub lending_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// =======================================
+// ACCOUNT DATA STRUCTS
+// =======================================
+
+#[account]
+pub struct MarketState {
+    pub authority: Pubkey,
+    pub base_asset_symbol: String,
+    pub quote_asset_mint: Pubkey,
+
+    // Funding
+    pub funding_rate: i64,
+    pub last_funding_time: i64,
+
+    // Maintenance margin ratio in bps
+    pub maintenance_margin_ratio_bps: u64,
+    pub base_margin_ratio_bps: u64,
+    pub auto_deleverage_enabled: bool,
+
+    pub fee_vault: Pubkey,
+    pub insurance_vault: Pubkey,
+
+    pub open_interest_long: u64,
+    pub open_interest_short: u64,
+    pub index_price: u64,
+
+    // Dutch auction discount
+    pub dutch_auction_discount_bps: u64,
+
+    // Virtual AMM
+    pub vamm_enabled: bool,
+    pub base_asset_reserve: u128,
+    pub quote_asset_reserve: u128,
+    pub sqrt_k: u128,
+    pub peg_multiplier: u128,
+    // Net base held by traders against the vAMM (longs positive)
+    pub vamm_net_base_position: i64,
+
+    // Order book (Pubkey::default() until initialize_order_book)
+    pub order_book: Pubkey,
+    pub event_queue: Pubkey,
+
+    // Flat fee paid to keepers executing trigger orders (quote units)
+    pub keeper_fee: u64,
+
+    // Trading fees in bps of notional
+    pub taker_fee_bps: u64,
+    // Negative for a maker rebate
+    pub maker_fee_bps: i64,
+    pub market_authority_bump: u8,
+
+    // Volume-based fee tiers, sorted by ascending min_volume
+    pub fee_tiers: [FeeTier; MAX_FEE_TIERS],
+    pub fee_tier_count: u8,
+
+    // Referral program (bps of the fee) and referral rewards still owed from the fee vault
+    pub referrer_fee_share_bps: u64,
+    pub referee_discount_bps: u64,
+    pub unclaimed_referral_rewards: u64,
+
+    // Fee vault balance reserved to cover vAMM repeg and depth-change costs
+    pub vamm_cost_reserved: u64,
+
+    // Pyth price feed every instruction pricing this market must read
+    pub oracle: Pubkey,
+}
+
+/// Maximum leverage allowed when opening positions or placing orders.
+pub const MAX_LEVERAGE: u64 = 10;
+
+/// Denominator for values expressed in basis points (1 bps = 0.01%).
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+pub const DEFAULT_TAKER_FEE_BPS: u64 = 5;
+pub const DEFAULT_MAKER_FEE_BPS: i64 = 2;
+/// Upper bound for any configurable trading fee (1%).
+pub const MAX_FEE_BPS: u64 = 100;
+pub const MAX_FEE_TIERS: usize = 8;
+pub const MAX_COLLATERAL_ASSETS: usize = 8;
+pub const MAX_MARGIN_POSITIONS: usize = 8;
+pub const RECENT_CLIENT_ORDER_IDS: usize = 16;
+
+pub const LENDING_INDEX_PRECISION: u128 = 1_000_000_000_000;
+pub const MAX_BORROW_RATE_BPS: u64 = 50_000; // 500% APR
+pub const SECONDS_PER_YEAR: i64 = 365 * 24 * 60 * 60;
+
+pub const MAX_SESSION_MARKETS: usize = 4;
+pub const MAX_SESSION_DURATION: i64 = 7 * 24 * 60 * 60;
+// Session key permissions (SessionKey::allowed_instructions)
+pub const SESSION_OPEN_POSITION: u16 = 1 << 0;
+pub const SESSION_CLOSE_POSITION: u16 = 1 << 1;
+pub const SESSION_PLACE_ORDER: u16 = 1 << 2;
+pub const SESSION_CANCEL_ORDER: u16 = 1 << 3;
+// Stop, trailing stop, bracket and TWAP orders
+pub const SESSION_PLACE_CONDITIONAL: u16 = 1 << 4;
+pub const SESSION_ALL: u16 = SESSION_OPEN_POSITION
+    | SESSION_CLOSE_POSITION
+    | SESSION_PLACE_ORDER
+    | SESSION_CANCEL_ORDER
+    | SESSION_PLACE_CONDITIONAL;
+/// Window over which UserStats volume decays to zero.
+pub const VOLUME_WINDOW_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// Precision of `MarketState::peg_multiplier`.
+pub const PEG_PRECISION: u128 = 1_000;
+
+impl MarketState {
+    pub const MAX_SIZE: usize =
+        32 + // authority
+        (4 + 10) + // base_asset_symbol
+        32 + // quote_asset_mint
+        8 +  // funding_rate
+        8 +  // last_funding_time
+        8 +  // maintenance_margin_ratio_bps
+        8 +  // base_margin_ratio_bps
+        1 +  // auto_deleverage_enabled
+        32 + // fee_vault
+        32 + // insurance_vault
+        8 +  // open_interest_long
+        8 +  // open_interest_short
+        8 +  // index_price
+        8 +  // dutch_auction_discount_bps
+        1 +  // vamm_enabled
+        16 + // base_asset_reserve
+        16 + // quote_asset_reserve
+        16 + // sqrt_k
+        16 + // peg_multiplier
+        8 +  // vamm_net_base_position
+        32 + // order_book
+        32 + // event_queue
+        8 +  // keeper_fee
+        8 +  // taker_fee_bps
+        8 +  // maker_fee_bps
+        1 +  // market_authority_bump
+        FeeTier::SIZE * MAX_FEE_TIERS + // fee_tiers
+        1 +  // fee_tier_count
+        8 +  // referrer_fee_share_bps
+        8 +  // referee_discount_bps
+        8 +  // unclaimed_referral_rewards
+        8 +  // vamm_cost_reserved
+        32;  // oracle
+
+    /// Size of the original layout, before the vAMM, order book, fee and referral
+    /// fields were appended. Markets of this size migrate via migrate_legacy_market.
+    pub const LEGACY_SIZE: usize =
+        32 + (4 + 10) + 32 + 8 + 8 + 8 + 8 + 1 + 32 + 32 + 8 + 8 + 8 + 8;
+
+    /// Fee in bps for a user with `volume_30d` of rolling volume, and the tier it came
+    /// from (0 = base market fees, n = the n-th entry of the tier table).
+    /// Negative for a maker rebate.
+    pub fn fee_for_volume(&self, volume_30d: u64, is_maker: bool) -> (i64, u8) {
+        let tiers = &self.fee_tiers[..self.fee_tier_count as usize];
+        match tiers.iter().rposition(|tier| volume_30d >= tier.min_volume) {
+            Some(i) => {
+                let tier = &tiers[i];
+                let bps = if is_maker { tier.maker_fee_bps } else { tier.taker_fee_bps as i64 };
+                (bps, i as u8 + 1)
+            }
+            None => {
+                let bps = if is_maker { self.maker_fee_bps } else { self.taker_fee_bps as i64 };
+                (bps, 0)
+            }
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct FeeTier {
+    // Minimum 30-day (taker + maker) notional volume to qualify
+    pub min_volume: u64,
+    pub taker_fee_bps: u64,
+    pub maker_fee_bps: i64,
+}
 
-    /// CHECK: Placeholder vault for fees
-    #[account(init, payer = authority, space = 8 + 165)]
-    pub fee_vault: AccountInfo<'info>,
+impl FeeTier {
+    pub const SIZE: usize = 8 + 8 + 8;
+}
+
+#[account]
+pub struct UserPosition {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub collateral: u64,
+    pub size: u64,
+    pub is_long: bool,
+    pub entry_price: u64,
+    pub unrealized_pnl: i64,
+    // Seed for the next order PDA placed against this position
+    pub next_order_seq: u64,
+    // Incremented each time a position is opened from flat or flipped
+    pub position_id: u64,
+    // Set on the first non-quote collateral deposit; margin checks then require UserCollateral
+    pub has_collateral_assets: bool,
+    // Ring of the client order ids used by the latest placements, so retries are rejected
+    // even after the original order filled, expired or was cancelled
+    pub recent_client_order_ids: [u64; RECENT_CLIENT_ORDER_IDS],
+    pub recent_client_order_id_cursor: u8,
+}
+
+impl UserPosition {
+    pub const MAX_SIZE: usize =
+        32 +  // user
+        32 +  // market
+        8 +   // collateral
+        8 +   // size
+        1 +   // is_long
+        8 +   // entry_price
+        8 +   // unrealized_pnl
+        8 +   // next_order_seq
+        8 +   // position_id
+        1 +   // has_collateral_assets
+        8 * RECENT_CLIENT_ORDER_IDS + // recent_client_order_ids
+        1;    // recent_client_order_id_cursor
+
+    /// Reject a client order id used by one of the last `RECENT_CLIENT_ORDER_IDS`
+    /// placements on this position, then remember it. 0 opts out.
+    pub fn record_client_order_id(&mut self, client_order_id: u64) -> Result<()> {
+        if client_order_id == 0 {
+            return Ok(());
+        }
+        require!(
+            !self.recent_client_order_ids.contains(&client_order_id),
+            PerpError::DuplicateClientOrderId
+        );
+        let slot = self.recent_client_order_id_cursor as usize % RECENT_CLIENT_ORDER_IDS;
+        self.recent_client_order_ids[slot] = client_order_id;
+        self.recent_client_order_id_cursor = ((slot + 1) % RECENT_CLIENT_ORDER_IDS) as u8;
+        Ok(())
+    }
+
+    /// Orders remember the position_id they were placed against, so bracket and stop
+    /// orders left over from a closed position cannot fire on a new one.
+    pub fn start_new_position(&mut self) -> Result<()> {
+        self.position_id = self.position_id.checked_add(1).ok_or(PerpError::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Accepted non-quote collateral for one market, seeds `[b"collateral_registry", market]`.
+#[account]
+pub struct CollateralRegistry {
+    pub market: Pubkey,
+    pub asset_count: u8,
+    pub assets: [CollateralAsset; MAX_COLLATERAL_ASSETS],
+    pub bump: u8,
+    // Decimals of the market's quote mint, the unit asset values are expressed in
+    pub quote_decimals: u8,
+}
+
+impl CollateralRegistry {
+    pub const MAX_SIZE: usize =
+        32 + // market
+        1 +  // asset_count
+        CollateralAsset::SIZE * MAX_COLLATERAL_ASSETS + // assets
+        1 +  // bump
+        1;   // quote_decimals
+
+    pub fn find(&self, mint: &Pubkey) -> Option<usize> {
+        self.assets[..self.asset_count as usize]
+            .iter()
+            .position(|asset| asset.mint == *mint)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct CollateralAsset {
+    pub mint: Pubkey,
+    pub oracle: Pubkey,
+    pub decimals: u8,
+    // Share of oracle value counted for initial / maintenance margin (bps)
+    pub initial_asset_weight_bps: u64,
+    pub maintenance_asset_weight_bps: u64,
+    pub deposit_cap: u64,
+    pub total_deposits: u64,
+}
+
+impl CollateralAsset {
+    pub const SIZE: usize = 32 + 32 + 1 + 8 + 8 + 8 + 8;
+}
+
+/// A user's balances of registered collateral assets, indexed like the registry,
+/// seeds `[b"user_collateral", user, market]`.
+#[account]
+pub struct UserCollateral {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub balances: [u64; MAX_COLLATERAL_ASSETS],
+    pub bump: u8,
+}
+
+impl UserCollateral {
+    pub const MAX_SIZE: usize = 32 + 32 + 8 * MAX_COLLATERAL_ASSETS + 1;
+
+    /// Fill in the owner on the first deposit (the account is `init_if_needed`).
+    pub fn init_if_new(&mut self, user: Pubkey, market: Pubkey, bump: u8) {
+        if self.user == Pubkey::default() {
+            self.user = user;
+            self.market = market;
+            self.bump = bump;
+        }
+    }
+}
+
+/// Cross-margin account: one pool of quote collateral backing positions in up to
+/// MAX_MARGIN_POSITIONS markets, seeds `[b"margin_account", owner, subaccount_id]`.
+/// Each subaccount is margined on its own.
+#[account]
+pub struct MarginAccount {
+    pub owner: Pubkey,
+    pub subaccount_id: u16,
+    pub quote_asset_mint: Pubkey,
+    pub collateral: u64,
+    pub positions: [MarginPosition; MAX_MARGIN_POSITIONS],
+    pub bump: u8,
+    // Opted in to lend part of its idle collateral (see update_lending)
+    pub lending_enabled: bool,
+    // Interest-bearing shares in the quote mint's LendingPool
+    pub lend_shares: u64,
+    // Debt to the LendingPool, in borrow shares
+    pub borrow_shares: u64,
+}
+
+impl MarginAccount {
+    pub const MAX_SIZE: usize =
+        32 + // owner
+        2 +  // subaccount_id
+        32 + // quote_asset_mint
+        8 +  // collateral
+        MarginPosition::SIZE * MAX_MARGIN_POSITIONS + // positions
+        1 +  // bump
+        1 +  // lending_enabled
+        8 +  // lend_shares
+        8;   // borrow_shares
+
+    pub fn find_position(&self, market: &Pubkey) -> Option<usize> {
+        self.positions
+            .iter()
+            .position(|p| p.size > 0 && p.market == *market)
+    }
+
+    pub fn free_slot(&self) -> Option<usize> {
+        self.positions.iter().position(|p| p.size == 0)
+    }
+}
+
+/// Pool of lent idle quote collateral, seeds `[b"lending_pool", quote_mint]`. Shares
+/// are valued through `deposit_index` and debt through `borrow_index`, both scaled
+/// by LENDING_INDEX_PRECISION.
+#[account]
+pub struct LendingPool {
+    pub authority: Pubkey,
+    pub quote_asset_mint: Pubkey,
+    pub lending_vault: Pubkey,
+    pub params: LendingParams,
+    pub deposit_index: u128,
+    pub borrow_index: u128,
+    pub total_deposit_shares: u64,
+    pub total_borrow_shares: u64,
+    pub last_update_ts: i64,
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct LendingParams {
+    // Share of a participating account's idle collateral that is lent
+    pub lend_fraction_bps: u64,
+    // Annual borrow rate: base + slope1 up to the optimal utilization, then slope2
+    pub base_rate_bps: u64,
+    pub optimal_utilization_bps: u64,
+    pub slope1_bps: u64,
+    pub slope2_bps: u64,
+}
+
+impl LendingPool {
+    pub const MAX_SIZE: usize =
+        32 + // authority
+        32 + // quote_asset_mint
+        32 + // lending_vault
+        8 * 5 + // params
+        16 + // deposit_index
+        16 + // borrow_index
+        8 +  // total_deposit_shares
+        8 +  // total_borrow_shares
+        8 +  // last_update_ts
+        1;   // bump
+
+    pub fn deposit_value(&self, shares: u64) -> Result<u64> {
+        let value = shares as u128 * self.deposit_index / LENDING_INDEX_PRECISION;
+        u64::try_from(value).map_err(|_| error!(PerpError::MathOverflow))
+    }
+
+    /// Debt owed for `shares`, rounded up.
+    pub fn debt_value(&self, shares: u64) -> Result<u64> {
+        let value = (shares as u128 * self.borrow_index).div_ceil(LENDING_INDEX_PRECISION);
+        u64::try_from(value).map_err(|_| error!(PerpError::MathOverflow))
+    }
+
+    pub fn shares_for_deposit(&self, amount: u64) -> Result<u64> {
+        let shares = amount as u128 * LENDING_INDEX_PRECISION / self.deposit_index;
+        u64::try_from(shares).map_err(|_| error!(PerpError::MathOverflow))
+    }
+
+    /// Deposits not currently lent out to borrowers.
+    pub fn available_liquidity(&self) -> Result<u64> {
+        Ok(self
+            .deposit_value(self.total_deposit_shares)?
+            .saturating_sub(self.debt_value(self.total_borrow_shares)?))
+    }
+
+    pub fn utilization_bps(&self) -> Result<u64> {
+        let deposits = self.deposit_value(self.total_deposit_shares)?;
+        if deposits == 0 {
+            return Ok(0);
+        }
+        let borrows = self.debt_value(self.total_borrow_shares)? as u128;
+        Ok((borrows * BPS_DENOMINATOR as u128 / deposits as u128).min(BPS_DENOMINATOR as u128) as u64)
+    }
+
+    /// Annual borrow rate in bps at `utilization_bps`.
+    pub fn borrow_rate_bps(&self, utilization_bps: u64) -> u64 {
+        let p = &self.params;
+        if utilization_bps <= p.optimal_utilization_bps {
+            p.base_rate_bps + p.slope1_bps * utilization_bps / p.optimal_utilization_bps
+        } else {
+            let excess = utilization_bps - p.optimal_utilization_bps;
+            let range = BPS_DENOMINATOR - p.optimal_utilization_bps;
+            p.base_rate_bps + p.slope1_bps + p.slope2_bps * excess / range
+        }
+    }
+
+    /// Accrue interest since the last update: debt grows through `borrow_index` and
+    /// the same interest is credited to lenders through `deposit_index`.
+    pub fn accrue(&mut self, now: i64) -> Result<()> {
+        let elapsed = now.saturating_sub(self.last_update_ts);
+        if elapsed <= 0 {
+            return Ok(());
+        }
+        self.last_update_ts = now;
+        if self.total_borrow_shares == 0 || self.total_deposit_shares == 0 {
+            return Ok(());
+        }
+
+        let rate_bps = self.borrow_rate_bps(self.utilization_bps()?) as u128;
+        let borrows = self.debt_value(self.total_borrow_shares)? as u128;
+        let period = BPS_DENOMINATOR as u128 * SECONDS_PER_YEAR as u128;
+
+        let interest = borrows * rate_bps * elapsed as u128 / period;
+        self.borrow_index += self.borrow_index * rate_bps * elapsed as u128 / period;
+        self.deposit_index += interest * LENDING_INDEX_PRECISION / self.total_deposit_shares as u128;
+        Ok(())
+    }
+
+    /// Mint deposit shares for `amount`; returns the shares.
+    pub fn deposit(&mut self, amount: u64) -> Result<u64> {
+        let shares = self.shares_for_deposit(amount)?;
+        self.total_deposit_shares = self
+            .total_deposit_shares
+            .checked_add(shares)
+            .ok_or(PerpError::MathOverflow)?;
+        Ok(shares)
+    }
+
+    /// Burn deposit shares; returns their value.
+    pub fn withdraw(&mut self, shares: u64) -> Result<u64> {
+        let amount = self.deposit_value(shares)?;
+        self.total_deposit_shares = self.total_deposit_shares.saturating_sub(shares);
+        Ok(amount)
+    }
+
+    /// Record a new debt of `amount`, rounding shares up; returns the shares.
+    pub fn borrow(&mut self, amount: u64) -> Result<u64> {
+        let shares = (amount as u128 * LENDING_INDEX_PRECISION).div_ceil(self.borrow_index);
+        let shares = u64::try_from(shares).map_err(|_| error!(PerpError::MathOverflow))?;
+        self.total_borrow_shares = self
+            .total_borrow_shares
+            .checked_add(shares)
+            .ok_or(PerpError::MathOverflow)?;
+        Ok(shares)
+    }
+
+    /// Repay `amount` of a debt of `debt_shares`; returns the shares repaid.
+    pub fn repay(&mut self, amount: u64, debt_shares: u64) -> Result<u64> {
+        let shares = if amount >= self.debt_value(debt_shares)? {
+            debt_shares
+        } else {
+            let shares = amount as u128 * LENDING_INDEX_PRECISION / self.borrow_index;
+            u64::try_from(shares).map_err(|_| error!(PerpError::MathOverflow))?
+        };
+        self.total_borrow_shares = self.total_borrow_shares.saturating_sub(shares);
+        Ok(shares)
+    }
+}
+
+/// One slot of a MarginAccount; empty when `size == 0`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug)]
+pub struct MarginPosition {
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub size: u64,
+    pub entry_price: u64,
+}
+
+impl MarginPosition {
+    pub const SIZE: usize = 32 + 1 + 8 + 8;
+}
+
+/// Time-bounded trading key registered by `owner`, seeds `[b"session_key", owner, signer]`.
+#[account]
+pub struct SessionKey {
+    pub owner: Pubkey,
+    pub signer: Pubkey,
+    pub markets: [Pubkey; MAX_SESSION_MARKETS],
+    pub market_count: u8,
+    // Per-trade cap on notional (size * price)
+    pub max_notional: u64,
+    // Bitmask of SESSION_* flags
+    pub allowed_instructions: u16,
+    pub expires_at: i64,
+    pub bump: u8,
+}
+
+impl SessionKey {
+    pub const MAX_SIZE: usize =
+        32 + // owner
+        32 + // signer
+        32 * MAX_SESSION_MARKETS + // markets
+        1 +  // market_count
+        8 +  // max_notional
+        2 +  // allowed_instructions
+        8 +  // expires_at
+        1;   // bump
+
+    pub fn allows(&self, market: &Pubkey, permission: u16, now: i64) -> bool {
+        now < self.expires_at
+            && self.allowed_instructions & permission == permission
+            && self.markets[..self.market_count as usize].contains(market)
+    }
+}
+
+/// Per-user trading stats shared across markets, seeds `[b"user_stats", user]`.
+/// Volumes are decaying accumulators approximating the last 30 days of notional.
+#[account]
+pub struct UserStats {
+    pub authority: Pubkey,
+    pub taker_volume_30d: u64,
+    pub maker_volume_30d: u64,
+    pub last_volume_update_ts: i64,
+    pub bump: u8,
+    // Set once by register_referrer; default when the user has no referrer
+    pub referrer: Pubkey,
+    // Hot key allowed to trade (never withdraw) for the user; default when unset
+    pub delegate: Pubkey,
+}
+
+impl UserStats {
+    pub const MAX_SIZE: usize =
+        32 + // authority
+        8 +  // taker_volume_30d
+        8 +  // maker_volume_30d
+        8 +  // last_volume_update_ts
+        1 +  // bump
+        32 + // referrer
+        32;  // delegate
+
+    /// Linearly decay both volumes by the time elapsed since the last update, so a
+    /// volume left untouched for a full window drops to zero.
+    pub fn decay_volume(&mut self, now: i64) {
+        let elapsed = now.saturating_sub(self.last_volume_update_ts).clamp(0, VOLUME_WINDOW_SECONDS);
+        if elapsed == 0 {
+            return;
+        }
+        let remaining = (VOLUME_WINDOW_SECONDS - elapsed) as u128;
+        let window = VOLUME_WINDOW_SECONDS as u128;
+        self.taker_volume_30d = (self.taker_volume_30d as u128 * remaining / window) as u64;
+        self.maker_volume_30d = (self.maker_volume_30d as u128 * remaining / window) as u64;
+        self.last_volume_update_ts = now;
+    }
+
+    pub fn record_volume(&mut self, notional: u64, is_maker: bool) {
+        let volume = if is_maker { &mut self.maker_volume_30d } else { &mut self.taker_volume_30d };
+        *volume = volume.saturating_add(notional);
+    }
+
+    pub fn total_volume_30d(&self) -> u64 {
+        self.taker_volume_30d.saturating_add(self.maker_volume_30d)
+    }
+}
+
+/// A referrer's claimable fee share for one market,
+/// seeds `[b"referrer_rewards", referrer, market]`.
+#[account]
+pub struct ReferrerRewards {
+    pub referrer: Pubkey,
+    pub market: Pubkey,
+    pub claimable: u64,
+    pub total_earned: u64,
+    pub bump: u8,
+}
+
+impl ReferrerRewards {
+    pub const MAX_SIZE: usize = 32 + 32 + 8 + 8 + 1;
+}
+
+/// Governance-controlled liquidity mining program for one market,
+/// seeds `[b"liquidity_mining", market]`.
+#[account]
+pub struct LiquidityMiningConfig {
+    pub market: Pubkey,
+    pub reward_vault: Pubkey,
+    pub epoch_duration: i64,
+    pub rewards_per_epoch: u64,
+    // Orders within this distance of the oracle price (bps) earn score
+    pub band_bps: u64,
+    pub current_epoch: u64,
+    pub epoch_start_ts: i64,
+    pub last_sample_ts: i64,
+    pub current_epoch_score: u128,
+    // Finalized totals of the previous epoch, used for claims
+    pub prev_epoch_score: u128,
+    pub prev_epoch_rewards: u64,
+    pub bump: u8,
+}
+
+impl LiquidityMiningConfig {
+    pub const MAX_SIZE: usize =
+        32 + // market
+        32 + // reward_vault
+        8 +  // epoch_duration
+        8 +  // rewards_per_epoch
+        8 +  // band_bps
+        8 +  // current_epoch
+        8 +  // epoch_start_ts
+        8 +  // last_sample_ts
+        16 + // current_epoch_score
+        16 + // prev_epoch_score
+        8 +  // prev_epoch_rewards
+        1;   // bump
+}
+
+/// A maker's time-weighted in-band resting size, seeds `[b"maker_score", market, owner]`.
+#[account]
+pub struct MakerScore {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub score: u128,
+    // Score of the last finished epoch, claimable until the next epoch ends
+    pub pending_epoch: u64,
+    pub pending_score: u128,
+    pub bump: u8,
+}
+
+impl MakerScore {
+    pub const MAX_SIZE: usize = 32 + 32 + 8 + 16 + 8 + 16 + 1;
+
+    /// Move the score into `pending` once its epoch has ended. Scores older than the
+    /// previous epoch are dropped.
+    pub fn roll_epoch(&mut self, current_epoch: u64) {
+        if self.epoch >= current_epoch {
+            return;
+        }
+        if self.epoch + 1 == current_epoch {
+            self.pending_epoch = self.epoch;
+            self.pending_score = self.score;
+        } else {
+            self.pending_epoch = 0;
+            self.pending_score = 0;
+        }
+        self.epoch = current_epoch;
+        self.score = 0;
+    }
+}
+
+/// Bracket order struct for OCO: stop_loss and take_profit.
+/// Closes `size` of the position (capped at the current size). A price of 0 leaves that
+/// leg unset, so several take-profit-only brackets can ladder out of one position.
+#[account]
+pub struct BracketOrder {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub stop_loss_price: u64,
+    pub take_profit_price: u64,
+    pub size: u64,
+    pub is_long: bool,
+    pub client_order_id: u64,
+    pub order_seq: u64,
+    pub bump: u8,
+    pub position_id: u64,
+    // Worst acceptable fill price (0 => no limit)
+    pub limit_price: u64,
+    // Unix timestamp from which the order can no longer execute (0 => no expiry)
+    pub expiry_ts: i64,
+    // Signer that paid the rent (default => the owner, for orders from older layouts)
+    pub payer: Pubkey,
+}
+
+impl BracketOrder {
+    pub const MAX_SIZE: usize =
+        32 + // user
+        32 + // market
+        8 +  // stop_loss_price
+        8 +  // take_profit_price
+        8 +  // size
+        1 +  // is_long
+        8 +  // client_order_id
+        8 +  // order_seq
+        1 +  // bump
+        8 +  // position_id
+        8 +  // limit_price
+        8 +  // expiry_ts
+        32;  // payer
+
+    /// Account the rent goes back to when the order is closed.
+    pub fn rent_payer(&self) -> Pubkey {
+        if self.payer == Pubkey::default() { self.user } else { self.payer }
+    }
+}
+
+#[account]
+pub struct StopOrder {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub trigger_price: u64,
+    pub is_take_profit: bool,
+    pub size: u64,
+    pub is_long: bool,
+    pub client_order_id: u64,
+    pub order_seq: u64,
+    pub bump: u8,
+
+    // Trailing stops: trigger_price trails watermark_price by trail_amount
+    pub is_trailing: bool,
+    pub trail_amount: u64,
+    pub trail_is_bps: bool,
+    pub watermark_price: u64,
+
+    pub position_id: u64,
+    // Worst acceptable fill price (0 => no limit)
+    pub limit_price: u64,
+    // Unix timestamp from which the order can no longer execute (0 => no expiry)
+    pub expiry_ts: i64,
+    // Signer that paid the rent (default => the owner, for orders from older layouts)
+    pub payer: Pubkey,
+}
+
+impl StopOrder {
+    pub const MAX_SIZE: usize =
+        32 +  // user
+        32 +  // market
+        8 +   // trigger_price
+        1 +   // is_take_profit
+        8 +   // size
+        1 +   // is_long
+        8 +   // client_order_id
+        8 +   // order_seq
+        1 +   // bump
+        1 +   // is_trailing
+        8 +   // trail_amount
+        1 +   // trail_is_bps
+        8 +   // watermark_price
+        8 +   // position_id
+        8 +   // limit_price
+        8 +   // expiry_ts
+        32;   // payer
+
+    /// Account the rent goes back to when the order is closed.
+    pub fn rent_payer(&self) -> Pubkey {
+        if self.payer == Pubkey::default() { self.user } else { self.payer }
+    }
+
+    /// Trigger price derived from the watermark: below it for longs, above it for shorts.
+    pub fn trailing_trigger_price(&self) -> Result<u64> {
+        let distance = if self.trail_is_bps {
+            (self.watermark_price as u128)
+                .checked_mul(self.trail_amount as u128)
+                .ok_or(PerpError::MathOverflow)?
+                .checked_div(BPS_DENOMINATOR as u128)
+                .ok_or(PerpError::MathOverflow)? as u64
+        } else {
+            self.trail_amount
+        };
+
+        if self.is_long {
+            Ok(self.watermark_price.saturating_sub(distance))
+        } else {
+            self.watermark_price
+                .checked_add(distance)
+                .ok_or_else(|| error!(PerpError::MathOverflow))
+        }
+    }
+
+    /// Move the watermark if `current_price` is better for the position.
+    /// Returns true if the watermark (and trigger price) changed.
+    pub fn update_watermark(&mut self, current_price: u64) -> Result<bool> {
+        let improved = if self.is_long {
+            current_price > self.watermark_price
+        } else {
+            current_price < self.watermark_price
+        };
+        if improved {
+            self.watermark_price = current_price;
+            self.trigger_price = self.trailing_trigger_price()?;
+        }
+        Ok(improved)
+    }
+
+    /// Long: stop-loss fires at or below the trigger, take-profit at or above.
+    /// Short: the reverse.
+    pub fn is_triggered(&self, current_price: u64) -> bool {
+        match (self.is_long, self.is_take_profit) {
+            (true, false) | (false, true) => current_price <= self.trigger_price,
+            (true, true) | (false, false) => current_price >= self.trigger_price,
+        }
+    }
+}
+
+#[account]
+pub struct TwapOrder {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub total_size: u64,
+    pub executed_size: u64,
+    pub num_slices: u32,
+    pub slices_executed: u32,
+    pub interval_seconds: i64,
+    // Worst acceptable fill price (0 => no limit)
+    pub limit_price: u64,
+    pub last_execution_ts: i64,
+    pub client_order_id: u64,
+    pub order_seq: u64,
+    pub bump: u8,
+    // Unix timestamp from which no further slices execute (0 => no expiry)
+    pub expiry_ts: i64,
+    // Per-slice notional cap of the session key that placed the order (0 => none)
+    pub session_max_notional: u64,
+    // Signer that paid the rent (default => the owner, for orders from older layouts)
+    pub payer: Pubkey,
+}
+
+impl TwapOrder {
+    pub const MAX_SIZE: usize =
+        32 + // user
+        32 + // market
+        1 +  // is_long
+        8 +  // total_size
+        8 +  // executed_size
+        4 +  // num_slices
+        4 +  // slices_executed
+        8 +  // interval_seconds
+        8 +  // limit_price
+        8 +  // last_execution_ts
+        8 +  // client_order_id
+        8 +  // order_seq
+        1 +  // bump
+        8 +  // expiry_ts
+        8 +  // session_max_notional
+        32;  // payer
+
+    /// Account the rent goes back to when the order is closed.
+    pub fn rent_payer(&self) -> Pubkey {
+        if self.payer == Pubkey::default() { self.user } else { self.payer }
+    }
+
+    /// Equal slices, with the rounding remainder going to the last one.
+    pub fn next_slice_size(&self) -> u64 {
+        let remaining_size = self.total_size - self.executed_size;
+        let remaining_slices = (self.num_slices - self.slices_executed) as u64;
+        if remaining_slices <= 1 {
+            remaining_size
+        } else {
+            self.total_size / self.num_slices as u64
+        }
+    }
+}
+
+/// Resting orders per side of the book.
+pub const ORDER_BOOK_DEPTH: usize = 64;
+/// Fill events the queue can hold before the crank must run.
+pub const EVENT_QUEUE_CAPACITY: usize = 128;
+
+#[zero_copy]
+#[derive(Default)]
+pub struct RestingOrder {
+    pub order_id: u64,
+    pub owner: Pubkey,
+    pub user_position: Pubkey,
+    pub price: u64,
+    pub size: u64,
+    // Unix timestamp after which the order cannot match (0 => no expiry)
+    pub expiry_ts: i64,
+    // User-supplied id (0 => none)
+    pub client_order_id: u64,
+}
 
-    /// CHECK: Placeholder vault for insurance fund
-    #[account(init, payer = authority, space = 8 + 165)]
-    pub insurance_vault: AccountInfo<'info>,
+impl RestingOrder {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry_ts != 0 && self.expiry_ts <= now
+    }
+}
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+/// Bids are sorted by descending price, asks by ascending price; ties keep time priority.
+#[account(zero_copy)]
+pub struct OrderBook {
+    pub market: Pubkey,
+    pub next_order_id: u64,
+    pub bid_count: u64,
+    pub ask_count: u64,
+    pub bids: [RestingOrder; ORDER_BOOK_DEPTH],
+    pub asks: [RestingOrder; ORDER_BOOK_DEPTH],
 }
 
-#[derive(Accounts)]
-pub struct DepositCollateral<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+impl OrderBook {
+    pub fn side_mut(&mut self, is_bid: bool) -> (&mut [RestingOrder; ORDER_BOOK_DEPTH], &mut u64) {
+        if is_bid {
+            (&mut self.bids, &mut self.bid_count)
+        } else {
+            (&mut self.asks, &mut self.ask_count)
+        }
+    }
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+    /// Insert an order behind all orders at the same or better price.
+    pub fn insert(&mut self, is_bid: bool, order: RestingOrder) -> Result<()> {
+        let (orders, count) = self.side_mut(is_bid);
+        let len = *count as usize;
+        require!(len < ORDER_BOOK_DEPTH, PerpError::OrderBookFull);
+
+        let index = orders[..len]
+            .iter()
+            .position(|resting| {
+                if is_bid {
+                    resting.price < order.price
+                } else {
+                    resting.price > order.price
+                }
+            })
+            .unwrap_or(len);
+
+        orders.copy_within(index..len, index + 1);
+        orders[index] = order;
+        *count += 1;
+        Ok(())
+    }
 
-    #[account(
-        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
-    )]
-    pub quote_asset_mint: Account<'info, Mint>,
+    pub fn remove_at(orders: &mut [RestingOrder; ORDER_BOOK_DEPTH], count: &mut u64, index: usize) {
+        let len = *count as usize;
+        orders.copy_within(index + 1..len, index);
+        orders[len - 1] = RestingOrder::default();
+        *count -= 1;
+    }
 
-    #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + UserPosition::MAX_SIZE,
-        seeds = [
-            b"user_position",
-            user.key().as_ref(),
-            market_state.key().as_ref()
-        ],
-        bump
-    )]
-    pub user_position: Account<'info, UserPosition>,
+    pub fn best(&self, is_bid: bool) -> Option<&RestingOrder> {
+        let (orders, count) = if is_bid { (&self.bids, self.bid_count) } else { (&self.asks, self.ask_count) };
+        orders[..count as usize].first()
+    }
 
-    #[account(mut)]
-    pub user_collateral_account: Account<'info, TokenAccount>,
+    pub fn find<F>(&self, predicate: F) -> Option<&RestingOrder>
+    where
+        F: Fn(&RestingOrder) -> bool,
+    {
+        self.bids[..self.bid_count as usize]
+            .iter()
+            .chain(self.asks[..self.ask_count as usize].iter())
+            .find(|order| predicate(order))
+    }
 
-    #[account(
-        init_if_needed,
-        payer = user,
-        token::mint = quote_asset_mint,
-        token::authority = user_vault_authority,
-        seeds = [
-            b"user_vault",
-            user.key().as_ref(),
-            market_state.key().as_ref()
-        ],
-        bump
-    )]
-    pub user_vault: Account<'info, TokenAccount>,
+    /// Total notional of the orders resting for `position` on both sides.
+    pub fn open_notional(&self, position: &Pubkey) -> Result<u128> {
+        self.bids[..self.bid_count as usize]
+            .iter()
+            .chain(self.asks[..self.ask_count as usize].iter())
+            .filter(|order| order.user_position == *position)
+            .try_fold(0u128, |total, order| {
+                total.checked_add(notional(order.size, order.price)?).ok_or_else(|| error!(PerpError::MathOverflow))
+            })
+    }
 
-    /// CHECK:
-    #[account(
-        seeds = [
-            b"user_vault",
-            user.key().as_ref(),
-            market_state.key().as_ref()
-        ],
-        bump
-    )]
-    pub user_vault_authority: AccountInfo<'info>,
+    /// Remove every expired order on one side, returning them in book order.
+    pub fn remove_expired(&mut self, is_bid: bool, now: i64) -> Vec<(bool, RestingOrder)> {
+        self.remove_where(is_bid, |order| order.is_expired(now))
+    }
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    /// Remove every order on one side matching `predicate`, returning them in book order.
+    pub fn remove_where<F>(&mut self, is_bid: bool, predicate: F) -> Vec<(bool, RestingOrder)>
+    where
+        F: Fn(&RestingOrder) -> bool,
+    {
+        let (orders, count) = self.side_mut(is_bid);
+        let mut removed = Vec::new();
+        let mut index = 0;
+        while index < *count as usize {
+            if predicate(&orders[index]) {
+                removed.push((is_bid, orders[index]));
+                Self::remove_at(orders, count, index);
+            } else {
+                index += 1;
+            }
+        }
+        removed
+    }
+
+    /// Remove the first order (bids, then asks) matching `predicate`.
+    /// Returns the side (true for bids) and the removed order.
+    pub fn remove_by<F>(&mut self, predicate: F) -> Option<(bool, RestingOrder)>
+    where
+        F: Fn(&RestingOrder) -> bool,
+    {
+        for is_bid in [true, false] {
+            let (orders, count) = self.side_mut(is_bid);
+            if let Some(index) = orders[..*count as usize].iter().position(&predicate) {
+                let order = orders[index];
+                Self::remove_at(orders, count, index);
+                return Some((is_bid, order));
+            }
+        }
+        None
+    }
 }
 
-#[derive(Accounts)]
-pub struct WithdrawCollateral<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[zero_copy]
+pub struct FillEvent {
+    pub maker_owner: Pubkey,
+    pub maker_position: Pubkey,
+    pub taker_owner: Pubkey,
+    pub taker_position: Pubkey,
+    pub maker_order_id: u64,
+    pub maker_client_order_id: u64,
+    pub price: u64,
+    pub size: u64,
+    pub timestamp: i64,
+    pub taker_is_long: u8,
+    pub _padding: [u8; 7],
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+/// Ring buffer of fills waiting to be settled by consume_events.
+#[account(zero_copy)]
+pub struct EventQueue {
+    pub market: Pubkey,
+    pub head: u64,
+    pub count: u64,
+    pub seq_num: u64,
+    pub events: [FillEvent; EVENT_QUEUE_CAPACITY],
+}
 
-    #[account(
-        constraint = market_state.quote_asset_mint == quote_asset_mint.key() @ PerpError::InvalidMint
-    )]
-    pub quote_asset_mint: Account<'info, Mint>,
+impl EventQueue {
+    pub fn push(&mut self, event: FillEvent) -> Result<()> {
+        require!((self.count as usize) < EVENT_QUEUE_CAPACITY, PerpError::EventQueueFull);
+        let index = (self.head as usize + self.count as usize) % EVENT_QUEUE_CAPACITY;
+        self.events[index] = event;
+        self.count += 1;
+        self.seq_num += 1;
+        Ok(())
+    }
 
-    #[account(
-        mut,
-        has_one = user @ PerpError::Unauthorized
-    )]
-    pub user_position: Account<'info, UserPosition>,
+    pub fn peek(&self) -> Option<&FillEvent> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(&self.events[self.head as usize])
+    }
 
-    /// CHECK:
-    #[account(
-        seeds = [
-            b"user_vault",
-            user_position.user.as_ref(),
-            market_state.key().as_ref()
-        ],
-        bump,
-    )]
-    pub user_vault_authority: AccountInfo<'info>,
+    /// Total notional of the queued fills that `position` is a party to and that
+    /// consume_events has not yet applied to it.
+    pub fn pending_notional(&self, position: &Pubkey) -> Result<u128> {
+        (0..self.count as usize)
+            .map(|offset| &self.events[(self.head as usize + offset) % EVENT_QUEUE_CAPACITY])
+            .filter(|event| event.maker_position == *position || event.taker_position == *position)
+            .try_fold(0u128, |total, event| {
+                total.checked_add(notional(event.size, event.price)?).ok_or_else(|| error!(PerpError::MathOverflow))
+            })
+    }
 
-    #[account(
-        mut,
-        constraint = user_vault.mint == quote_asset_mint.key() @ PerpError::InvalidMint
-    )]
-    pub user_vault: Account<'info, TokenAccount>,
+    /// Push a popped event back at the tail without counting it as a new fill.
+    pub fn requeue(&mut self, event: FillEvent) -> Result<()> {
+        require!((self.count as usize) < EVENT_QUEUE_CAPACITY, PerpError::EventQueueFull);
+        let index = (self.head as usize + self.count as usize) % EVENT_QUEUE_CAPACITY;
+        self.events[index] = event;
+        self.count += 1;
+        Ok(())
+    }
 
-    #[account(mut)]
-    pub user_collateral_account: Account<'info, TokenAccount>,
+    pub fn pop(&mut self) -> Result<FillEvent> {
+        require!(self.count > 0, PerpError::EventQueueEmpty);
+        let event = self.events[self.head as usize];
+        self.head = (self.head + 1) % EVENT_QUEUE_CAPACITY as u64;
+        self.count -= 1;
+        Ok(event)
+    }
+}
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeInForce {
+    /// Rests until filled or cancelled.
+    GoodTillCancel,
+    /// Rests until `expiry_ts`; expired orders are removed at match time.
+    GoodTillTime,
+    /// Rejected if any part would cross the book.
+    PostOnly,
+    /// Repriced one tick behind the best opposite order if it would cross.
+    PostOnlySlide,
+    /// Matches what it can, the remainder is cancelled.
+    ImmediateOrCancel,
+    /// Must fill completely on placement or the instruction fails.
+    FillOrKill,
 }
 
-#[derive(Accounts)]
-pub struct OpenPosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+impl TimeInForce {
+    pub fn can_rest(&self) -> bool {
+        !matches!(self, TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill)
+    }
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+/// Parameters shared by all order entry instructions. Stops, brackets and trailing
+/// stops close the position, so `is_long` is the closing side; for them and TWAP orders
+/// `price` is the worst acceptable fill price (0 => none) and only good-till-cancel or
+/// good-till-time apply. `self_trade_behavior` only matters for orders matched on the book.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub struct OrderParams {
+    pub is_long: bool,
+    pub price: u64,
+    pub size: u64,
+    pub time_in_force: TimeInForce,
+    // Only used with GoodTillTime
+    pub expiry_ts: i64,
+    // User-supplied id for reconciliation and cancel-by-client-id (0 => none)
+    pub client_order_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+}
 
-    #[account(
-        mut,
-        has_one = user @ PerpError::Unauthorized,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+/// What happens when a taker order would match a resting order of the same owner.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTradeBehavior {
+    /// Cancel the rest of the incoming order; the resting order is kept.
+    CancelTaker,
+    /// Cancel the resting order and keep matching.
+    CancelMaker,
+    /// Cancel the resting order and the rest of the incoming order.
+    CancelBoth,
+    /// Reduce both orders by the overlapping size without trading and cancel the smaller one.
+    DecrementAndCancel,
+}
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+// =======================================
+// EVENTS
+// =======================================
+#[event]
+pub struct PositionOpened {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub size: u64,
+    pub is_long: bool,
 }
 
-#[derive(Accounts)]
-pub struct ClosePosition<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[event]
+pub struct PositionClosed {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub realized_pnl: i64,
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct PositionLiquidated {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub penalty: i64,
+    pub liquidation_size: u64,
+}
 
-    #[account(mut, has_one = user @ PerpError::Unauthorized)]
-    pub user_position: Account<'info, UserPosition>,
+#[event]
+pub struct FundingRateUpdated {
+    pub market: Pubkey,
+    pub new_funding_rate: i64,
+}
 
-    /// CHECK:
-    pub oracle_price_feed_account: AccountInfo<'info>,
+#[event]
+pub struct FundingSettled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub funding_payment: i64,
 }
 
-#[derive(Accounts)]
-pub struct LiquidatePosition<'info> {
-    #[account(mut)]
-    pub liquidator: Signer<'info>,
+#[event]
+pub struct CollateralDeposited {
+    pub user: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct CollateralWithdrawn {
+    pub user: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(mut)]
-    pub user_position: Account<'info, UserPosition>,
+#[event]
+pub struct VammRepegged {
+    pub market: Pubkey,
+    pub old_peg_multiplier: u128,
+    pub new_peg_multiplier: u128,
+    /// Quote reserved from the fee vault (negative when reserve was released)
+    pub cost: i64,
+}
 
-    /// CHECK:
-    pub oracle_price_feed_account: AccountInfo<'info>,
+#[event]
+pub struct VammDepthUpdated {
+    pub market: Pubkey,
+    pub old_sqrt_k: u128,
+    pub new_sqrt_k: u128,
+    /// Quote reserved from the fee vault (negative when reserve was released)
+    pub cost: i64,
 }
 
-#[derive(Accounts)]
-pub struct UpdateFundingRate<'info> {
-    #[account(mut)]
-    pub authority: Signer<'info>,
+#[event]
+pub struct LimitOrderPlaced {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_order_id: u64,
+    pub is_long: bool,
+    pub price: u64,
+    pub size: u64,
+    pub filled_size: u64,
+    pub time_in_force: TimeInForce,
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct LimitOrderCancelled {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_order_id: u64,
+    pub is_long: bool,
+    pub remaining_size: u64,
+}
 
-    /// CHECK:
-    pub oracle_price_feed_account: AccountInfo<'info>,
+#[event]
+pub struct LimitOrderExpired {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub order_id: u64,
+    pub client_order_id: u64,
+    pub is_long: bool,
+    pub remaining_size: u64,
 }
 
-#[derive(Accounts)]
-pub struct SettleFunding<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct OrderFilled {
+    pub market: Pubkey,
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub maker_order_id: u64,
+    pub maker_client_order_id: u64,
+    pub price: u64,
+    pub size: u64,
+    pub taker_is_long: bool,
+}
 
-    #[account(mut)]
-    pub user_position: Account<'info, UserPosition>,
+#[event]
+pub struct CollateralAssetConfigured {
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub oracle: Pubkey,
+    pub initial_asset_weight_bps: u64,
+    pub maintenance_asset_weight_bps: u64,
+    pub deposit_cap: u64,
 }
 
-#[derive(Accounts)]
-pub struct PlaceBracketOrder<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[event]
+pub struct CollateralAssetDeposited {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct CollateralAssetWithdrawn {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
 
-    #[account(
-        mut,
-        has_one = user @ PerpError::Unauthorized,
-    )]
-    pub user_position: Account<'info, UserPosition>,
+#[event]
+pub struct DepositedFor {
+    pub depositor: Pubkey,
+    pub beneficiary: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct LendingParamsUpdated {
+    pub quote_asset_mint: Pubkey,
+    pub params: LendingParams,
+}
 
-    #[account(init, payer = user, space = 8 + BracketOrder::MAX_SIZE)]
-    pub bracket_order: Account<'info, BracketOrder>,
+#[event]
+pub struct LendingUpdated {
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+    pub enabled: bool,
+    pub lend_shares: u64,
+    pub borrow_shares: u64,
+    pub repaid: u64,
+    pub collateral: u64,
+}
 
-    /// CHECK:
-    pub oracle_price_feed_account: AccountInfo<'info>,
+#[event]
+pub struct SessionKeyRegistered {
+    pub owner: Pubkey,
+    pub signer: Pubkey,
+    pub max_notional: u64,
+    pub allowed_instructions: u16,
+    pub expires_at: i64,
+}
 
-    pub system_program: Program<'info, System>,
+#[event]
+pub struct SessionKeyRevoked {
+    pub owner: Pubkey,
+    pub signer: Pubkey,
 }
 
-#[derive(Accounts)]
-pub struct TriggerBracketOrder<'info> {
-    #[account(mut)]
-    pub market_state: Account<'info, MarketState>,
+#[event]
+pub struct DelegateUpdated {
+    pub user: Pubkey,
+    pub delegate: Pubkey,
+}
 
-    #[account(mut)]
-    pub user_position: Account<'info, UserPosition>,
+#[event]
+pub struct AccountReallocated {
+    pub account: Pubkey,
+    pub old_len: u64,
+    pub new_len: u64,
+}
 
-    #[account(mut)]
-    pub bracket_order: Account<'info, BracketOrder>,
+#[event]
+pub struct LegacyMarketMigrated {
+    pub market: Pubkey,
+    pub fee_vault: Pubkey,
+}
 
-    /// CHECK:
-    pub oracle_price_feed_account: AccountInfo<'info>,
+#[event]
+pub struct VaultAuthorityMigrated {
+    pub user: Pubkey,
+    pub scope: Pubkey,
+    pub vault: Pubkey,
+    pub vault_authority: Pubkey,
+}
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+#[event]
+pub struct MarginDeposited {
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+    pub amount: u64,
 }
 
-// For backward-compat with original place_stop_order, trigger_stop_order.
-// can skip them or keep them if needed.
+#[event]
+pub struct MarginWithdrawn {
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+    pub amount: u64,
+}
 
-// =======================================
-// ACCOUNT DATA STRUCTS
-// =======================================
+#[event]
+pub struct SubaccountTransfer {
+    pub owner: Pubkey,
+    pub from_subaccount_id: u16,
+    pub to_subaccount_id: u16,
+    pub amount: u64,
+    pub received: u64,
+}
 
-#[account]
-pub struct MarketState {
-    pub authority: Pubkey,
-    pub base_asset_symbol: String,
-    pub quote_asset_mint: Pubkey,
+#[event]
+pub struct CrossPositionUpdated {
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+    pub market: Pubkey,
+    pub is_long: bool,
+    pub size: u64,
+    pub price: u64,
+    pub realized_pnl: i64,
+}
 
-    // Funding
-    pub funding_rate: i64,
-    pub last_funding_time: i64,
+#[event]
+pub struct CrossPositionLiquidated {
+    pub owner: Pubkey,
+    pub margin_account: Pubkey,
+    pub market: Pubkey,
+    pub liquidator: Pubkey,
+    pub size: u64,
+    pub price: u64,
+    pub realized_pnl: i64,
+    pub net_equity: i64,
+    pub liquidator_reward: u64,
+}
 
-    // Maintenance margin ratio in bps
-    pub maintenance_margin_ratio_bps: u64,
-    pub base_margin_ratio_bps: u64,
-    pub auto_deleverage_enabled: bool,
+#[event]
+pub struct FeeCharged {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+    pub fee_bps: i64,
+    pub fee_tier: u8,
+    pub is_maker: bool,
+    pub referrer_reward: u64,
+}
 
-    pub fee_vault: Pubkey,
-    pub insurance_vault: Pubkey,
+#[event]
+pub struct MakerRebatePaid {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub amount: u64,
+}
 
-    pub open_interest_long: u64,
-    pub open_interest_short: u64,
-    pub index_price: u64,
+#[event]
+pub struct LiquidityMiningEpochEnded {
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub total_score: u128,
+    pub rewards: u64,
+}
 
-    // Dutch auction discount
-    pub dutch_auction_discount_bps: u64,
+#[event]
+pub struct LiquidityMiningRewardsClaimed {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub epoch: u64,
+    pub amount: u64,
 }
 
-impl MarketState {
-    pub const MAX_SIZE: usize =
-        32 + // authority
-        (4 + 10) + // base_asset_symbol
-        32 + // quote_asset_mint
-        8 +  // funding_rate
-        8 +  // last_funding_time
-        8 +  // maintenance_margin_ratio_bps
-        8 +  // base_margin_ratio_bps
-        1 +  // auto_deleverage_enabled
-        32 + // fee_vault
-        32 + // insurance_vault
-        8 +  // open_interest_long
-        8 +  // open_interest_short
-        8 +  // index_price
-        8;   // dutch_auction_discount_bps
+#[event]
+pub struct ReferrerRegistered {
+    pub referee: Pubkey,
+    pub referrer: Pubkey,
 }
 
-#[account]
-pub struct UserPosition {
-    pub user: Pubkey,
+#[event]
+pub struct ReferralRewardsClaimed {
+    pub referrer: Pubkey,
     pub market: Pubkey,
-    pub collateral: u64,
-    pub size: u64,
-    pub is_long: bool,
-    pub entry_price: u64,
-    pub unrealized_pnl: i64,
+    pub amount: u64,
 }
 
-impl UserPosition {
-    pub const MAX_SIZE: usize =
-        32 +  // user
-        32 +  // market
-        8 +   // collateral
-        8 +   // size
-        1 +   // is_long
-        8 +   // entry_price
-        8;    // unrealized_pnl
+#[event]
+pub struct FeesWithdrawn {
+    pub market: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
 }
 
-/// Bracket order struct for OCO: stop_loss and take_profit.
-#[account]
-pub struct BracketOrder {
+#[event]
+pub struct KeeperRewardPaid {
+    pub keeper: Pubkey,
     pub user: Pubkey,
     pub market: Pubkey,
-    pub stop_loss_price: u64,
-    pub take_profit_price: u64,
-    pub size: u64,
-    pub is_long: bool,
-}
-
-impl BracketOrder {
-    pub const MAX_SIZE: usize =
-        32 + // user
-        32 + // market
-        8 +  // stop_loss_price
-        8 +  // take_profit_price
-        8 +  // size
-        1;   // is_long
+    pub amount: u64,
 }
 
-#[account]
-pub struct StopOrder {
+#[event]
+pub struct TwapOrderPlaced {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub trigger_price: u64,
-    pub is_take_profit: bool,
-    pub size: u64,
     pub is_long: bool,
+    pub total_size: u64,
+    pub num_slices: u32,
+    pub interval_seconds: i64,
+    pub limit_price: u64,
+    pub client_order_id: u64,
 }
 
-impl StopOrder {
-    pub const MAX_SIZE: usize =
-        32 +  // user
-        32 +  // market
-        8 +   // trigger_price
-        1 +   // is_take_profit
-        8 +   // size
-        1;    // is_long
+#[event]
+pub struct TwapSliceExecuted {
+    pub user: Pubkey,
+    pub market: Pubkey,
+    pub slice_size: u64,
+    pub fill_price: u64,
+    pub slices_executed: u32,
+    pub num_slices: u32,
 }
 
-// =======================================
-// EVENTS
-// =======================================
 #[event]
-pub struct PositionOpened {
+pub struct TwapOrderCancelled {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub size: u64,
-    pub is_long: bool,
+    pub executed_size: u64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct PositionClosed {
+pub struct StopOrderPlaced {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub realized_pnl: i64,
+    pub trigger_price: u64,
+    pub is_take_profit: bool,
+    pub size: u64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct PositionLiquidated {
+pub struct StopOrderTriggered {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub penalty: i64,
-    pub liquidation_size: u64,
+    pub size: u64,
+    pub realized_pnl: i64,
 }
 
 #[event]
-pub struct FundingRateUpdated {
+pub struct TrailingStopUpdated {
+    pub user: Pubkey,
     pub market: Pubkey,
-    pub new_funding_rate: i64,
+    pub watermark_price: u64,
+    pub trigger_price: u64,
 }
 
 #[event]
-pub struct FundingSettled {
+pub struct StopOrderCancelled {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub funding_payment: i64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct CollateralDeposited {
+pub struct BracketOrderPlaced {
     pub user: Pubkey,
-    pub amount: u64,
+    pub market: Pubkey,
+    pub stop_loss_price: u64,
+    pub take_profit_price: u64,
+    pub size: u64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct CollateralWithdrawn {
+pub struct BracketOrderModified {
     pub user: Pubkey,
-    pub amount: u64,
+    pub market: Pubkey,
+    pub stop_loss_price: u64,
+    pub take_profit_price: u64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct StopOrderPlaced {
+pub struct BracketOrderTriggered {
     pub user: Pubkey,
     pub market: Pubkey,
-    pub trigger_price: u64,
     pub is_take_profit: bool,
+    pub size: u64,
+    pub realized_pnl: i64,
+    pub client_order_id: u64,
 }
 
 #[event]
-pub struct StopOrderTriggered {
+pub struct BracketOrderCancelled {
     pub user: Pubkey,
     pub market: Pubkey,
+    pub client_order_id: u64,
 }
 
- // Bracket order events can be added if needed.  
-
 
 // =======================================
 // ERRORS
@@ -1073,4 +8503,169 @@ pub enum PerpError {
 
     #[msg("Invalid mint.")]
     InvalidMint,
+
+    #[msg("vAMM is not enabled for this market.")]
+    VammNotEnabled,
+
+    #[msg("vAMM is already enabled for this market.")]
+    VammAlreadyEnabled,
+
+    #[msg("Insufficient vAMM liquidity.")]
+    InsufficientVammLiquidity,
+
+    #[msg("Account does not belong to this market.")]
+    InvalidMarket,
+
+    #[msg("Order book is full.")]
+    OrderBookFull,
+
+    #[msg("Order not found.")]
+    OrderNotFound,
+
+    #[msg("Event queue is full, crank consume_events.")]
+    EventQueueFull,
+
+    #[msg("Event queue is empty.")]
+    EventQueueEmpty,
+
+    #[msg("Post-only order would cross the book.")]
+    PostOnlyWouldCross,
+
+    #[msg("Fill-or-kill order cannot be filled completely.")]
+    FillOrKillNotFilled,
+
+    #[msg("Order expiry is in the past.")]
+    OrderExpired,
+
+    #[msg("Client order id is already in use by a resting order.")]
+    DuplicateClientOrderId,
+
+    #[msg("Order no longer matches the position.")]
+    StaleOrder,
+
+    #[msg("Stop loss and take profit must be on opposite sides of the current price.")]
+    InvalidTriggerPrice,
+
+    #[msg("Stop order is not a trailing stop.")]
+    NotTrailingStop,
+
+    #[msg("TWAP order has already executed all slices.")]
+    TwapCompleted,
+
+    #[msg("TWAP interval has not elapsed since the last slice.")]
+    TwapIntervalNotElapsed,
+
+    #[msg("Fill price is outside the order's limit price.")]
+    PriceLimitExceeded,
+
+    #[msg("Fee vault does not belong to this market.")]
+    InvalidFeeVault,
+
+    #[msg("Fee exceeds the maximum allowed.")]
+    InvalidFee,
+
+    #[msg("A required remaining account was not provided.")]
+    MissingRemainingAccount,
+
+    #[msg("Too many fee tiers.")]
+    TooManyFeeTiers,
+
+    #[msg("Fee tiers must be sorted by ascending minimum volume.")]
+    InvalidFeeTiers,
+
+    #[msg("Referrer has already been registered.")]
+    ReferrerAlreadySet,
+
+    #[msg("Invalid referrer.")]
+    InvalidReferrer,
+
+    #[msg("Fee vault balance is reserved or insufficient.")]
+    InsufficientFeeVaultBalance,
+
+    #[msg("Invalid liquidity mining parameters.")]
+    InvalidLiquidityMiningParams,
+
+    #[msg("No rewards to claim.")]
+    NoRewardsToClaim,
+
+    #[msg("Initial asset weight must not exceed the maintenance weight, which must not exceed 100%.")]
+    InvalidAssetWeight,
+
+    #[msg("Collateral registry is full.")]
+    CollateralRegistryFull,
+
+    #[msg("Collateral mint is not listed in the registry.")]
+    CollateralNotListed,
+
+    #[msg("Deposit would exceed the collateral asset's deposit cap.")]
+    DepositCapExceeded,
+
+    #[msg("Collateral registry and user collateral accounts are required for this position.")]
+    MissingCollateralAccounts,
+
+    #[msg("Mint has a freeze authority or token extension that is not supported as collateral.")]
+    UnsupportedMint,
+
+    #[msg("All margin account position slots are in use.")]
+    MarginAccountFull,
+
+    #[msg("Source and destination subaccounts must differ.")]
+    InvalidSubaccount,
+
+    #[msg("Vault still uses the legacy authority; call migrate_vault_authority first.")]
+    VaultNotMigrated,
+
+    #[msg("Vault is not owned by the legacy vault authority.")]
+    VaultAlreadyMigrated,
+
+    #[msg("A user cannot be their own delegate.")]
+    InvalidDelegate,
+
+    #[msg("Invalid session key scope or expiry.")]
+    InvalidSessionKey,
+
+    #[msg("Trade notional exceeds the session key's limit.")]
+    SessionNotionalExceeded,
+
+    #[msg("Invalid lending parameters.")]
+    InvalidLendingParams,
+
+    #[msg("Lending pool account is required for this margin account.")]
+    MissingLendingPool,
+
+    #[msg("Lending vault does not match the lending pool.")]
+    InvalidLendingVault,
+
+    #[msg("Not enough idle liquidity in the lending pool.")]
+    InsufficientLendingLiquidity,
+
+    #[msg("Account type cannot be migrated.")]
+    UnknownAccountLayout,
+
+    #[msg("Account already uses the current layout.")]
+    AccountAlreadyMigrated,
+
+    #[msg("Market uses the original layout; call migrate_legacy_market instead.")]
+    LegacyMarketLayout,
+
+    #[msg("Market already has an order book.")]
+    OrderBookAlreadyInitialized,
+
+    #[msg("Time in force is not supported for this order type.")]
+    UnsupportedTimeInForce,
+
+    #[msg("Order side must close the position.")]
+    InvalidOrderSide,
+
+    #[msg("Account is not a stop, bracket or TWAP order.")]
+    NotConditionalOrder,
+
+    #[msg("Price feed is not the market's oracle.")]
+    InvalidOracle,
+
+    #[msg("Referred users must pass their referrer's rewards account.")]
+    MissingReferrerRewards,
+
+    #[msg("Rent must go back to the account that paid it.")]
+    InvalidRentReceiver,
 }
@@ -1,15 +1,35 @@
+import {
+  createMint,
+  createAssociatedTokenAccount,
+  mintTo,
+  getAccount,
+  ExtensionType,
+  getMintLen,
+  createInitializeMintInstruction,
+  createInitializePermanentDelegateInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  TOKEN_2022_PROGRAM_ID,
+} from "@solana/spl-token";
+
 describe("Perpetual Program Tests", () => {
 
   const TOKEN_PROGRAM_ID = new web3.PublicKey("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+  // Pyth SOL/USD price feed on devnet, used as every test market's oracle
+  const SOL_USD_FEED = new web3.PublicKey("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix");
+  const PYTH_DEVNET_PROGRAM = new web3.PublicKey("gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s");
+  // Positions fill at the raw oracle price, so traders that open a few units need
+  // collateral well above notional / 10 at that price.
+  const POSITION_COLLATERAL = 100_000_000_000;
 
-  let marketStateKp, feeVaultKp, insuranceVaultKp;
+  let marketStateKp, insuranceVaultKp;
   let userPositionKp, userVaultKp;
   let quoteAssetMint;
+  // Real SPL mint and market used by the vault round-trip tests
+  let realMint, realMarket, realUserTokenAccount;
 
   before(async () => {
     // Generate keypairs for the MarketState, Vaults, and User
     marketStateKp = web3.Keypair.generate();
-    feeVaultKp = web3.Keypair.generate();
     insuranceVaultKp = web3.Keypair.generate();
     userPositionKp = web3.Keypair.generate();
     userVaultKp = web3.Keypair.generate();
@@ -18,21 +38,284 @@ describe("Perpetual Program Tests", () => {
     quoteAssetMint = web3.Keypair.generate().publicKey;
   });
 
+  // Aggregate price of a Pyth v2 price account, in the feed's own exponent.
+  const readOraclePrice = async (feed: web3.PublicKey) => {
+    const info = await pg.connection.getAccountInfo(feed);
+    return new BN(info.data.readBigInt64LE(208).toString());
+  };
+
+  const readOracleExpo = async (feed: web3.PublicKey) =>
+    (await pg.connection.getAccountInfo(feed)).data.readInt32LE(20);
+
+  // A devnet Pyth price feed updated in the last 30 seconds whose exponent is not `expo`.
+  const findLiveFeedWithOtherExpo = async (expo: number) => {
+    const now = Math.floor(Date.now() / 1000);
+    const accounts = await pg.connection.getProgramAccounts(PYTH_DEVNET_PROGRAM, {
+      filters: [{ dataSize: 3312 }],
+      dataSlice: { offset: 0, length: 232 },
+    });
+    const feed = accounts.find(({ account: { data } }) =>
+      data.readUInt32LE(8) === 3 && // price account
+      data.readInt32LE(20) !== expo &&
+      data.readBigInt64LE(208) > BigInt(0) &&
+      data.readUInt32LE(224) === 1 && // trading
+      now - Number(data.readBigInt64LE(96)) < 30
+    );
+    assert(feed, "No live devnet feed with a different exponent");
+    return feed.pubkey;
+  };
+
+  const pda = (...seeds: Buffer[]) =>
+    web3.PublicKey.findProgramAddressSync(seeds, pg.program.programId)[0];
+
+  const orderParams = (overrides = {}) => ({
+    isLong: true,
+    price: new BN(0),
+    size: new BN(1),
+    timeInForce: { goodTillCancel: {} },
+    expiryTs: new BN(0),
+    clientOrderId: new BN(0),
+    selfTradeBehavior: { cancelMaker: {} },
+    ...overrides,
+  });
+
+  // A market on a real SPL mint with an order book, for tests that need several traders.
+  const createTradingMarket = async () => {
+    const authority = pg.wallet.publicKey;
+    const mint = await createMint(pg.connection, pg.wallet.keypair, authority, null, 6);
+    const market = web3.Keypair.generate();
+    const insurance = web3.Keypair.generate();
+
+    await pg.program.methods
+      .initializeMarket(new BN(0), "SOL", null)
+      .accounts({
+        marketState: market.publicKey,
+        quoteAssetMint: mint,
+        marketAuthority: pda(Buffer.from("market_authority"), market.publicKey.toBuffer()),
+        feeVault: pda(Buffer.from("fee_vault"), market.publicKey.toBuffer()),
+        insuranceVault: insurance.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        authority,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([market, insurance])
+      .rpc();
+
+    const orderBook = web3.Keypair.generate();
+    const eventQueue = web3.Keypair.generate();
+    const createIxs = [];
+    for (const [kp, name] of [[orderBook, "orderBook"], [eventQueue, "eventQueue"]]) {
+      const space = pg.program.account[name].size;
+      createIxs.push(
+        web3.SystemProgram.createAccount({
+          fromPubkey: authority,
+          newAccountPubkey: kp.publicKey,
+          space,
+          lamports: await pg.connection.getMinimumBalanceForRentExemption(space),
+          programId: pg.program.programId,
+        })
+      );
+    }
+    await pg.program.methods
+      .initializeOrderBook()
+      .accounts({
+        authority,
+        marketState: market.publicKey,
+        orderBook: orderBook.publicKey,
+        eventQueue: eventQueue.publicKey,
+      })
+      .preInstructions(createIxs)
+      .signers([orderBook, eventQueue])
+      .rpc();
+
+    return {
+      mint,
+      market: market.publicKey,
+      feeVault: pda(Buffer.from("fee_vault"), market.publicKey.toBuffer()),
+      orderBook: orderBook.publicKey,
+      eventQueue: eventQueue.publicKey,
+    };
+  };
+
+  // A funded trader with `collateral` deposited into `fixture.market`.
+  const createTrader = async (fixture, collateral: number) => {
+    const kp = web3.Keypair.generate();
+    const user = kp.publicKey;
+    const fundTx = new web3.Transaction().add(
+      web3.SystemProgram.transfer({ fromPubkey: pg.wallet.publicKey, toPubkey: user, lamports: 0.2 * web3.LAMPORTS_PER_SOL })
+    );
+    await web3.sendAndConfirmTransaction(pg.connection, fundTx, [pg.wallet.keypair]);
+
+    const tokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, fixture.mint, user);
+    await mintTo(pg.connection, pg.wallet.keypair, fixture.mint, tokenAccount, pg.wallet.publicKey, collateral);
+
+    const trader = {
+      kp,
+      user,
+      tokenAccount,
+      userPosition: pda(Buffer.from("user_position"), user.toBuffer(), fixture.market.toBuffer()),
+      userStats: pda(Buffer.from("user_stats"), user.toBuffer()),
+      userVault: pda(Buffer.from("user_vault"), user.toBuffer(), fixture.market.toBuffer()),
+      userVaultAuthority: pda(Buffer.from("vault_authority"), user.toBuffer(), fixture.market.toBuffer()),
+    };
+
+    await pg.program.methods
+      .depositCollateral(new BN(collateral))
+      .accounts({
+        user,
+        marketState: fixture.market,
+        quoteAssetMint: fixture.mint,
+        userPosition: trader.userPosition,
+        userStats: trader.userStats,
+        userCollateralAccount: tokenAccount,
+        userVault: trader.userVault,
+        userVaultAuthority: trader.userVaultAuthority,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([kp])
+      .rpc();
+
+    return trader;
+  };
+
+  const openPosition = (fixture, trader, isLong: boolean, size: number, referrerRewards = null) =>
+    pg.program.methods
+      .openPosition(isLong, new BN(size))
+      .accounts({
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        marketState: fixture.market,
+        quoteAssetMint: fixture.mint,
+        userPosition: trader.userPosition,
+        collateralRegistry: null,
+        userCollateral: null,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        userVaultAuthority: trader.userVaultAuthority,
+        userVault: trader.userVault,
+        userStats: trader.userStats,
+        referrerRewards,
+        feeVault: fixture.feeVault,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([trader.kp])
+      .rpc();
+
+  // PDA of the next stop, bracket or TWAP order `trader` places in `fixture.market`.
+  const nextOrderPda = async (seed: string, fixture, trader) => {
+    const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+    return pda(
+      Buffer.from(seed),
+      trader.user.toBuffer(),
+      fixture.market.toBuffer(),
+      position.nextOrderSeq.toArrayLike(Buffer, "le", 8)
+    );
+  };
+
+  const placeStop = async (fixture, trader, params, triggerPrice: number, isTakeProfit: boolean) => {
+    const stopOrder = await nextOrderPda("stop_order", fixture, trader);
+    await pg.program.methods
+      .placeStopOrder(orderParams(params), new BN(triggerPrice), isTakeProfit)
+      .accounts({
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        userStats: null,
+        marketState: fixture.market,
+        userPosition: trader.userPosition,
+        stopOrder,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([trader.kp])
+      .rpc();
+    return stopOrder;
+  };
+
+  const placeTwap = async (fixture, trader, params, numSlices: number, intervalSeconds: number) => {
+    const twapOrder = await nextOrderPda("twap_order", fixture, trader);
+    await pg.program.methods
+      .placeTwapOrder(orderParams(params), numSlices, new BN(intervalSeconds))
+      .accounts({
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        userStats: null,
+        marketState: fixture.market,
+        userPosition: trader.userPosition,
+        twapOrder,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([trader.kp])
+      .rpc();
+    return twapOrder;
+  };
+
+  const placeBracket = async (fixture, trader, params, stopLossPrice: BN, takeProfitPrice: BN) => {
+    const bracketOrder = await nextOrderPda("bracket_order", fixture, trader);
+    await pg.program.methods
+      .placeBracketOrder(orderParams(params), stopLossPrice, takeProfitPrice)
+      .accounts({
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        userStats: null,
+        marketState: fixture.market,
+        userPosition: trader.userPosition,
+        bracketOrder,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([trader.kp])
+      .rpc();
+    return bracketOrder;
+  };
+
+  const placeLimit = (fixture, trader, params) =>
+    pg.program.methods
+      .placeLimitOrder(orderParams(params))
+      .accounts({
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        userStats: null,
+        marketState: fixture.market,
+        userPosition: trader.userPosition,
+        orderBook: fixture.orderBook,
+        eventQueue: fixture.eventQueue,
+      })
+      .signers([trader.kp])
+      .rpc();
+
   it("Initializes Market", async () => {
     const initialFundingRate = new BN(0);
     const baseAssetSymbol = "SOL";
 
+    const [marketAuthority] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("market_authority"), marketStateKp.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [feeVault] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("fee_vault"), marketStateKp.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
     const txHash = await pg.program.methods
-      .initializeMarket(initialFundingRate, baseAssetSymbol, quoteAssetMint)
+      .initializeMarket(initialFundingRate, baseAssetSymbol, null)
       .accounts({
         marketState: marketStateKp.publicKey,
-        feeVault: feeVaultKp.publicKey,
+        quoteAssetMint,
+        marketAuthority,
+        feeVault,
         insuranceVault: insuranceVaultKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
         authority: pg.wallet.publicKey,
         systemProgram: web3.SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID, 
       })
-      .signers([marketStateKp, feeVaultKp, insuranceVaultKp])
+      .signers([marketStateKp, insuranceVaultKp])
       .rpc();
 
     console.log(`InitializeMarket txHash: ${txHash}`);
@@ -44,6 +327,153 @@ describe("Perpetual Program Tests", () => {
     assert.strictEqual(marketState.authority.toBase58(), pg.wallet.publicKey.toBase58());
     assert.strictEqual(marketState.baseAssetSymbol, "SOL");
     assert.strictEqual(marketState.quoteAssetMint.toBase58(), quoteAssetMint.toBase58());
+    assert.strictEqual(marketState.feeVault.toBase58(), feeVault.toBase58());
+    assert(marketState.takerFeeBps.eq(new BN(5)), "Default taker fee mismatch");
+    assert(marketState.makerFeeBps.eq(new BN(2)), "Default maker fee mismatch");
+    assert.strictEqual(marketState.oracle.toBase58(), SOL_USD_FEED.toBase58());
+  });
+
+  it("Sets trading fees", async () => {
+    await pg.program.methods
+      .setTradingFees(new BN(7), new BN(1))
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+
+    const marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert(marketState.takerFeeBps.eq(new BN(7)), "Taker fee not updated");
+    assert(marketState.makerFeeBps.eq(new BN(1)), "Maker fee not updated");
+  });
+
+  it("Sets a negative maker fee as a rebate", async () => {
+    await pg.program.methods
+      .setTradingFees(new BN(7), new BN(-2))
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+
+    let marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert(marketState.makerFeeBps.eq(new BN(-2)), "Maker rebate not set");
+
+    // A rebate larger than the taker fee is rejected
+    try {
+      await pg.program.methods
+        .setTradingFees(new BN(1), new BN(-2))
+        .accounts({
+          authority: pg.wallet.publicKey,
+          marketState: marketStateKp.publicKey,
+        })
+        .rpc();
+      assert.fail("Rebate above the taker fee should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidFee");
+    }
+
+    await pg.program.methods
+      .setTradingFees(new BN(7), new BN(1))
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+    marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert(marketState.makerFeeBps.eq(new BN(1)), "Maker fee not restored");
+  });
+
+  it("Sets volume-based fee tiers", async () => {
+    const tiers = [
+      { minVolume: new BN(1_000_000), takerFeeBps: new BN(4), makerFeeBps: new BN(1) },
+      { minVolume: new BN(10_000_000), takerFeeBps: new BN(3), makerFeeBps: new BN(0) },
+    ];
+    await pg.program.methods
+      .setFeeTiers(tiers)
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+
+    const marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert.strictEqual(marketState.feeTierCount, 2);
+    assert(marketState.feeTiers[1].takerFeeBps.eq(new BN(3)), "Tier taker fee mismatch");
+  });
+
+  it("Sets referral params", async () => {
+    await pg.program.methods
+      .setReferralParams(new BN(2000), new BN(500))
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+
+    const marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert(marketState.referrerFeeShareBps.eq(new BN(2000)), "Referrer share mismatch");
+    assert(marketState.refereeDiscountBps.eq(new BN(500)), "Referee discount mismatch");
+  });
+
+  it("Initializes the collateral registry", async () => {
+    const [collateralRegistry] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_registry"), marketStateKp.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .initializeCollateralRegistry()
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+        quoteAssetMint,
+        collateralRegistry,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const registry = await pg.program.account.collateralRegistry.fetch(collateralRegistry);
+    assert.strictEqual(registry.market.toBase58(), marketStateKp.publicKey.toBase58());
+    assert.strictEqual(registry.assetCount, 0);
+  });
+
+  const marginAccountPdas = (subaccountId: number) => {
+    const id = Buffer.alloc(2);
+    id.writeUInt16LE(subaccountId);
+    const [marginAccount] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("margin_account"), pg.wallet.publicKey.toBuffer(), id],
+      pg.program.programId
+    );
+    const [marginVault] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_vault"), pg.wallet.publicKey.toBuffer(), marginAccount.toBuffer()],
+      pg.program.programId
+    );
+    return { marginAccount, marginVault };
+  };
+
+  it("Initializes isolated cross-margin subaccounts", async () => {
+    for (const subaccountId of [0, 1]) {
+      const { marginAccount, marginVault } = marginAccountPdas(subaccountId);
+
+      await pg.program.methods
+        .initializeMarginAccount(subaccountId)
+        .accounts({
+          owner: pg.wallet.publicKey,
+          quoteAssetMint,
+          marginAccount,
+          marginVault,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      const account = await pg.program.account.marginAccount.fetch(marginAccount);
+      assert.strictEqual(account.owner.toBase58(), pg.wallet.publicKey.toBase58());
+      assert.strictEqual(account.subaccountId, subaccountId);
+      assert.strictEqual(account.collateral.toNumber(), 0);
+      assert.ok(account.positions.every((p) => p.size.toNumber() === 0));
+    }
   });
 
   it("Deposits Collateral", async () => {
@@ -81,8 +511,11 @@ describe("Perpetual Program Tests", () => {
       .openPosition(isLong, positionSize)
       .accounts({
         user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
         marketState: marketStateKp.publicKey,
         userPosition: userPositionKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
         systemProgram: web3.SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID, // Fix applied here
       })
@@ -102,9 +535,11 @@ describe("Perpetual Program Tests", () => {
       .closePosition()
       .accounts({
         user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
         marketState: marketStateKp.publicKey,
         userPosition: userPositionKp.publicKey,
-        oraclePriceFeedAccount: marketStateKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
       })
       .rpc();
 
@@ -122,7 +557,7 @@ describe("Perpetual Program Tests", () => {
       .accounts({
         authority: pg.wallet.publicKey,
         marketState: marketStateKp.publicKey,
-        oraclePriceFeedAccount: marketStateKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
       })
       .rpc();
 
@@ -138,8 +573,11 @@ describe("Perpetual Program Tests", () => {
       .openPosition(true, new BN(1))
       .accounts({
         user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
         marketState: marketStateKp.publicKey,
         userPosition: userPositionKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
         systemProgram: web3.SystemProgram.programId,
         tokenProgram: TOKEN_PROGRAM_ID, 
       })
@@ -152,7 +590,7 @@ describe("Perpetual Program Tests", () => {
         liquidator: pg.wallet.publicKey,
         marketState: marketStateKp.publicKey,
         userPosition: userPositionKp.publicKey,
-        oraclePriceFeedAccount: marketStateKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
       })
       .rpc();
 
@@ -162,4 +600,1847 @@ describe("Perpetual Program Tests", () => {
     const userPosition = await pg.program.account.userPosition.fetch(userPositionKp.publicKey);
     console.log("UserPosition after liquidation:", userPosition);
   });
+
+  it("Initializes and repegs the vAMM", async () => {
+    const sqrtK = new BN(1_000_000);
+    const peg = new BN(1_000_000); // price 1000 with PEG_PRECISION = 1000
+
+    const txHash = await pg.program.methods
+      .initializeVamm(sqrtK, peg)
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+      })
+      .rpc();
+
+    console.log(`InitializeVamm txHash: ${txHash}`);
+    await pg.connection.confirmTransaction(txHash);
+
+    const { feeVault } = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    const repegTx = await pg.program.methods
+      .repegVamm(new BN(1_100_000))
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+        feeVault,
+      })
+      .rpc();
+    await pg.connection.confirmTransaction(repegTx);
+
+    const marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    console.log("MarketState after repeg:", marketState);
+    assert.strictEqual(marketState.vammEnabled, true);
+    assert(marketState.baseAssetReserve.eq(sqrtK), "Base reserve mismatch");
+    assert(marketState.pegMultiplier.eq(new BN(1_100_000)), "Peg not updated");
+    // No net position against the vAMM, so the repeg costs nothing
+    assert(marketState.vammCostReserved.eq(new BN(0)), "Unexpected repeg cost");
+  });
+
+  it("Rejects reallocating an account already on the current layout", async () => {
+    try {
+      await pg.program.methods
+        .reallocAccount()
+        .accounts({
+          payer: pg.wallet.publicKey,
+          account: marketStateKp.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("Reallocating a current-layout market should fail");
+    } catch (err) {
+      assert.include(err.toString(), "AccountAlreadyMigrated");
+    }
+  });
+
+  let orderBookKp, eventQueueKp;
+
+  it("Places and cancels a limit order", async () => {
+    orderBookKp = web3.Keypair.generate();
+    eventQueueKp = web3.Keypair.generate();
+
+    // Order book and event queue are too large for init, so pre-allocate them.
+    const createIxs = [];
+    for (const [kp, name] of [[orderBookKp, "orderBook"], [eventQueueKp, "eventQueue"]]) {
+      const space = pg.program.account[name].size;
+      createIxs.push(
+        web3.SystemProgram.createAccount({
+          fromPubkey: pg.wallet.publicKey,
+          newAccountPubkey: kp.publicKey,
+          space,
+          lamports: await pg.connection.getMinimumBalanceForRentExemption(space),
+          programId: pg.program.programId,
+        })
+      );
+    }
+
+    await pg.program.methods
+      .initializeOrderBook()
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: marketStateKp.publicKey,
+        orderBook: orderBookKp.publicKey,
+        eventQueue: eventQueueKp.publicKey,
+      })
+      .preInstructions(createIxs)
+      .signers([orderBookKp, eventQueueKp])
+      .rpc();
+
+    await pg.program.methods
+      .placeLimitOrder({
+        isLong: true,
+        price: new BN(900),
+        size: new BN(1),
+        timeInForce: { goodTillCancel: {} },
+        expiryTs: new BN(0),
+        clientOrderId: new BN(0),
+        selfTradeBehavior: { cancelMaker: {} },
+      })
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        userStats: null,
+        marketState: marketStateKp.publicKey,
+        userPosition: userPositionKp.publicKey,
+        orderBook: orderBookKp.publicKey,
+        eventQueue: eventQueueKp.publicKey,
+      })
+      .rpc();
+
+    let orderBook = await pg.program.account.orderBook.fetch(orderBookKp.publicKey);
+    assert.strictEqual(orderBook.bidCount.toNumber(), 1, "Order should rest on the book");
+    const orderId = orderBook.bids[0].orderId;
+
+    await pg.program.methods
+      .cancelOrder(orderId)
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        userStats: null,
+        marketState: marketStateKp.publicKey,
+        orderBook: orderBookKp.publicKey,
+      })
+      .rpc();
+
+    orderBook = await pg.program.account.orderBook.fetch(orderBookKp.publicKey);
+    assert.strictEqual(orderBook.bidCount.toNumber(), 0, "Order should be cancelled");
+  });
+
+  it("Rejects replacing a market's order book", async () => {
+    const newBookKp = web3.Keypair.generate();
+    const newQueueKp = web3.Keypair.generate();
+    const createIxs = [];
+    for (const [kp, name] of [[newBookKp, "orderBook"], [newQueueKp, "eventQueue"]]) {
+      const space = pg.program.account[name].size;
+      createIxs.push(
+        web3.SystemProgram.createAccount({
+          fromPubkey: pg.wallet.publicKey,
+          newAccountPubkey: kp.publicKey,
+          space,
+          lamports: await pg.connection.getMinimumBalanceForRentExemption(space),
+          programId: pg.program.programId,
+        })
+      );
+    }
+
+    try {
+      await pg.program.methods
+        .initializeOrderBook()
+        .accounts({
+          authority: pg.wallet.publicKey,
+          marketState: marketStateKp.publicKey,
+          orderBook: newBookKp.publicKey,
+          eventQueue: newQueueKp.publicKey,
+        })
+        .preInstructions(createIxs)
+        .signers([newBookKp, newQueueKp])
+        .rpc();
+      assert.fail("A second order book should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "OrderBookAlreadyInitialized");
+    }
+
+    const marketState = await pg.program.account.marketState.fetch(marketStateKp.publicKey);
+    assert.strictEqual(marketState.orderBook.toBase58(), orderBookKp.publicKey.toBase58());
+  });
+
+  it("Cancels orders by client order id and mass cancel", async () => {
+    for (const clientOrderId of [7, 8]) {
+      await pg.program.methods
+        .placeLimitOrder({
+          isLong: false,
+          price: new BN(1100 + clientOrderId),
+          size: new BN(1),
+          timeInForce: { goodTillCancel: {} },
+          expiryTs: new BN(0),
+          clientOrderId: new BN(clientOrderId),
+          selfTradeBehavior: { cancelMaker: {} },
+        })
+        .accounts({
+          user: pg.wallet.publicKey,
+          authority: pg.wallet.publicKey,
+          sessionKey: null,
+          userStats: null,
+          marketState: marketStateKp.publicKey,
+          userPosition: userPositionKp.publicKey,
+          orderBook: orderBookKp.publicKey,
+          eventQueue: eventQueueKp.publicKey,
+        })
+        .rpc();
+    }
+
+    const cancelAccounts = {
+      user: pg.wallet.publicKey,
+      authority: pg.wallet.publicKey,
+      sessionKey: null,
+      userStats: null,
+      marketState: marketStateKp.publicKey,
+      orderBook: orderBookKp.publicKey,
+    };
+
+    await pg.program.methods.cancelOrderByClientId(new BN(7)).accounts(cancelAccounts).rpc();
+    let orderBook = await pg.program.account.orderBook.fetch(orderBookKp.publicKey);
+    assert.strictEqual(orderBook.askCount.toNumber(), 1, "Client id 7 should be cancelled");
+    assert(orderBook.asks[0].clientOrderId.eq(new BN(8)), "Client id 8 should remain");
+
+    await pg.program.methods.cancelAllOrders(null).accounts(cancelAccounts).rpc();
+    orderBook = await pg.program.account.orderBook.fetch(orderBookKp.publicKey);
+    assert.strictEqual(orderBook.askCount.toNumber(), 0, "All orders should be cancelled");
+  });
+
+  describe("Client order ids", () => {
+    let fixture, maker, trader;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      maker = await createTrader(fixture, 1_000_000);
+      trader = await createTrader(fixture, POSITION_COLLATERAL);
+      await openPosition(fixture, trader, true, 2);
+    });
+
+    it("Rejects retrying a client order id after the order filled", async () => {
+      await placeLimit(fixture, maker, { isLong: false, price: new BN(1000) });
+      await placeLimit(fixture, trader, {
+        price: new BN(1000),
+        clientOrderId: new BN(500),
+        timeInForce: { immediateOrCancel: {} },
+      });
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      assert.strictEqual(orderBook.askCount.toNumber(), 0, "The order should have filled");
+
+      try {
+        await placeLimit(fixture, trader, {
+          price: new BN(1000),
+          clientOrderId: new BN(500),
+          timeInForce: { immediateOrCancel: {} },
+        });
+        assert.fail("Retried client order id should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "DuplicateClientOrderId");
+      }
+    });
+
+    it("Rejects a conditional order reusing a client order id", async () => {
+      await placeStop(fixture, trader, { isLong: false, clientOrderId: new BN(501) }, 900, false);
+      try {
+        await placeTwap(fixture, trader, { size: new BN(2), clientOrderId: new BN(501) }, 2, 60);
+        assert.fail("Client order id already used by the stop should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "DuplicateClientOrderId");
+      }
+    });
+
+    it("Cancels stop and TWAP orders by client order id and mass cancel", async () => {
+      const stopA = await placeStop(fixture, trader, { isLong: false, clientOrderId: new BN(11) }, 900, false);
+      const twap = await placeTwap(fixture, trader, { size: new BN(2), clientOrderId: new BN(12) }, 2, 60);
+      const stopB = await placeStop(fixture, trader, { isLong: false, clientOrderId: new BN(13) }, 1100, true);
+
+      const cancelAccounts = {
+        user: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        userStats: null,
+        marketState: fixture.market,
+      };
+      const remaining = (orders) => orders.map((pubkey) => ({ pubkey, isWritable: true, isSigner: false }));
+
+      await pg.program.methods
+        .cancelConditionalOrderByClientId(new BN(12))
+        .accounts(cancelAccounts)
+        .remainingAccounts(remaining([stopA, twap, stopB]))
+        .signers([trader.kp])
+        .rpc();
+
+      assert.strictEqual(await pg.connection.getAccountInfo(twap), null, "TWAP 12 should be closed");
+      assert.notStrictEqual(await pg.connection.getAccountInfo(stopA), null, "Stop 11 should remain");
+      assert.notStrictEqual(await pg.connection.getAccountInfo(stopB), null, "Stop 13 should remain");
+
+      await pg.program.methods
+        .cancelAllConditionalOrders()
+        .accounts(cancelAccounts)
+        .remainingAccounts(remaining([stopA, stopB]))
+        .signers([trader.kp])
+        .rpc();
+
+      assert.strictEqual(await pg.connection.getAccountInfo(stopA), null, "Stop 11 should be closed");
+      assert.strictEqual(await pg.connection.getAccountInfo(stopB), null, "Stop 13 should be closed");
+    });
+  });
+
+  describe("Collateral asset valuation", () => {
+    it("Values assets with different oracle exponents and decimals in quote units", async () => {
+      const fixture = await createTradingMarket();
+      await pg.program.methods
+        .setTradingFees(new BN(0), new BN(0))
+        .accounts({ authority: pg.wallet.publicKey, marketState: fixture.market })
+        .rpc();
+
+      const collateralRegistry = pda(Buffer.from("collateral_registry"), fixture.market.toBuffer());
+      await pg.program.methods
+        .initializeCollateralRegistry()
+        .accounts({
+          authority: pg.wallet.publicKey,
+          marketState: fixture.market,
+          quoteAssetMint: fixture.mint,
+          collateralRegistry,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+      const registry = await pg.program.account.collateralRegistry.fetch(collateralRegistry);
+      assert.strictEqual(registry.quoteDecimals, 6);
+
+      // A thousand whole tokens of each asset, at full weight.
+      const otherFeed = await findLiveFeedWithOtherExpo(await readOracleExpo(SOL_USD_FEED));
+      const assets = [
+        { decimals: 9, feed: SOL_USD_FEED },
+        { decimals: 5, feed: otherFeed },
+      ];
+      const trader = await createTrader(fixture, 1);
+      const userCollateral = pda(Buffer.from("user_collateral"), trader.user.toBuffer(), fixture.market.toBuffer());
+
+      for (const asset of assets) {
+        const mint = await createMint(pg.connection, pg.wallet.keypair, pg.wallet.publicKey, null, asset.decimals);
+        const whole = new BN(10).pow(new BN(asset.decimals));
+        const amount = whole.muln(1000);
+        await pg.program.methods
+          .configureCollateralAsset(new BN(10_000), new BN(10_000), amount, null)
+          .accounts({
+            authority: pg.wallet.publicKey,
+            marketState: fixture.market,
+            collateralRegistry,
+            mint,
+            oraclePriceFeedAccount: asset.feed,
+          })
+          .rpc();
+
+        const tokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, mint, trader.user);
+        await mintTo(pg.connection, pg.wallet.keypair, mint, tokenAccount, pg.wallet.publicKey, BigInt(amount.toString()));
+        await pg.program.methods
+          .depositCollateralAsset(amount)
+          .accounts({
+            user: trader.user,
+            marketState: fixture.market,
+            collateralRegistry,
+            mint,
+            userPosition: trader.userPosition,
+            userCollateral,
+            userTokenAccount: tokenAccount,
+            userVaultAuthority: trader.userVaultAuthority,
+            collateralVault: pda(
+              Buffer.from("collateral_vault"),
+              trader.user.toBuffer(),
+              fixture.market.toBuffer(),
+              mint.toBuffer()
+            ),
+            systemProgram: web3.SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([trader.kp])
+          .rpc();
+      }
+
+      // Each whole token is worth price * 10^(quote decimals + expo) native quote units.
+      let expectedValue = 0;
+      for (const asset of assets) {
+        const price = (await readOraclePrice(asset.feed)).toNumber();
+        expectedValue += 1000 * price * 10 ** (6 + (await readOracleExpo(asset.feed)));
+      }
+
+      // Positions fill at the market's oracle price with up to 10x leverage on collateral
+      // plus asset value; bracket the expected value by 10% to allow for price moves.
+      const open = (size: number) =>
+        pg.program.methods
+          .openPosition(true, new BN(size))
+          .accounts({
+            user: trader.user,
+            authority: trader.user,
+            sessionKey: null,
+            marketState: fixture.market,
+            quoteAssetMint: fixture.mint,
+            userPosition: trader.userPosition,
+            collateralRegistry,
+            userCollateral,
+            oraclePriceFeedAccount: SOL_USD_FEED,
+            userVaultAuthority: trader.userVaultAuthority,
+            userVault: trader.userVault,
+            userStats: trader.userStats,
+            referrerRewards: null,
+            feeVault: fixture.feeVault,
+            systemProgram: web3.SystemProgram.programId,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .remainingAccounts(assets.map(({ feed }) => ({ pubkey: feed, isWritable: false, isSigner: false })))
+          .signers([trader.kp])
+          .rpc();
+      const maxSize = (expectedValue * 10) / (await readOraclePrice(SOL_USD_FEED)).toNumber();
+
+      try {
+        await open(Math.ceil(maxSize * 1.1));
+        assert.fail("A position above the assets' value should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "InsufficientMargin");
+      }
+
+      const size = Math.floor(maxSize * 0.9);
+      await open(size);
+      const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+      assert(position.size.eq(new BN(size)), "A position within the assets' value should open");
+    });
+  });
+
+  describe("Token-2022 quote mints", () => {
+    // A 6-decimal Token-2022 mint with the given extensions, initialized by `extensionIxs`.
+    const createToken2022Mint = async (extensions, extensionIxs: (mint: web3.PublicKey) => web3.TransactionInstruction[]) => {
+      const mint = web3.Keypair.generate();
+      const space = getMintLen(extensions);
+      const tx = new web3.Transaction().add(
+        web3.SystemProgram.createAccount({
+          fromPubkey: pg.wallet.publicKey,
+          newAccountPubkey: mint.publicKey,
+          space,
+          lamports: await pg.connection.getMinimumBalanceForRentExemption(space),
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        ...extensionIxs(mint.publicKey),
+        createInitializeMintInstruction(mint.publicKey, 6, pg.wallet.publicKey, null, TOKEN_2022_PROGRAM_ID)
+      );
+      await web3.sendAndConfirmTransaction(pg.connection, tx, [pg.wallet.keypair, mint]);
+      return mint.publicKey;
+    };
+
+    const initializeMarket = async (mint: web3.PublicKey, tokenProgram: web3.PublicKey, quoteFreezeAuthority = null) => {
+      const market = web3.Keypair.generate();
+      const insurance = web3.Keypair.generate();
+      await pg.program.methods
+        .initializeMarket(new BN(0), "SOL", quoteFreezeAuthority)
+        .accounts({
+          marketState: market.publicKey,
+          quoteAssetMint: mint,
+          marketAuthority: pda(Buffer.from("market_authority"), market.publicKey.toBuffer()),
+          feeVault: pda(Buffer.from("fee_vault"), market.publicKey.toBuffer()),
+          insuranceVault: insurance.publicKey,
+          oraclePriceFeedAccount: SOL_USD_FEED,
+          authority: pg.wallet.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram,
+        })
+        .signers([market, insurance])
+        .rpc();
+      return market.publicKey;
+    };
+
+    it("Credits a deposit with the amount received after the transfer fee", async () => {
+      // 1% transfer fee
+      const mint = await createToken2022Mint([ExtensionType.TransferFeeConfig], (mint) => [
+        createInitializeTransferFeeConfigInstruction(
+          mint,
+          pg.wallet.publicKey,
+          pg.wallet.publicKey,
+          100,
+          BigInt(1_000_000_000),
+          TOKEN_2022_PROGRAM_ID
+        ),
+      ]);
+      const market = await initializeMarket(mint, TOKEN_2022_PROGRAM_ID);
+
+      const user = pg.wallet.publicKey;
+      const tokenAccount = await createAssociatedTokenAccount(
+        pg.connection, pg.wallet.keypair, mint, user, undefined, TOKEN_2022_PROGRAM_ID
+      );
+      await mintTo(pg.connection, pg.wallet.keypair, mint, tokenAccount, user, 1_000_000, [], undefined, TOKEN_2022_PROGRAM_ID);
+
+      const userPosition = pda(Buffer.from("user_position"), user.toBuffer(), market.toBuffer());
+      const userVault = pda(Buffer.from("user_vault"), user.toBuffer(), market.toBuffer());
+      await pg.program.methods
+        .depositCollateral(new BN(1_000_000))
+        .accounts({
+          user,
+          marketState: market,
+          quoteAssetMint: mint,
+          userPosition,
+          userStats: pda(Buffer.from("user_stats"), user.toBuffer()),
+          userCollateralAccount: tokenAccount,
+          userVault,
+          userVaultAuthority: pda(Buffer.from("vault_authority"), user.toBuffer(), market.toBuffer()),
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .rpc();
+
+      const position = await pg.program.account.userPosition.fetch(userPosition);
+      const vault = await getAccount(pg.connection, userVault, undefined, TOKEN_2022_PROGRAM_ID);
+      assert(position.collateral.eqn(990_000), "Only the amount after the fee should be credited");
+      assert.strictEqual(Number(vault.amount), 990_000);
+    });
+
+    it("Rejects a mint with a permanent delegate", async () => {
+      const mint = await createToken2022Mint([ExtensionType.PermanentDelegate], (mint) => [
+        createInitializePermanentDelegateInstruction(mint, pg.wallet.publicKey, TOKEN_2022_PROGRAM_ID),
+      ]);
+      try {
+        await initializeMarket(mint, TOKEN_2022_PROGRAM_ID);
+        assert.fail("A permanent delegate could move vault funds");
+      } catch (err) {
+        assert.include(err.toString(), "UnsupportedMint");
+      }
+    });
+
+    it("Rejects a freeze authority unless it is acknowledged", async () => {
+      const freezeAuthority = pg.wallet.publicKey;
+      const mint = await createMint(pg.connection, pg.wallet.keypair, pg.wallet.publicKey, freezeAuthority, 6);
+      try {
+        await initializeMarket(mint, TOKEN_PROGRAM_ID);
+        assert.fail("An unacknowledged freeze authority should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "UnsupportedMint");
+      }
+
+      const market = await initializeMarket(mint, TOKEN_PROGRAM_ID, freezeAuthority);
+      const marketState = await pg.program.account.marketState.fetch(market);
+      assert.strictEqual(marketState.quoteAssetMint.toBase58(), mint.toBase58());
+    });
+  });
+
+  describe("Native SOL collateral", () => {
+    it("Round-trips deposit_sol and withdraw_sol as plain lamports", async () => {
+      const NATIVE_MINT = new web3.PublicKey("So11111111111111111111111111111111111111112");
+      const fixture = await createTradingMarket();
+      const collateralRegistry = pda(Buffer.from("collateral_registry"), fixture.market.toBuffer());
+      await pg.program.methods
+        .initializeCollateralRegistry()
+        .accounts({
+          authority: pg.wallet.publicKey,
+          marketState: fixture.market,
+          quoteAssetMint: fixture.mint,
+          collateralRegistry,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+      await pg.program.methods
+        .configureCollateralAsset(new BN(8_000), new BN(9_000), new BN(web3.LAMPORTS_PER_SOL), null)
+        .accounts({
+          authority: pg.wallet.publicKey,
+          marketState: fixture.market,
+          collateralRegistry,
+          mint: NATIVE_MINT,
+          oraclePriceFeedAccount: SOL_USD_FEED,
+        })
+        .rpc();
+
+      const trader = await createTrader(fixture, 1_000_000);
+      const userCollateral = pda(Buffer.from("user_collateral"), trader.user.toBuffer(), fixture.market.toBuffer());
+      const collateralVault = pda(
+        Buffer.from("collateral_vault"),
+        trader.user.toBuffer(),
+        fixture.market.toBuffer(),
+        NATIVE_MINT.toBuffer()
+      );
+      const unwrapAccount = pda(Buffer.from("sol_unwrap"), trader.user.toBuffer(), fixture.market.toBuffer());
+      const amount = 0.05 * web3.LAMPORTS_PER_SOL;
+      const accounts = {
+        user: trader.user,
+        marketState: fixture.market,
+        collateralRegistry,
+        nativeMint: NATIVE_MINT,
+        userPosition: trader.userPosition,
+        userCollateral,
+        userVaultAuthority: trader.userVaultAuthority,
+        collateralVault,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      };
+
+      await pg.program.methods.depositSol(new BN(amount)).accounts(accounts).signers([trader.kp]).rpc();
+
+      const vault = await getAccount(pg.connection, collateralVault);
+      let balances = (await pg.program.account.userCollateral.fetch(userCollateral)).balances;
+      assert.strictEqual(Number(vault.amount), amount, "Vault should hold the wrapped SOL");
+      assert(balances[0].eqn(amount), "Deposit should be credited");
+
+      // The wallet pays transaction fees, so the trader's lamports move by exactly the amount.
+      const lamportsBefore = await pg.connection.getBalance(trader.user);
+      await pg.program.methods
+        .withdrawSol(new BN(amount))
+        .accounts({ ...accounts, oraclePriceFeedAccount: SOL_USD_FEED, unwrapAccount })
+        .remainingAccounts([{ pubkey: SOL_USD_FEED, isWritable: false, isSigner: false }])
+        .signers([trader.kp])
+        .rpc();
+      const lamportsAfter = await pg.connection.getBalance(trader.user);
+
+      assert.strictEqual(lamportsAfter - lamportsBefore, amount, "Withdrawal should arrive as lamports");
+      assert.strictEqual(await pg.connection.getAccountInfo(unwrapAccount), null, "sol_unwrap should be closed");
+      assert.strictEqual(Number((await getAccount(pg.connection, collateralVault)).amount), 0);
+      balances = (await pg.program.account.userCollateral.fetch(userCollateral)).balances;
+      assert(balances[0].eqn(0), "Withdrawal should be debited");
+    });
+  });
+
+  describe("Self-trade prevention", () => {
+    let fixture, trader, queueBefore;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      trader = await createTrader(fixture, 1_000_000);
+    });
+
+    beforeEach(async () => {
+      // Every case starts from the trader's own ask of size 2 at 1000.
+      await pg.program.methods
+        .cancelAllOrders(null)
+        .accounts({
+          user: trader.user,
+          authority: trader.user,
+          sessionKey: null,
+          userStats: null,
+          marketState: fixture.market,
+          orderBook: fixture.orderBook,
+        })
+        .signers([trader.kp])
+        .rpc();
+      await placeLimit(fixture, trader, { isLong: false, price: new BN(1000), size: new BN(2) });
+      queueBefore = (await pg.program.account.eventQueue.fetch(fixture.eventQueue)).count.toNumber();
+    });
+
+    const selfCross = async (size: number, selfTradeBehavior) => {
+      await placeLimit(fixture, trader, { price: new BN(1000), size: new BN(size), selfTradeBehavior });
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      const queue = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      assert.strictEqual(queue.count.toNumber(), queueBefore, "A self-trade must never fill");
+      return orderBook;
+    };
+
+    it("Cancels the taker and keeps the maker with CancelTaker", async () => {
+      const orderBook = await selfCross(1, { cancelTaker: {} });
+      assert.strictEqual(orderBook.bidCount.toNumber(), 0, "The taker should not rest");
+      assert.strictEqual(orderBook.askCount.toNumber(), 1);
+      assert(orderBook.asks[0].size.eqn(2), "The maker should be untouched");
+    });
+
+    it("Cancels the maker and rests the taker with CancelMaker", async () => {
+      const orderBook = await selfCross(1, { cancelMaker: {} });
+      assert.strictEqual(orderBook.askCount.toNumber(), 0, "The maker should be cancelled");
+      assert.strictEqual(orderBook.bidCount.toNumber(), 1, "The taker should rest");
+      assert(orderBook.bids[0].size.eqn(1));
+    });
+
+    it("Cancels both sides with CancelBoth", async () => {
+      const orderBook = await selfCross(1, { cancelBoth: {} });
+      assert.strictEqual(orderBook.askCount.toNumber(), 0, "The maker should be cancelled");
+      assert.strictEqual(orderBook.bidCount.toNumber(), 0, "The taker should be cancelled");
+    });
+
+    it("Shrinks the larger side and cancels the smaller with DecrementAndCancel", async () => {
+      const orderBook = await selfCross(1, { decrementAndCancel: {} });
+      assert.strictEqual(orderBook.bidCount.toNumber(), 0, "The smaller taker should be used up");
+      assert.strictEqual(orderBook.askCount.toNumber(), 1);
+      assert(orderBook.asks[0].size.eqn(1), "The maker should shrink by the overlap");
+    });
+
+    it("Cancels the smaller maker and rests the taker's remainder with DecrementAndCancel", async () => {
+      const orderBook = await selfCross(3, { decrementAndCancel: {} });
+      assert.strictEqual(orderBook.askCount.toNumber(), 0, "The smaller maker should be cancelled");
+      assert.strictEqual(orderBook.bidCount.toNumber(), 1);
+      assert(orderBook.bids[0].size.eqn(1), "The taker should rest with what exceeds the overlap");
+    });
+  });
+
+  describe("Trigger keepers", () => {
+    const KEEPER_FEE = 2_500;
+    let fixture, trader, keeper, keeperTokenAccount;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      const admin = { authority: pg.wallet.publicKey, marketState: fixture.market };
+      // Oracle prices are in the feed's exponent, so keep trading fees out of the vault math.
+      await pg.program.methods.setTradingFees(new BN(0), new BN(0)).accounts(admin).rpc();
+      await pg.program.methods.setKeeperFee(new BN(KEEPER_FEE)).accounts(admin).rpc();
+
+      trader = await createTrader(fixture, POSITION_COLLATERAL);
+      await openPosition(fixture, trader, true, 1);
+
+      keeper = web3.Keypair.generate();
+      keeperTokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, fixture.mint, keeper.publicKey);
+    });
+
+    const triggerStop = (stopOrder, oracle = SOL_USD_FEED) =>
+      pg.program.methods
+        .triggerStopOrder()
+        .accounts({
+          user: trader.user,
+          marketState: fixture.market,
+          quoteAssetMint: fixture.mint,
+          userPosition: trader.userPosition,
+          stopOrder,
+          rentReceiver: trader.user,
+          oraclePriceFeedAccount: oracle,
+          keeper: keeper.publicKey,
+          keeperTokenAccount,
+          userVaultAuthority: trader.userVaultAuthority,
+          userVault: trader.userVault,
+          userStats: trader.userStats,
+          referrerRewards: null,
+          feeVault: fixture.feeVault,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc();
+
+    it("Rejects a trigger priced from a feed other than the market's oracle", async () => {
+      // A stop-loss above the current price is immediately triggerable.
+      const price = await readOraclePrice(SOL_USD_FEED);
+      const stopOrder = await placeStop(fixture, trader, { isLong: false }, price.muln(2).toNumber(), false);
+
+      try {
+        await triggerStop(stopOrder, fixture.market);
+        assert.fail("A foreign price feed should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "InvalidOracle");
+      }
+
+      await pg.program.methods
+        .cancelStopOrder()
+        .accounts({ user: trader.user, authority: trader.user, userStats: null, sessionKey: null, stopOrder, rentReceiver: trader.user })
+        .signers([trader.kp])
+        .rpc();
+    });
+
+    it("Lets any keeper trigger a stop and pays it the keeper fee from the position's collateral", async () => {
+      const price = await readOraclePrice(SOL_USD_FEED);
+      const stopOrder = await placeStop(fixture, trader, { isLong: false }, price.muln(2).toNumber(), false);
+
+      const vaultBefore = await getAccount(pg.connection, trader.userVault);
+      await triggerStop(stopOrder);
+
+      const keeperAccount = await getAccount(pg.connection, keeperTokenAccount);
+      const vaultAfter = await getAccount(pg.connection, trader.userVault);
+      const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+
+      assert.strictEqual(Number(keeperAccount.amount), KEEPER_FEE, "Keeper should receive the keeper fee");
+      assert.strictEqual(
+        Number(vaultBefore.amount) - Number(vaultAfter.amount),
+        KEEPER_FEE,
+        "The keeper fee should leave the trader's vault"
+      );
+      assert.strictEqual(position.size.toNumber(), 0, "The stop should close the position");
+      assert.strictEqual(await pg.connection.getAccountInfo(stopOrder), null, "Stop order should be closed");
+    });
+  });
+
+  describe("Bracket sizing", () => {
+    let fixture, trader, keeper, keeperTokenAccount;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      // Exits fill at the oracle price, so keep trading fees out of the vault math.
+      await pg.program.methods
+        .setTradingFees(new BN(0), new BN(0))
+        .accounts({ authority: pg.wallet.publicKey, marketState: fixture.market })
+        .rpc();
+
+      trader = await createTrader(fixture, POSITION_COLLATERAL);
+      keeper = web3.Keypair.generate();
+      keeperTokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, fixture.mint, keeper.publicKey);
+    });
+
+    const triggerAccounts = (order) => ({
+      user: trader.user,
+      marketState: fixture.market,
+      quoteAssetMint: fixture.mint,
+      userPosition: trader.userPosition,
+      ...order,
+      rentReceiver: trader.user,
+      oraclePriceFeedAccount: SOL_USD_FEED,
+      keeper: keeper.publicKey,
+      keeperTokenAccount,
+      userVaultAuthority: trader.userVaultAuthority,
+      userVault: trader.userVault,
+      userStats: trader.userStats,
+      referrerRewards: null,
+      feeVault: fixture.feeVault,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    });
+
+    // Brackets must straddle the price when placed, so place them one tick either side
+    // and let the keeper retry until the feed has moved through one of the legs.
+    const placeTightBracket = async (size: number) => {
+      const price = await readOraclePrice(SOL_USD_FEED);
+      return placeBracket(fixture, trader, { isLong: false, size: new BN(size) }, price.subn(1), price.addn(1));
+    };
+
+    const triggerWhenMoved = async (bracketOrder) => {
+      for (let attempt = 0; ; attempt++) {
+        try {
+          return await pg.program.methods
+            .triggerBracketOrder()
+            .accounts(triggerAccounts({ bracketOrder }))
+            .signers([keeper])
+            .rpc();
+        } catch (err) {
+          if (attempt >= 30 || !err.toString().includes("OrderTriggerConditionNotMet")) throw err;
+          await new Promise((resolve) => setTimeout(resolve, 1000));
+        }
+      }
+    };
+
+    const positionSize = async () =>
+      (await pg.program.account.userPosition.fetch(trader.userPosition)).size.toNumber();
+
+    it("Closes only the bracket's size of a larger position", async () => {
+      await openPosition(fixture, trader, true, 3);
+      const bracketOrder = await placeTightBracket(1);
+
+      await triggerWhenMoved(bracketOrder);
+
+      assert.strictEqual(await positionSize(), 2, "Only the bracket's size should close");
+      assert.strictEqual(await pg.connection.getAccountInfo(bracketOrder), null, "Bracket should be closed");
+    });
+
+    it("Scales out through a ladder of two brackets", async () => {
+      const first = await placeTightBracket(1);
+      const second = await placeTightBracket(1);
+
+      await triggerWhenMoved(first);
+      assert.strictEqual(await positionSize(), 1, "The first bracket closes one");
+
+      await triggerWhenMoved(second);
+      assert.strictEqual(await positionSize(), 0, "The second bracket closes the rest");
+    });
+
+    it("Caps a bracket at a position reduced by another order", async () => {
+      await openPosition(fixture, trader, true, 3);
+      const bracketOrder = await placeTightBracket(3);
+
+      // A stop-loss above the current price reduces the position first.
+      const price = await readOraclePrice(SOL_USD_FEED);
+      const stopOrder = await placeStop(fixture, trader, { isLong: false, size: new BN(2) }, price.muln(2).toNumber(), false);
+      await pg.program.methods
+        .triggerStopOrder()
+        .accounts(triggerAccounts({ stopOrder }))
+        .signers([keeper])
+        .rpc();
+      assert.strictEqual(await positionSize(), 1);
+
+      await triggerWhenMoved(bracketOrder);
+      assert.strictEqual(await positionSize(), 0, "The bracket closes what is left, not its full size");
+    });
+
+    it("Rejects a stop order larger than the position", async () => {
+      await openPosition(fixture, trader, true, 1);
+      try {
+        await placeStop(fixture, trader, { isLong: false, size: new BN(2) }, 900, false);
+        assert.fail("A stop larger than the position should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "InvalidAmount");
+      }
+    });
+  });
+
+  describe("TWAP orders", () => {
+    let fixture, trader, keeper, keeperTokenAccount;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      // Reducing slices fill at the oracle price, so keep trading fees out of the vault math.
+      await pg.program.methods
+        .setTradingFees(new BN(0), new BN(0))
+        .accounts({ authority: pg.wallet.publicKey, marketState: fixture.market })
+        .rpc();
+
+      trader = await createTrader(fixture, POSITION_COLLATERAL);
+      await openPosition(fixture, trader, true, 1);
+
+      keeper = web3.Keypair.generate();
+      keeperTokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, fixture.mint, keeper.publicKey);
+    });
+
+    const executeSlice = (twapOrder) =>
+      pg.program.methods
+        .executeTwapSlice()
+        .accounts({
+          user: trader.user,
+          marketState: fixture.market,
+          quoteAssetMint: fixture.mint,
+          userPosition: trader.userPosition,
+          twapOrder,
+          rentReceiver: trader.user,
+          oraclePriceFeedAccount: SOL_USD_FEED,
+          keeper: keeper.publicKey,
+          keeperTokenAccount,
+          userVaultAuthority: trader.userVaultAuthority,
+          userVault: trader.userVault,
+          userStats: trader.userStats,
+          referrerRewards: null,
+          feeVault: fixture.feeVault,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc();
+
+    it("Places a TWAP order, executes a slice and cancels the rest", async () => {
+      const twapOrder = await placeTwap(fixture, trader, { isLong: true, size: new BN(2), clientOrderId: new BN(30) }, 2, 60);
+
+      let twap = await pg.program.account.twapOrder.fetch(twapOrder);
+      assert(twap.totalSize.eq(new BN(2)), "Total size mismatch");
+      assert.strictEqual(twap.numSlices, 2);
+      assert(twap.executedSize.eqn(0), "Nothing should execute at placement");
+
+      await executeSlice(twapOrder);
+
+      twap = await pg.program.account.twapOrder.fetch(twapOrder);
+      const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+      assert(twap.executedSize.eqn(1), "One slice should execute");
+      assert.strictEqual(twap.slicesExecuted, 1);
+      assert(position.size.eqn(2), "The slice should add to the position");
+      // Both units filled at the oracle, so the averaged entry stays near it.
+      const price = (await readOraclePrice(SOL_USD_FEED)).toNumber();
+      assert(
+        Math.abs(position.entryPrice.toNumber() - price) < price / 10,
+        "The opening slice should fill at the oracle price"
+      );
+
+      try {
+        await executeSlice(twapOrder);
+        assert.fail("The next slice must wait for the interval");
+      } catch (err) {
+        assert.include(err.toString(), "TwapIntervalNotElapsed");
+      }
+
+      await pg.program.methods
+        .cancelTwapOrder()
+        .accounts({ user: trader.user, authority: trader.user, userStats: null, sessionKey: null, twapOrder, rentReceiver: trader.user })
+        .signers([trader.kp])
+        .rpc();
+      assert.strictEqual(await pg.connection.getAccountInfo(twapOrder), null, "TWAP order should be closed");
+    });
+
+    it("Rejects a slice priced from a feed other than the market's oracle", async () => {
+      const twapOrder = await placeTwap(fixture, trader, { isLong: true, size: new BN(2) }, 2, 60);
+      try {
+        await pg.program.methods
+          .executeTwapSlice()
+          .accounts({
+            user: trader.user,
+            marketState: fixture.market,
+            quoteAssetMint: fixture.mint,
+            userPosition: trader.userPosition,
+            twapOrder,
+            rentReceiver: trader.user,
+            oraclePriceFeedAccount: fixture.market,
+            keeper: keeper.publicKey,
+            keeperTokenAccount,
+            userVaultAuthority: trader.userVaultAuthority,
+            userVault: trader.userVault,
+            userStats: trader.userStats,
+            referrerRewards: null,
+            feeVault: fixture.feeVault,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([keeper])
+          .rpc();
+        assert.fail("A foreign price feed should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "InvalidOracle");
+      }
+
+      await pg.program.methods
+        .cancelTwapOrder()
+        .accounts({ user: trader.user, authority: trader.user, userStats: null, sessionKey: null, twapOrder, rentReceiver: trader.user })
+        .signers([trader.kp])
+        .rpc();
+    });
+
+    it("Counts only the filled size of a reducing slice capped at the position", async () => {
+      // Slices of 3 against a position of 2: the first slice can only close 2.
+      const twapOrder = await placeTwap(fixture, trader, { isLong: false, size: new BN(6) }, 2, 60);
+      await executeSlice(twapOrder);
+
+      const twap = await pg.program.account.twapOrder.fetch(twapOrder);
+      const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+      assert(twap.executedSize.eqn(2), "Only the filled size counts as executed");
+      assert(position.size.eqn(0), "The position should be closed, not flipped");
+    });
+  });
+
+  describe("Referrals", () => {
+    it("Requires the referrer's rewards account on a referred user's trades", async () => {
+      const fixture = await createTradingMarket();
+      const referrer = await createTrader(fixture, POSITION_COLLATERAL);
+      const referee = await createTrader(fixture, POSITION_COLLATERAL);
+
+      await pg.program.methods
+        .registerReferrer()
+        .accounts({ user: referee.user, userStats: referee.userStats, referrerStats: referrer.userStats })
+        .signers([referee.kp])
+        .rpc();
+
+      try {
+        await openPosition(fixture, referee, true, 1);
+        assert.fail("A referred trade without the rewards account should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "MissingReferrerRewards");
+      }
+
+      // Anyone can create the referrer's rewards account.
+      const referrerRewards = pda(Buffer.from("referrer_rewards"), referrer.user.toBuffer(), fixture.market.toBuffer());
+      await pg.program.methods
+        .initializeReferrerRewards()
+        .accounts({
+          payer: pg.wallet.publicKey,
+          referrer: referrer.user,
+          marketState: fixture.market,
+          referrerRewards,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+
+      await openPosition(fixture, referee, true, 1, referrerRewards);
+      const position = await pg.program.account.userPosition.fetch(referee.userPosition);
+      assert(position.size.eq(new BN(1)));
+    });
+  });
+
+  describe("Withdrawals", () => {
+    it("Keeps initial margin at the oracle price when withdrawing the maximum", async () => {
+      const fixture = await createTradingMarket();
+      const trader = await createTrader(fixture, POSITION_COLLATERAL);
+      await openPosition(fixture, trader, true, 1);
+
+      const withdrawAccounts = {
+        user: trader.user,
+        marketState: fixture.market,
+        quoteAssetMint: fixture.mint,
+        userPosition: trader.userPosition,
+        userVaultAuthority: trader.userVaultAuthority,
+        userVault: trader.userVault,
+        userCollateralAccount: trader.tokenAccount,
+        collateralRegistry: null,
+        userCollateral: null,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      };
+      await pg.program.methods.withdrawMax().accounts(withdrawAccounts).signers([trader.kp]).rpc();
+
+      // One unit of size at the oracle price needs a tenth of its notional at 10x.
+      const position = await pg.program.account.userPosition.fetch(trader.userPosition);
+      const oraclePrice = await readOraclePrice(SOL_USD_FEED);
+      assert(position.collateral.lt(oraclePrice.divn(9)), "Only initial margin should remain");
+      try {
+        await pg.program.methods.withdrawCollateral(oraclePrice.divn(20)).accounts(withdrawAccounts).signers([trader.kp]).rpc();
+        assert.fail("Withdrawing below initial margin should fail");
+      } catch (err) {
+        assert.include(err.toString(), "InsufficientMargin");
+      }
+    });
+  });
+
+  describe("Cross-margin losses", () => {
+    it("Moves a loss beyond the collateral from the lending vault into the fee vault", async () => {
+      const fixture = await createTradingMarket();
+      const admin = { authority: pg.wallet.publicKey, marketState: fixture.market };
+      await pg.program.methods.setTradingFees(new BN(0), new BN(0)).accounts(admin).rpc();
+      // A deep vAMM at 1000; it prices cross trades, so a repeg moves the position's PnL.
+      await pg.program.methods.initializeVamm(new BN(1_000_000_000), new BN(1_000_000)).accounts(admin).rpc();
+
+      const lendingPool = pda(Buffer.from("lending_pool"), fixture.mint.toBuffer());
+      const lendingVault = pda(Buffer.from("lending_vault"), fixture.mint.toBuffer());
+      await pg.program.methods
+        .initializeLendingPool({
+          lendFractionBps: new BN(5000),
+          baseRateBps: new BN(0),
+          optimalUtilizationBps: new BN(8000),
+          slope1Bps: new BN(0),
+          slope2Bps: new BN(0),
+        })
+        .accounts({
+          ...admin,
+          quoteAssetMint: fixture.mint,
+          lendingPool,
+          lendingVault,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+      // The trader's UserStats come from a token isolated deposit.
+      const trader = await createTrader(fixture, 1);
+      const id = Buffer.alloc(2);
+      const marginAccount = pda(Buffer.from("margin_account"), trader.user.toBuffer(), id);
+      const marginVault = pda(Buffer.from("user_vault"), trader.user.toBuffer(), marginAccount.toBuffer());
+      const marginVaultAuthority = pda(Buffer.from("vault_authority"), trader.user.toBuffer(), marginAccount.toBuffer());
+      await pg.program.methods
+        .initializeMarginAccount(0)
+        .accounts({
+          owner: trader.user,
+          quoteAssetMint: fixture.mint,
+          marginAccount,
+          marginVaultAuthority,
+          marginVault,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([trader.kp])
+        .rpc();
+      await mintTo(pg.connection, pg.wallet.keypair, fixture.mint, trader.tokenAccount, pg.wallet.publicKey, 10_000);
+      await pg.program.methods
+        .depositMargin(new BN(10_000))
+        .accounts({
+          owner: trader.user,
+          marginAccount,
+          quoteAssetMint: fixture.mint,
+          marginVault,
+          ownerTokenAccount: trader.tokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([trader.kp])
+        .rpc();
+      // Lend half: 5_000 stays idle, 5_000 moves to the lending vault.
+      await pg.program.methods
+        .updateLending(true)
+        .accounts({
+          owner: trader.user,
+          marginAccount,
+          lendingPool,
+          quoteAssetMint: fixture.mint,
+          marginVaultAuthority,
+          marginVault,
+          lendingVault,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([trader.kp])
+        .rpc();
+
+      const crossAccounts = {
+        owner: trader.user,
+        authority: trader.user,
+        sessionKey: null,
+        marginAccount,
+        marketState: fixture.market,
+        quoteAssetMint: fixture.mint,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        marginVaultAuthority,
+        marginVault,
+        userStats: trader.userStats,
+        referrerRewards: null,
+        feeVault: fixture.feeVault,
+        lendingPool,
+        lendingVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      };
+      await pg.program.methods.openCrossPosition(true, new BN(90)).accounts(crossAccounts).signers([trader.kp]).rpc();
+
+      // Down 10%: a loss of about 9_000 against 5_000 of idle collateral.
+      await pg.program.methods.repegVamm(new BN(900_000)).accounts({ ...admin, feeVault: fixture.feeVault }).rpc();
+
+      const balance = async (account) => Number((await getAccount(pg.connection, account)).amount);
+      const [feeBefore, lendingBefore, marginBefore] = await Promise.all([
+        balance(fixture.feeVault),
+        balance(lendingVault),
+        balance(marginVault),
+      ]);
+      await pg.program.methods.closeCrossPosition().accounts(crossAccounts).signers([trader.kp]).rpc();
+      const [feeAfter, lendingAfter, marginAfter] = await Promise.all([
+        balance(fixture.feeVault),
+        balance(lendingVault),
+        balance(marginVault),
+      ]);
+
+      const deficit = lendingBefore - lendingAfter;
+      assert(deficit >= 4_000 && deficit < 4_100, `Unexpected deficit ${deficit}`);
+      assert.strictEqual(feeAfter - feeBefore, deficit, "The deficit should land in the fee vault");
+      assert.strictEqual(marginAfter, marginBefore, "Nothing should be stranded in the margin vault");
+      const account = await pg.program.account.marginAccount.fetch(marginAccount);
+      assert(account.collateral.eqn(0), "The loss uses up the idle collateral");
+    });
+  });
+
+  describe("Session notional caps", () => {
+    // SESSION_PLACE_CONDITIONAL
+    const allowedInstructions = 1 << 4;
+    const maxNotional = 1_000;
+    let fixture, trader, session, sessionKey, keeper, keeperTokenAccount;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      trader = await createTrader(fixture, POSITION_COLLATERAL);
+      await openPosition(fixture, trader, true, 1);
+
+      // The session key pays for the order accounts it creates.
+      session = web3.Keypair.generate();
+      const fundTx = new web3.Transaction().add(
+        web3.SystemProgram.transfer({ fromPubkey: pg.wallet.publicKey, toPubkey: session.publicKey, lamports: 0.05 * web3.LAMPORTS_PER_SOL })
+      );
+      await web3.sendAndConfirmTransaction(pg.connection, fundTx, [pg.wallet.keypair]);
+
+      sessionKey = pda(Buffer.from("session_key"), trader.user.toBuffer(), session.publicKey.toBuffer());
+      const expiresAt = new BN(Math.floor(Date.now() / 1000) + 3600);
+      await pg.program.methods
+        .registerSessionKey(session.publicKey, [fixture.market], new BN(maxNotional), allowedInstructions, expiresAt)
+        .accounts({ owner: trader.user, sessionKey, systemProgram: web3.SystemProgram.programId })
+        .signers([trader.kp])
+        .rpc();
+
+      keeper = web3.Keypair.generate();
+      keeperTokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, fixture.mint, keeper.publicKey);
+    });
+
+    const sessionAccounts = () => ({
+      user: trader.user,
+      authority: session.publicKey,
+      sessionKey,
+      userStats: null,
+      marketState: fixture.market,
+      userPosition: trader.userPosition,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    it("Rejects a stop order above the session key's notional cap", async () => {
+      const params = orderParams({ isLong: false, clientOrderId: new BN(60) });
+      try {
+        await pg.program.methods
+          .placeStopOrder(params, new BN(maxNotional + 1), false)
+          .accounts({ ...sessionAccounts(), stopOrder: await nextOrderPda("stop_order", fixture, trader) })
+          .signers([session])
+          .rpc();
+        assert.fail("Stop order above the cap should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "SessionNotionalExceeded");
+      }
+
+      const stopOrder = await nextOrderPda("stop_order", fixture, trader);
+      await pg.program.methods
+        .placeStopOrder(params, new BN(maxNotional), false)
+        .accounts({ ...sessionAccounts(), stopOrder })
+        .signers([session])
+        .rpc();
+      const stop = await pg.program.account.stopOrder.fetch(stopOrder);
+      assert(stop.triggerPrice.eqn(maxNotional), "Stop at the cap should be placed");
+    });
+
+    it("Checks the largest TWAP slice at placement and every slice at execution", async () => {
+      // Largest slice of 3 over 2 slices is 2; at a limit of 501 that is above the cap.
+      try {
+        await pg.program.methods
+          .placeTwapOrder(orderParams({ isLong: false, size: new BN(3), price: new BN(501), clientOrderId: new BN(61) }), 2, new BN(60))
+          .accounts({ ...sessionAccounts(), twapOrder: await nextOrderPda("twap_order", fixture, trader) })
+          .signers([session])
+          .rpc();
+        assert.fail("TWAP slices above the cap should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "SessionNotionalExceeded");
+      }
+
+      const twapOrder = await nextOrderPda("twap_order", fixture, trader);
+      await pg.program.methods
+        .placeTwapOrder(orderParams({ isLong: false, size: new BN(2), clientOrderId: new BN(62) }), 2, new BN(60))
+        .accounts({ ...sessionAccounts(), twapOrder })
+        .signers([session])
+        .rpc();
+      const twap = await pg.program.account.twapOrder.fetch(twapOrder);
+      assert(twap.sessionMaxNotional.eqn(maxNotional), "The session cap should be stored on the order");
+
+      // The reducing slice fills at the oracle price, far above the cap.
+      try {
+        await pg.program.methods
+          .executeTwapSlice()
+          .accounts({
+            user: trader.user,
+            marketState: fixture.market,
+            quoteAssetMint: fixture.mint,
+            userPosition: trader.userPosition,
+            twapOrder,
+            rentReceiver: session.publicKey,
+            oraclePriceFeedAccount: SOL_USD_FEED,
+            keeper: keeper.publicKey,
+            keeperTokenAccount,
+            userVaultAuthority: trader.userVaultAuthority,
+            userVault: trader.userVault,
+            userStats: trader.userStats,
+            referrerRewards: null,
+            feeVault: fixture.feeVault,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([keeper])
+          .rpc();
+        assert.fail("Slice above the session cap should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "SessionNotionalExceeded");
+      }
+
+      // The session key paid the order's rent, so cancelling returns it there, not to the owner.
+      assert(twap.payer.equals(session.publicKey), "The session key should be recorded as payer");
+      const cancelAccounts = { user: trader.user, authority: trader.user, userStats: null, sessionKey: null, twapOrder };
+      try {
+        await pg.program.methods
+          .cancelTwapOrder()
+          .accounts({ ...cancelAccounts, rentReceiver: trader.user })
+          .signers([trader.kp])
+          .rpc();
+        assert.fail("Rent must not go to the owner");
+      } catch (err) {
+        assert.include(err.toString(), "InvalidRentReceiver");
+      }
+      const sessionLamports = await pg.connection.getBalance(session.publicKey);
+      await pg.program.methods
+        .cancelTwapOrder()
+        .accounts({ ...cancelAccounts, rentReceiver: session.publicKey })
+        .signers([trader.kp])
+        .rpc();
+      assert.isAbove(await pg.connection.getBalance(session.publicKey), sessionLamports, "Rent goes back to the session key");
+    });
+  });
+
+  describe("Time in force", () => {
+    let fixture, maker, taker;
+
+    before(async () => {
+      fixture = await createTradingMarket();
+      maker = await createTrader(fixture, 1_000_000);
+      taker = await createTrader(fixture, 1_000_000);
+    });
+
+    beforeEach(async () => {
+      // Every case starts from one resting ask of size 1 at 1000.
+      for (const trader of [maker, taker]) {
+        await pg.program.methods
+          .cancelAllOrders(null)
+          .accounts({
+            user: trader.user,
+            authority: trader.user,
+            sessionKey: null,
+            userStats: null,
+            marketState: fixture.market,
+            orderBook: fixture.orderBook,
+          })
+          .signers([trader.kp])
+          .rpc();
+      }
+      await placeLimit(fixture, maker, { isLong: false, price: new BN(1000) });
+    });
+
+    it("Rejects a post-only order that would cross", async () => {
+      try {
+        await placeLimit(fixture, taker, { price: new BN(1000), timeInForce: { postOnly: {} } });
+        assert.fail("Crossing post-only order should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "PostOnlyWouldCross");
+      }
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      assert.strictEqual(orderBook.bidCount.toNumber(), 0);
+      assert.strictEqual(orderBook.askCount.toNumber(), 1, "Maker must be untouched");
+    });
+
+    it("Slides a crossing post-only-slide order one tick behind the best ask", async () => {
+      await placeLimit(fixture, taker, { price: new BN(1005), timeInForce: { postOnlySlide: {} } });
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      assert.strictEqual(orderBook.bidCount.toNumber(), 1);
+      assert(orderBook.bids[0].price.eq(new BN(999)), "Bid should rest at 999");
+      assert.strictEqual(orderBook.askCount.toNumber(), 1);
+    });
+
+    it("Fills what it can of an immediate-or-cancel order and rests nothing", async () => {
+      const queueBefore = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      await placeLimit(fixture, taker, { price: new BN(1000), size: new BN(3), timeInForce: { immediateOrCancel: {} } });
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      const queue = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      assert.strictEqual(orderBook.askCount.toNumber(), 0, "The ask should be filled");
+      assert.strictEqual(orderBook.bidCount.toNumber(), 0, "The unfilled remainder must not rest");
+      assert.strictEqual(queue.count.toNumber(), queueBefore.count.toNumber() + 1);
+    });
+
+    it("Rejects a fill-or-kill order that cannot fill completely", async () => {
+      const queueBefore = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      try {
+        await placeLimit(fixture, taker, { price: new BN(1000), size: new BN(2), timeInForce: { fillOrKill: {} } });
+        assert.fail("Partially fillable fill-or-kill order should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "FillOrKillNotFilled");
+      }
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      const queue = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      assert.strictEqual(orderBook.askCount.toNumber(), 1, "Nothing is committed");
+      assert.strictEqual(queue.count.toNumber(), queueBefore.count.toNumber());
+
+      await placeLimit(fixture, taker, { price: new BN(1000), size: new BN(1), timeInForce: { fillOrKill: {} } });
+      const filled = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      assert.strictEqual(filled.askCount.toNumber(), 0, "A fully fillable fill-or-kill order fills");
+    });
+
+    it("Rejects a good-till-time order whose expiry has passed", async () => {
+      try {
+        await placeLimit(fixture, taker, {
+          price: new BN(900),
+          timeInForce: { goodTillTime: {} },
+          expiryTs: new BN(Math.floor(Date.now() / 1000) - 60),
+        });
+        assert.fail("Expired good-till-time order should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "OrderExpired");
+      }
+    });
+
+    it("Removes an expired good-till-time maker at match time instead of filling it", async () => {
+      await placeLimit(fixture, maker, {
+        isLong: false,
+        price: new BN(990),
+        clientOrderId: new BN(42),
+        timeInForce: { goodTillTime: {} },
+        expiryTs: new BN(Math.floor(Date.now() / 1000) + 2),
+      });
+      await new Promise((resolve) => setTimeout(resolve, 4000));
+
+      const queueBefore = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      await placeLimit(fixture, taker, { price: new BN(995) });
+
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      const queue = await pg.program.account.eventQueue.fetch(fixture.eventQueue);
+      assert.strictEqual(queue.count.toNumber(), queueBefore.count.toNumber(), "Expired maker must not fill");
+      assert.strictEqual(orderBook.askCount.toNumber(), 1, "Only the good-till-cancel ask remains");
+      assert(orderBook.asks[0].price.eq(new BN(1000)));
+      assert.strictEqual(orderBook.bidCount.toNumber(), 1, "The taker bid rests");
+    });
+
+    it("Accepts only good-till-cancel and good-till-time for conditional orders", async () => {
+      try {
+        await placeTwap(fixture, taker, { size: new BN(4), timeInForce: { immediateOrCancel: {} } }, 2, 60);
+        assert.fail("Immediate-or-cancel TWAP should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "UnsupportedTimeInForce");
+      }
+    });
+  });
+
+  describe("Resting order margin", () => {
+    it("Counts resting orders against the leverage limit", async () => {
+      const fixture = await createTradingMarket();
+      // 10_000 of collateral supports 100_000 of notional at 10x.
+      const trader = await createTrader(fixture, 10_000);
+
+      await placeLimit(fixture, trader, { price: new BN(1000), size: new BN(60) });
+      try {
+        await placeLimit(fixture, trader, { price: new BN(1000), size: new BN(60), clientOrderId: new BN(2) });
+        assert.fail("Resting orders past the leverage limit should be rejected");
+      } catch (err) {
+        assert.include(err.toString(), "InsufficientMargin");
+      }
+
+      await placeLimit(fixture, trader, { price: new BN(1000), size: new BN(40), clientOrderId: new BN(3) });
+      const orderBook = await pg.program.account.orderBook.fetch(fixture.orderBook);
+      assert.strictEqual(orderBook.bidCount.toNumber(), 2);
+    });
+  });
+
+  it("Places and cancels a stop order", async () => {
+    await pg.program.methods
+      .openPosition(true, new BN(1))
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        marketState: marketStateKp.publicKey,
+        userPosition: userPositionKp.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const position = await pg.program.account.userPosition.fetch(userPositionKp.publicKey);
+    const [stopOrderPda] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("stop_order"),
+        pg.wallet.publicKey.toBuffer(),
+        marketStateKp.publicKey.toBuffer(),
+        position.nextOrderSeq.toArrayLike(Buffer, "le", 8),
+      ],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .placeStopOrder(
+        {
+          isLong: false,
+          price: new BN(0),
+          size: new BN(1),
+          timeInForce: { goodTillCancel: {} },
+          expiryTs: new BN(0),
+          clientOrderId: new BN(1),
+          selfTradeBehavior: { cancelMaker: {} },
+        },
+        new BN(900),
+        false
+      )
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        userStats: null,
+        marketState: marketStateKp.publicKey,
+        userPosition: userPositionKp.publicKey,
+        stopOrder: stopOrderPda,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const stopOrder = await pg.program.account.stopOrder.fetch(stopOrderPda);
+    assert(stopOrder.triggerPrice.eq(new BN(900)), "Trigger price mismatch");
+
+    await pg.program.methods
+      .cancelStopOrder()
+      .accounts({ user: pg.wallet.publicKey, authority: pg.wallet.publicKey, userStats: null, sessionKey: null, stopOrder: stopOrderPda, rentReceiver: pg.wallet.publicKey })
+      .rpc();
+
+    const closed = await pg.connection.getAccountInfo(stopOrderPda);
+    assert.strictEqual(closed, null, "Stop order account should be closed");
+  });
+
+  it("Places, modifies and cancels a bracket order", async () => {
+    const position = await pg.program.account.userPosition.fetch(userPositionKp.publicKey);
+    const [bracketOrderPda] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("bracket_order"),
+        pg.wallet.publicKey.toBuffer(),
+        marketStateKp.publicKey.toBuffer(),
+        position.nextOrderSeq.toArrayLike(Buffer, "le", 8),
+      ],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .placeBracketOrder(
+        {
+          isLong: false,
+          price: new BN(0),
+          size: new BN(1),
+          timeInForce: { goodTillCancel: {} },
+          expiryTs: new BN(0),
+          clientOrderId: new BN(2),
+          selfTradeBehavior: { cancelMaker: {} },
+        },
+        new BN(900),
+        new BN(1100)
+      )
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        userStats: null,
+        marketState: marketStateKp.publicKey,
+        userPosition: userPositionKp.publicKey,
+        bracketOrder: bracketOrderPda,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await pg.program.methods
+      .modifyBracketOrder(new BN(950), new BN(1200))
+      .accounts({
+        user: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        sessionKey: null,
+        userStats: null,
+        marketState: marketStateKp.publicKey,
+        bracketOrder: bracketOrderPda,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+      })
+      .rpc();
+
+    const bracketOrder = await pg.program.account.bracketOrder.fetch(bracketOrderPda);
+    assert(bracketOrder.stopLossPrice.eq(new BN(950)), "Stop loss not modified");
+    assert(bracketOrder.takeProfitPrice.eq(new BN(1200)), "Take profit not modified");
+
+    await pg.program.methods
+      .cancelBracketOrder()
+      .accounts({ user: pg.wallet.publicKey, authority: pg.wallet.publicKey, userStats: null, sessionKey: null, bracketOrder: bracketOrderPda, rentReceiver: pg.wallet.publicKey })
+      .rpc();
+
+    const closed = await pg.connection.getAccountInfo(bracketOrderPda);
+    assert.strictEqual(closed, null, "Bracket order account should be closed");
+  });
+
+  it("Round-trips a deposit and withdrawal through the vault authority PDA", async () => {
+    const user = pg.wallet.publicKey;
+    const mint = await createMint(pg.connection, pg.wallet.keypair, user, null, 6);
+    const userTokenAccount = await createAssociatedTokenAccount(pg.connection, pg.wallet.keypair, mint, user);
+    await mintTo(pg.connection, pg.wallet.keypair, mint, userTokenAccount, user, 1_000_000);
+
+    const market = web3.Keypair.generate();
+    realMint = mint;
+    realMarket = market.publicKey;
+    realUserTokenAccount = userTokenAccount;
+    const insurance = web3.Keypair.generate();
+    const pda = (...seeds: Buffer[]) =>
+      web3.PublicKey.findProgramAddressSync(seeds, pg.program.programId)[0];
+    const marketAuthority = pda(Buffer.from("market_authority"), market.publicKey.toBuffer());
+    const feeVault = pda(Buffer.from("fee_vault"), market.publicKey.toBuffer());
+
+    await pg.program.methods
+      .initializeMarket(new BN(0), "SOL", null)
+      .accounts({
+        marketState: market.publicKey,
+        quoteAssetMint: mint,
+        marketAuthority,
+        feeVault,
+        insuranceVault: insurance.publicKey,
+        oraclePriceFeedAccount: SOL_USD_FEED,
+        authority: user,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([market, insurance])
+      .rpc();
+
+    const userPosition = pda(Buffer.from("user_position"), user.toBuffer(), market.publicKey.toBuffer());
+    const userStats = pda(Buffer.from("user_stats"), user.toBuffer());
+    const userVault = pda(Buffer.from("user_vault"), user.toBuffer(), market.publicKey.toBuffer());
+    const userVaultAuthority = pda(Buffer.from("vault_authority"), user.toBuffer(), market.publicKey.toBuffer());
+
+    await pg.program.methods
+      .depositCollateral(new BN(400_000))
+      .accounts({
+        user,
+        marketState: market.publicKey,
+        quoteAssetMint: mint,
+        userPosition,
+        userStats,
+        userCollateralAccount: userTokenAccount,
+        userVault,
+        userVaultAuthority,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const vault = await getAccount(pg.connection, userVault);
+    assert.strictEqual(vault.owner.toBase58(), userVaultAuthority.toBase58(), "Vault must be owned by the authority PDA");
+    assert.notStrictEqual(vault.owner.toBase58(), userVault.toBase58());
+    assert.strictEqual(Number(vault.amount), 400_000);
+
+    const withdrawAccounts = {
+      user,
+      marketState: market.publicKey,
+      quoteAssetMint: mint,
+      userPosition,
+      userVaultAuthority,
+      userVault,
+      userCollateralAccount: userTokenAccount,
+      collateralRegistry: null,
+      userCollateral: null,
+      oraclePriceFeedAccount: SOL_USD_FEED,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+
+    // More than the deposited collateral is rejected
+    try {
+      await pg.program.methods.withdrawCollateral(new BN(400_001)).accounts(withdrawAccounts).rpc();
+      assert.fail("Withdrawing more than the collateral should fail");
+    } catch (err) {
+      assert.include(err.toString(), "InsufficientCollateral");
+    }
+
+    await pg.program.methods.withdrawCollateral(new BN(150_000)).accounts(withdrawAccounts).rpc();
+    // With no open position the whole remainder is free
+    await pg.program.methods.withdrawMax().accounts(withdrawAccounts).rpc();
+
+    const position = await pg.program.account.userPosition.fetch(userPosition);
+    assert.strictEqual(position.collateral.toNumber(), 0);
+    assert.strictEqual(Number((await getAccount(pg.connection, userVault)).amount), 0);
+    assert.strictEqual(Number((await getAccount(pg.connection, userTokenAccount)).amount), 1_000_000);
+
+    // Already on the new layout, so migration is rejected
+    try {
+      await pg.program.methods
+        .migrateVaultAuthority(market.publicKey)
+        .accounts({
+          user,
+          legacyVaultAuthority: userVault,
+          vaultAuthority: userVaultAuthority,
+          vault: userVault,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      assert.fail("Migrating a vault on the new layout should fail");
+    } catch (err) {
+      assert.include(err.toString(), "VaultAlreadyMigrated");
+    }
+  });
+
+  it("Deposits on behalf of another user and sets a trading delegate", async () => {
+    const pda = (...seeds: Buffer[]) =>
+      web3.PublicKey.findProgramAddressSync(seeds, pg.program.programId)[0];
+    const beneficiary = web3.Keypair.generate().publicKey;
+
+    await pg.program.methods
+      .depositFor(beneficiary, new BN(250_000))
+      .accounts({
+        depositor: pg.wallet.publicKey,
+        marketState: realMarket,
+        quoteAssetMint: realMint,
+        userPosition: pda(Buffer.from("user_position"), beneficiary.toBuffer(), realMarket.toBuffer()),
+        userStats: pda(Buffer.from("user_stats"), beneficiary.toBuffer()),
+        depositorTokenAccount: realUserTokenAccount,
+        userVault: pda(Buffer.from("user_vault"), beneficiary.toBuffer(), realMarket.toBuffer()),
+        userVaultAuthority: pda(Buffer.from("vault_authority"), beneficiary.toBuffer(), realMarket.toBuffer()),
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const position = await pg.program.account.userPosition.fetch(
+      pda(Buffer.from("user_position"), beneficiary.toBuffer(), realMarket.toBuffer())
+    );
+    assert.strictEqual(position.user.toBase58(), beneficiary.toBase58());
+    assert.strictEqual(position.collateral.toNumber(), 250_000);
+
+    const delegate = web3.Keypair.generate().publicKey;
+    const userStats = pda(Buffer.from("user_stats"), pg.wallet.publicKey.toBuffer());
+    await pg.program.methods
+      .setDelegate(delegate)
+      .accounts({ user: pg.wallet.publicKey, userStats })
+      .rpc();
+
+    const stats = await pg.program.account.userStats.fetch(userStats);
+    assert.strictEqual(stats.delegate.toBase58(), delegate.toBase58());
+  });
+
+  it("Registers and revokes a scoped session key", async () => {
+    const sessionSigner = web3.Keypair.generate().publicKey;
+    const [sessionKey] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("session_key"), pg.wallet.publicKey.toBuffer(), sessionSigner.toBuffer()],
+      pg.program.programId
+    );
+    const expiresAt = new BN(Math.floor(Date.now() / 1000) + 3600);
+    // SESSION_PLACE_ORDER | SESSION_CANCEL_ORDER
+    const allowedInstructions = (1 << 2) | (1 << 3);
+
+    await pg.program.methods
+      .registerSessionKey(sessionSigner, [marketStateKp.publicKey], new BN(50_000), allowedInstructions, expiresAt)
+      .accounts({
+        owner: pg.wallet.publicKey,
+        sessionKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const registered = await pg.program.account.sessionKey.fetch(sessionKey);
+    assert.strictEqual(registered.signer.toBase58(), sessionSigner.toBase58());
+    assert.strictEqual(registered.marketCount, 1);
+    assert.strictEqual(registered.allowedInstructions, allowedInstructions);
+    assert(registered.expiresAt.eq(expiresAt), "Expiry mismatch");
+
+    await pg.program.methods
+      .revokeSessionKey()
+      .accounts({ owner: pg.wallet.publicKey, sessionKey })
+      .rpc();
+
+    const closed = await pg.connection.getAccountInfo(sessionKey);
+    assert.strictEqual(closed, null, "Session key account should be closed");
+  });
+
+  it("Initializes a lending pool and updates its rate curve", async () => {
+    const [lendingPool] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("lending_pool"), realMint.toBuffer()],
+      pg.program.programId
+    );
+    const [lendingVault] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("lending_vault"), realMint.toBuffer()],
+      pg.program.programId
+    );
+    const params = {
+      lendFractionBps: new BN(5000),
+      baseRateBps: new BN(100),
+      optimalUtilizationBps: new BN(8000),
+      slope1Bps: new BN(400),
+      slope2Bps: new BN(6000),
+    };
+
+    // Only the authority of a market quoted in the mint can create its pool
+    const stranger = web3.Keypair.generate();
+    const fundTx = new web3.Transaction().add(
+      web3.SystemProgram.transfer({ fromPubkey: pg.wallet.publicKey, toPubkey: stranger.publicKey, lamports: 0.05 * web3.LAMPORTS_PER_SOL })
+    );
+    await web3.sendAndConfirmTransaction(pg.connection, fundTx, [pg.wallet.keypair]);
+    try {
+      await pg.program.methods
+        .initializeLendingPool(params)
+        .accounts({
+          authority: stranger.publicKey,
+          marketState: realMarket,
+          quoteAssetMint: realMint,
+          lendingPool,
+          lendingVault,
+          systemProgram: web3.SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([stranger])
+        .rpc();
+      assert.fail("Non-authority should not create the lending pool");
+    } catch (err) {
+      assert.include(err.toString(), "Unauthorized");
+    }
+
+    await pg.program.methods
+      .initializeLendingPool(params)
+      .accounts({
+        authority: pg.wallet.publicKey,
+        marketState: realMarket,
+        quoteAssetMint: realMint,
+        lendingPool,
+        lendingVault,
+        systemProgram: web3.SystemProgram.programId,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    let pool = await pg.program.account.lendingPool.fetch(lendingPool);
+    assert.strictEqual(pool.lendingVault.toBase58(), lendingVault.toBase58());
+    assert(pool.params.lendFractionBps.eq(new BN(5000)), "Lend fraction mismatch");
+    assert(pool.depositIndex.eq(pool.borrowIndex), "Indices should start equal");
+
+    // A lend fraction above 100% is rejected
+    try {
+      await pg.program.methods
+        .setLendingParams({ ...params, lendFractionBps: new BN(10_001) })
+        .accounts({ authority: pg.wallet.publicKey, lendingPool })
+        .rpc();
+      assert.fail("Lend fraction above 100% should be rejected");
+    } catch (err) {
+      assert.include(err.toString(), "InvalidLendingParams");
+    }
+
+    await pg.program.methods
+      .setLendingParams({ ...params, lendFractionBps: new BN(2500) })
+      .accounts({ authority: pg.wallet.publicKey, lendingPool })
+      .rpc();
+    pool = await pg.program.account.lendingPool.fetch(lendingPool);
+    assert(pool.params.lendFractionBps.eq(new BN(2500)), "Lend fraction not updated");
+  });
 });